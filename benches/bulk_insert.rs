@@ -0,0 +1,122 @@
+//! compares the bulk inserts of `DataBase` with the row-by-row inserts they replace for large
+//! batches. needs a migrated postgres database in DATABASE_URL (see `fitbod-server migrate`).
+//! the users and workouts created are deleted again at the end.
+//!
+//! ```console
+//! $ cargo bench --bench bulk-insert -- [N_USERS] [N_WORKOUTS]
+//! ```
+
+use std::time::*;
+use chrono::prelude::*;
+use pretty_toa::ThousandsSep;
+use uuid::Uuid;
+use fitbod::db::DataBase;
+use fitbod::{Category, Exercise, ExerciseSet, User, WeightUnit, Workout};
+
+/// workouts per user, like a device syncing a year of daily workouts
+const WORKOUTS_PER_USER: usize = 365;
+
+fn users(n: usize) -> Vec<User> {
+    (0..n)
+        .map(|_| {
+            let user_id = Uuid::new_v4();
+            User { user_id, email: format!("bench-{}@example.com", user_id), key: rand::random(), created: Utc::now() }
+        })
+        .collect()
+}
+
+/// `n` daily workouts, of `WORKOUTS_PER_USER` per user, with a few exercises each
+fn workouts(users: &[User], n: usize) -> Vec<Workout> {
+    let today = Utc::now().date_naive().and_hms_opt(7, 0, 0).unwrap().and_utc();
+    (0..n)
+        .map(|i| {
+            let start_time = today - chrono::Duration::days((i % WORKOUTS_PER_USER) as i64 + 1);
+            Workout {
+                user_id: users[i / WORKOUTS_PER_USER].user_id,
+                workout_id: Uuid::new_v4(),
+                start_time,
+                end_time: start_time + chrono::Duration::minutes(50),
+                exercises: ["squat", "bench press", "row"].iter()
+                    .map(|name| Exercise {
+                        name: name.to_string(),
+                        sets: vec![ExerciseSet { reps: 5, weight: Some(80.0), unit: WeightUnit::Kg, rpe: Some(8.0) }; 3],
+                    })
+                    .collect(),
+                category: Some(Category::Strength),
+                tags: vec!["bench".to_string()],
+                note: None,
+            }
+        })
+        .collect()
+}
+
+async fn time<F, T>(f: F) -> (T, Duration)
+    where F: std::future::Future<Output = Result<T, sqlx::Error>>
+{
+    let start = Instant::now();
+    let result = f.await.unwrap();
+    (result, Instant::now() - start)
+}
+
+fn report(what: &str, n: usize, row_by_row: Duration, bulk: Duration) {
+    println!("{:<10} {:>10} {:>14.2?} {:>14.2?} {:>9.1}x {:>12}/s",
+        what,
+        n.thousands_sep(),
+        row_by_row,
+        bulk,
+        row_by_row.as_secs_f64() / bulk.as_secs_f64(),
+        ((n as f64 / bulk.as_secs_f64()) as u64).thousands_sep(),
+    );
+}
+
+fn main() {
+    dotenv::dotenv().ok();
+    let db_url = match std::env::var("DATABASE_URL") {
+        Ok(url) if url.starts_with("postgres") => url,
+        _ => {
+            println!("skipping bulk-insert benchmark: DATABASE_URL must be a postgres url");
+            return
+        }
+    };
+    // cargo passes --bench
+    let args: Vec<usize> = std::env::args().skip(1)
+        .filter(|x| ! x.starts_with("--"))
+        .map(|x| x.parse().expect("usage: bulk-insert [N_USERS] [N_WORKOUTS]"))
+        .collect();
+    let n_users = args.first().copied().unwrap_or(10_000);
+    let n_workouts = args.get(1).copied().unwrap_or(20 * WORKOUTS_PER_USER);
+    assert!(n_users * WORKOUTS_PER_USER >= n_workouts, "not enough users for {} workouts", n_workouts);
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let db = DataBase::new(&db_url).await.unwrap();
+
+        // each insert gets its own users and workouts, so neither finds the other's rows
+        let (loop_users, bulk_users) = (users(n_users), users(n_users));
+        let (loop_workouts, bulk_workouts) = (workouts(&loop_users, n_workouts), workouts(&bulk_users, n_workouts));
+
+        println!("{:<10} {:>10} {:>15} {:>14} {:>10} {:>14}", "", "rows", "row by row", "bulk", "speedup", "bulk rate");
+
+        let ((), row_by_row) = time(db.insert_users(&loop_users)).await;
+        let (n, bulk) = time(db.bulk_insert_users(&bulk_users)).await;
+        assert_eq!(n, n_users);
+        report("users", n_users, row_by_row, bulk);
+
+        let ((), row_by_row) = time(db.insert_workouts(&loop_workouts)).await;
+        let (inserted, bulk) = time(db.bulk_insert_workouts(&bulk_workouts)).await;
+        assert_eq!(inserted.len(), n_workouts);
+        report("workouts", n_workouts, row_by_row, bulk);
+
+        // sending everything again, e.g. after a dropped response, skips all of it
+        let (inserted, resend) = time(db.bulk_insert_workouts(&bulk_workouts)).await;
+        assert!(inserted.is_empty());
+        println!("re-sending {} workouts to bulk insert: {:.2?}, none inserted", n_workouts.thousands_sep(), resend);
+
+        let user_ids: Vec<Uuid> = loop_users.iter().chain(bulk_users.iter()).map(|u| u.user_id).collect();
+        sqlx::query("delete from users where user_id = any($1)")
+            .bind(&user_ids)
+            .execute(db.pool())
+            .await
+            .unwrap();
+    });
+}
@@ -0,0 +1,34 @@
+-- user_keys table: multiple keys per user, with expiry and revocation
+
+-- public keys used to verify signed requests. a user may have several active keys (e.g. one per device),
+-- and keys may be rotated by adding a new key and revoking the old one
+CREATE TABLE user_keys (
+    key_id      uuid NOT NULL UNIQUE
+                DEFAULT gen_random_uuid()
+                PRIMARY KEY,
+
+    user_id     uuid NOT NULL,
+
+    key         bytea NOT NULL                          -- ed25519 public key
+                CHECK (length(key) = 32),
+
+    created     timestamp with time zone NOT NULL
+                DEFAULT now(),
+
+    expires     timestamp with time zone,               -- null: never expires
+
+    revoked     timestamp with time zone,               -- null: not revoked
+
+    CONSTRAINT user_keys_user_fkey FOREIGN KEY (user_id)
+        REFERENCES users (user_id)
+        ON DELETE CASCADE,
+
+    CONSTRAINT user_key_uniq UNIQUE(user_id, key)
+);
+
+CREATE INDEX user_keys_user_id ON user_keys USING btree (
+    user_id
+);
+
+-- the key registered with each existing account becomes its first key
+insert into user_keys (user_id, key, created) select user_id, key, created from users;
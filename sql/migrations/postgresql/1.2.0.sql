@@ -0,0 +1,5 @@
+-- users.tz column: per-user time zone for workout dates
+
+ALTER TABLE users
+    ADD COLUMN tz text NOT NULL                         -- IANA time zone name (e.g. 'America/New_York'), used to
+        DEFAULT 'UTC';                                  -- determine the local date of workouts
@@ -0,0 +1,46 @@
+-- exercises and exercise_sets tables: optional exercises, sets, reps and load of workouts
+
+-- optional detail of a workout: exercises in the order performed, each with sets
+CREATE TABLE exercises (
+    workout_id  uuid NOT NULL,
+
+    position    integer NOT NULL                        -- order within the workout, starting at 0
+                CHECK (position >= 0),
+
+    name        text NOT NULL
+                CHECK (length(name) > 0),
+
+    PRIMARY KEY (workout_id, position),
+
+    CONSTRAINT exercises_workout_fkey FOREIGN KEY (workout_id)
+        REFERENCES workouts (workout_id)
+        ON DELETE CASCADE
+);
+
+CREATE TABLE exercise_sets (
+    workout_id          uuid NOT NULL,
+
+    exercise_position   integer NOT NULL,
+
+    position            integer NOT NULL                -- order within the exercise, starting at 0
+                        CHECK (position >= 0),
+
+    reps                integer NOT NULL
+                        CHECK (reps >= 0),
+
+    weight              double precision                -- null: bodyweight
+                        CHECK (weight >= 0),
+
+    unit                text NOT NULL
+                        DEFAULT 'kg'
+                        CHECK (unit IN ('kg', 'lb')),
+
+    rpe                 real                            -- rate of perceived exertion
+                        CHECK (rpe BETWEEN 1 AND 10),
+
+    PRIMARY KEY (workout_id, exercise_position, position),
+
+    CONSTRAINT exercise_sets_exercise_fkey FOREIGN KEY (workout_id, exercise_position)
+        REFERENCES exercises (workout_id, position)
+        ON DELETE CASCADE
+);
@@ -0,0 +1,10 @@
+-- workouts.category, workouts.tags and workouts.note columns
+
+ALTER TABLE workouts
+    ADD COLUMN category text                            -- null: uncategorized
+        CHECK (category IN ('strength', 'cardio', 'mobility', 'sport', 'other')),
+
+    ADD COLUMN tags text[] NOT NULL                     -- free-form labels
+        DEFAULT '{}',
+
+    ADD COLUMN note text;
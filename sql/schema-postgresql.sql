@@ -1,3 +1,7 @@
+-- full schema as of the latest migration in sql/migrations/postgresql (see `fitbod::migrate::POSTGRESQL`), plus some
+-- dummy data. drops everything first: to create or upgrade a database without losing data, use `fitbod-server migrate`.
+-- a new migration must be applied here as well, and recorded in the migrations table at the end
+
 BEGIN TRANSACTION;
 
 DROP schema public CASCADE;
@@ -13,17 +17,50 @@ CREATE TABLE users (
     email       text NOT NULL UNIQUE
                 CHECK (length(email) > 0),
 
-    key         bytea NOT NULL                          -- ed25519 public key used to sign requests
-                CHECK (length(key) = 32),
+    key         bytea NOT NULL                          -- ed25519 public key registered with the account. also
+                CHECK (length(key) = 32),               -- stored in user_keys, which is what requests are verified against
 
     created     timestamp with time zone NOT NULL
-                DEFAULT now()
+                DEFAULT now(),
+
+    tz          text NOT NULL                           -- IANA time zone name (e.g. 'America/New_York'), used to
+                DEFAULT 'UTC'                           -- determine the local date of workouts
 );
 
 CREATE INDEX users_email ON users USING hash (
     email
 );
 
+-- public keys used to verify signed requests. a user may have several active keys (e.g. one per device),
+-- and keys may be rotated by adding a new key and revoking the old one
+CREATE TABLE user_keys (
+    key_id      uuid NOT NULL UNIQUE
+                DEFAULT gen_random_uuid()
+                PRIMARY KEY,
+
+    user_id     uuid NOT NULL,
+
+    key         bytea NOT NULL                          -- ed25519 public key
+                CHECK (length(key) = 32),
+
+    created     timestamp with time zone NOT NULL
+                DEFAULT now(),
+
+    expires     timestamp with time zone,               -- null: never expires
+
+    revoked     timestamp with time zone,               -- null: not revoked
+
+    CONSTRAINT user_keys_user_fkey FOREIGN KEY (user_id)
+        REFERENCES users (user_id)
+        ON DELETE CASCADE,
+
+    CONSTRAINT user_key_uniq UNIQUE(user_id, key)
+);
+
+CREATE INDEX user_keys_user_id ON user_keys USING btree (
+    user_id
+);
+
 CREATE TABLE workouts (
     workout_id  uuid NOT NULL UNIQUE
                 DEFAULT gen_random_uuid()
@@ -37,6 +74,14 @@ CREATE TABLE workouts (
 
     end_time    timestamp with time zone NOT NULL,
 
+    category    text                                    -- null: uncategorized
+                CHECK (category IN ('strength', 'cardio', 'mobility', 'sport', 'other')),
+
+    tags        text[] NOT NULL                         -- free-form labels
+                DEFAULT '{}',
+
+    note        text,
+
     CONSTRAINT workouts_user_fkey FOREIGN KEY (user_id)
         REFERENCES users (user_id)
         ON DELETE CASCADE,
@@ -53,6 +98,51 @@ CREATE INDEX workouts_user_start_time ON workouts USING btree (
     start_time  DESC
 );
 
+-- optional detail of a workout: exercises in the order performed, each with sets
+CREATE TABLE exercises (
+    workout_id  uuid NOT NULL,
+
+    position    integer NOT NULL                        -- order within the workout, starting at 0
+                CHECK (position >= 0),
+
+    name        text NOT NULL
+                CHECK (length(name) > 0),
+
+    PRIMARY KEY (workout_id, position),
+
+    CONSTRAINT exercises_workout_fkey FOREIGN KEY (workout_id)
+        REFERENCES workouts (workout_id)
+        ON DELETE CASCADE
+);
+
+CREATE TABLE exercise_sets (
+    workout_id          uuid NOT NULL,
+
+    exercise_position   integer NOT NULL,
+
+    position            integer NOT NULL                -- order within the exercise, starting at 0
+                        CHECK (position >= 0),
+
+    reps                integer NOT NULL
+                        CHECK (reps >= 0),
+
+    weight              double precision                -- null: bodyweight
+                        CHECK (weight >= 0),
+
+    unit                text NOT NULL
+                        DEFAULT 'kg'
+                        CHECK (unit IN ('kg', 'lb')),
+
+    rpe                 real                            -- rate of perceived exertion
+                        CHECK (rpe BETWEEN 1 AND 10),
+
+    PRIMARY KEY (workout_id, exercise_position, position),
+
+    CONSTRAINT exercise_sets_exercise_fkey FOREIGN KEY (workout_id, exercise_position)
+        REFERENCES exercises (workout_id, position)
+        ON DELETE CASCADE
+);
+
 -- useful for debugging/cli purposes
 CREATE VIEW workout_durations AS
     SELECT
@@ -83,6 +173,26 @@ insert into migrations(version, descr) values (
     'initial schema: users, workouts, and migrations tables; workout_durations view'
 );
 
+insert into migrations(version, descr) values (
+    '1.1.0',
+    'user_keys table: multiple keys per user, with expiry and revocation'
+);
+
+insert into migrations(version, descr) values (
+    '1.2.0',
+    'users.tz column: per-user time zone for workout dates'
+);
+
+insert into migrations(version, descr) values (
+    '1.3.0',
+    'exercises and exercise_sets tables: optional exercises, sets, reps and load of workouts'
+);
+
+insert into migrations(version, descr) values (
+    '1.4.0',
+    'workouts.category, workouts.tags and workouts.note columns'
+);
+
 -- some dummy data for testing
 insert into users (user_id, email, key) values (
     '1fe9e4f0-8cd1-46be-963a-7f51470db6af',
@@ -90,6 +200,8 @@ insert into users (user_id, email, key) values (
     '\xd2cd9de812f5eaed03474f0c03efce7fac19e64423e49760faf25472bc158752' -- base64-encoded priv key in .env (FITBOD_SECRET_ACCESS_KEY)
 );
 
+insert into user_keys (user_id, key) select user_id, key from users;
+
 insert into workouts (user_id, start_time, end_time) values (
     '1fe9e4f0-8cd1-46be-963a-7f51470db6af',
     now() + interval '15 minutes',
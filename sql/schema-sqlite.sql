@@ -1,26 +1,33 @@
-BEGIN TRANSACTION;
+-- sqlite version of schema-postgresql.sql, applied as the first sqlite migration (see `fitbod::migrate::SQLITE`)
+-- by `fitbod-server migrate`, or by `fitbod::sqlite::SqliteDataBase` when it opens an empty database. tables,
+-- columns and constraints match the postgresql schema, except:
+--
+--   - uuids are 16-byte blobs
+--   - timestamps are integer unix timestamps in microseconds (the precision of postgresql timestamps)
+--   - workouts.tags is a json array of strings
+--   - ids have no defaults, the server always provides them
+--
+-- like every migration, it runs in a transaction, and the migration is recorded in the `migrations` table afterwards
 
-PRAGMA foreign_keys(1);
-PRAGMA user_version(100);
 PRAGMA application_id(42);
 
 CREATE TABLE IF NOT EXISTS `users` (
-	`user_id`	    BLOB ( 16 ) NOT NULL UNIQUE
-                    DEFAULT ( uuid_blob( uuid() ) ),
+	`user_id`	    BLOB ( 16 ) NOT NULL UNIQUE,
 
 	`email`	        TEXT NOT NULL UNIQUE,
 
-	`secret`	    BLOB ( 64 ) NOT NULL                                -- used to sign requests
-                    DEFAULT (randomblob(64)),
+	`key`	        BLOB ( 32 ) NOT NULL,                               -- ed25519 public key registered with the account. also
+                                                                        -- stored in user_keys, which is what requests are verified against
 
-    `created`  	    INTEGER ( 8 ) NOT NULL                              -- unix timestamp
-                    DEFAULT (strftime('%s', CURRENT_TIMESTAMP)),
+    `created`  	    INTEGER ( 8 ) NOT NULL,                             -- unix timestamp, microseconds
+
+    `tz`            TEXT NOT NULL                                       -- IANA time zone name (e.g. 'America/New_York'), used to
+                    DEFAULT 'UTC',                                      -- determine the local date of workouts
 
 	PRIMARY KEY(`user_id`),
     CHECK( length(`user_id`) = 16 ),
     CHECK( length(`email`) > 0 ),
-    CHECK( length(`secret`) > 0 )
-    -- CHECK( length(`secret`) = 64 )
+    CHECK( length(`key`) = 32 )
 
 ) WITHOUT ROWID;
 
@@ -28,22 +35,60 @@ CREATE INDEX IF NOT EXISTS `users_email` ON `users` (
 	`email`
 );
 
+-- public keys used to verify signed requests. a user may have several active keys (e.g. one per device),
+-- and keys may be rotated by adding a new key and revoking the old one
+CREATE TABLE IF NOT EXISTS `user_keys` (
+	`key_id`	    BLOB ( 16 ) NOT NULL UNIQUE,
+
+	`user_id`	    BLOB ( 16 ) NOT NULL,
+
+	`key`	        BLOB ( 32 ) NOT NULL,                               -- ed25519 public key
+
+    `created`  	    INTEGER ( 8 ) NOT NULL,                             -- unix timestamp, microseconds
+
+    `expires`  	    INTEGER ( 8 ),                                      -- null: never expires
+
+    `revoked`  	    INTEGER ( 8 ),                                      -- null: not revoked
+
+	PRIMARY KEY(`key_id`),
+
+	FOREIGN KEY(`user_id`) REFERENCES `users`(`user_id`)
+        ON DELETE CASCADE ON UPDATE CASCADE,
+
+    CONSTRAINT `user_key_uniq` UNIQUE(`user_id`, `key`),
+
+    CHECK( length(`key_id`) = 16 ),
+    CHECK( length(`key`) = 32 )
+
+) WITHOUT ROWID;
+
+CREATE INDEX IF NOT EXISTS `user_keys_user_id` ON `user_keys` (
+	`user_id`
+);
+
 CREATE TABLE IF NOT EXISTS `workouts` (
-	`workout_id`	BLOB ( 16 ) NOT NULL UNIQUE
-                    DEFAULT ( uuid_blob( uuid() ) ),
+	`workout_id`	BLOB ( 16 ) NOT NULL UNIQUE,
 
 	`user_id`	    BLOB ( 16 ) NOT NULL,
 
-	`start`	        INTEGER ( 8 ) NOT NULL,                             -- unix timestamp
+	`start_time`	INTEGER ( 8 ) NOT NULL,                             -- unix timestamp, microseconds
+
+    `end_time`  	INTEGER ( 8 ) NOT NULL,                             -- unix timestamp, microseconds
+
+    `category`      TEXT                                                -- null: uncategorized
+                    CHECK( `category` IN ('strength', 'cardio', 'mobility', 'sport', 'other') ),
 
-    `end`  	        INTEGER ( 8 ) NOT NULL,                             -- unix timestamp
+    `tags`          TEXT NOT NULL                                       -- free-form labels, as json array
+                    DEFAULT '[]',
+
+    `note`          TEXT,
 
 	PRIMARY KEY(`workout_id`),
 
 	FOREIGN KEY(`user_id`) REFERENCES `users`(`user_id`)
         ON DELETE CASCADE ON UPDATE CASCADE,
 
-    CHECK( `end` >= `start` ),
+    CONSTRAINT `user_start_uniq` UNIQUE(`user_id`, `start_time`),      -- application code assumes this condition
 
     CHECK( length(`workout_id`) = 16),
 
@@ -51,33 +96,80 @@ CREATE TABLE IF NOT EXISTS `workouts` (
 
 ) WITHOUT ROWID;
 
-CREATE INDEX IF NOT EXISTS `workouts_start` ON `workouts` (
-	`start`	DESC
+CREATE INDEX IF NOT EXISTS `workouts_start_time` ON `workouts` (
+	`start_time`	DESC
 );
 
-CREATE INDEX IF NOT EXISTS `user_workouts` ON `workouts` (
+CREATE INDEX IF NOT EXISTS `workouts_user_start_time` ON `workouts` (
 	`user_id`,
-	`start`	DESC
+	`start_time`	DESC
 );
 
-CREATE VIEW workout_durations AS
+-- optional detail of a workout: exercises in the order performed, each with sets
+CREATE TABLE IF NOT EXISTS `exercises` (
+	`workout_id`	BLOB ( 16 ) NOT NULL,
+
+    `position`      INTEGER NOT NULL                                    -- order within the workout, starting at 0
+                    CHECK( `position` >= 0 ),
+
+    `name`          TEXT NOT NULL
+                    CHECK( length(`name`) > 0 ),
+
+	PRIMARY KEY(`workout_id`, `position`),
+
+	FOREIGN KEY(`workout_id`) REFERENCES `workouts`(`workout_id`)
+        ON DELETE CASCADE
+
+) WITHOUT ROWID;
+
+CREATE TABLE IF NOT EXISTS `exercise_sets` (
+	`workout_id`	        BLOB ( 16 ) NOT NULL,
+
+    `exercise_position`     INTEGER NOT NULL,
+
+    `position`              INTEGER NOT NULL                            -- order within the exercise, starting at 0
+                            CHECK( `position` >= 0 ),
+
+    `reps`                  INTEGER NOT NULL
+                            CHECK( `reps` >= 0 ),
+
+    `weight`                REAL                                        -- null: bodyweight
+                            CHECK( `weight` >= 0 ),
+
+    `unit`                  TEXT NOT NULL
+                            DEFAULT 'kg'
+                            CHECK( `unit` IN ('kg', 'lb') ),
+
+    `rpe`                   REAL                                        -- rate of perceived exertion
+                            CHECK( `rpe` BETWEEN 1 AND 10 ),
+
+	PRIMARY KEY(`workout_id`, `exercise_position`, `position`),
+
+	FOREIGN KEY(`workout_id`, `exercise_position`) REFERENCES `exercises`(`workout_id`, `position`)
+        ON DELETE CASCADE
+
+) WITHOUT ROWID;
+
+-- useful for debugging/cli purposes
+CREATE VIEW IF NOT EXISTS workout_durations AS
     SELECT
         u.email AS email,
-        uuid_str(u.user_id) AS user_id,
-        uuid_str(w.workout_id) AS workout_id,
-        date(w.start + (w.end - w.start) / 2, 'unixepoch') AS dt,
-        cast(round((w.end - w.start) / 60.0) as integer) AS duration_minutes
+        lower(hex(u.user_id)) AS user_id,
+        lower(hex(w.workout_id)) AS workout_id,
+        date(w.start_time / 1000000, 'unixepoch') AS dt,
+        cast(round((w.end_time - w.start_time) / 60000000.0) as integer) AS duration_minutes
     FROM `users` u
     INNER JOIN `workouts` w ON u.user_id = w.user_id
-    -- ORDER BY w.start DESC
 ;
 
-CREATE VIEW users_view AS
-    SELECT
-        uuid_str(u.user_id) AS user_id,
-        u.email AS email,
-        -- hex(u.secret) as secret,
-        datetime(u.created, 'unixepoch') as created
-    FROM users u;
+-- facilitate future schema upgrades. versions match those of schema-postgresql.sql
+CREATE TABLE IF NOT EXISTS `migrations` (
+    `id`            INTEGER PRIMARY KEY AUTOINCREMENT,
+
+    `version`       TEXT NOT NULL,
 
-COMMIT;
+    `descr`         TEXT,
+
+    `applied`       INTEGER ( 8 ) NOT NULL                              -- unix timestamp, seconds
+                    DEFAULT (strftime('%s', CURRENT_TIMESTAMP))
+);
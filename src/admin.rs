@@ -0,0 +1,93 @@
+use std::io::{self, Write};
+use std::convert::TryInto;
+use std::fs::OpenOptions;
+use std::path::Path;
+use std::sync::Mutex;
+use serde::{Serialize, Deserialize};
+use chrono::prelude::*;
+use hashbrown::HashMap;
+use uuid::Uuid;
+use crate::auth::PublicKey;
+
+/// admin key set plus the audit log that every admin request made on behalf of a user is
+/// written to. admin access is only possible when one of these has been given to the `Cache`
+/// (see `Cache::with_admin_access`).
+pub struct AdminAccess {
+    keys: HashMap<Uuid, PublicKey>,
+    audit_log: Mutex<Box<dyn Write + Send + Sync>>,
+}
+
+/// entry in admin csv file, e.g.:
+///
+/// ```text
+/// admin_id,name,public_key
+/// 9a7e84a8-3d2f-4d10-9b8b-5f0f2d6c1c11,jstrong,0s2d6BL16u0DR08MA+/Of6wZ5kQj5JdgX68lRyvBWHUg=
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AdminKeyRecord {
+    pub admin_id: Uuid,
+    pub name: String,
+    /// base64-encoded ed25519 public key
+    pub public_key: String,
+}
+
+/// one admin request made on behalf of a user, written to the audit log as a line of json
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub time: DateTime<Utc>,
+    pub admin_id: Uuid,
+    pub user_id: Uuid,
+    pub method: String,
+    pub path: String,
+    /// hex-encoded sha256 of request body
+    pub body_sha256: String,
+}
+
+impl AdminAccess {
+    pub fn new<W>(keys: HashMap<Uuid, PublicKey>, audit_log: W) -> Self
+        where W: Write + Send + Sync + 'static
+    {
+        Self {
+            keys,
+            audit_log: Mutex::new(Box::new(audit_log)),
+        }
+    }
+
+    /// like `new`, appending audit entries to the file at `audit_log_path`
+    pub fn open<P: AsRef<Path>>(keys: HashMap<Uuid, PublicKey>, audit_log_path: P) -> Result<Self, io::Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(audit_log_path)?;
+        Ok(Self::new(keys, file))
+    }
+
+    /// decode admin keys loaded from csv
+    pub fn decode_keys(records: &[AdminKeyRecord]) -> Result<HashMap<Uuid, PublicKey>, String> {
+        let mut keys = HashMap::new();
+        for r in records {
+            let key: PublicKey = base64::decode(&r.public_key).ok()
+                .and_then(|x| x.try_into().ok())
+                .ok_or_else(|| format!("invalid public key for admin {} ({})", r.admin_id, r.name))?;
+            keys.insert(r.admin_id, key);
+        }
+        Ok(keys)
+    }
+
+    pub fn key(&self, admin_id: &Uuid) -> Option<&PublicKey> {
+        self.keys.get(admin_id)
+    }
+
+    pub fn n_keys(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// write entry to audit log, flushing before returning
+    pub fn record(&self, entry: &AuditEntry) -> Result<(), io::Error> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let mut audit_log = self.audit_log.lock().unwrap();
+        audit_log.write_all(&line)?;
+        audit_log.flush()
+    }
+}
@@ -1,10 +1,23 @@
+use std::convert::TryFrom;
+use std::str::FromStr;
 use serde::{Serialize, Deserialize};
 use chrono::prelude::*;
 use uuid::Uuid;
-use crate::Workout;
+use crate::{Workout, Category, UserKey};
+use crate::query::{MatchMode, Order, WorkoutQuery};
+use chrono_tz::Tz;
+use crate::stats::{BucketSize, WorkoutBucket, WorkoutStats};
 
 pub const SIG_HEADER        : &str = "x-fitbod-access-signature";
 pub const TIMESTAMP_HEADER  : &str = "x-fitbod-access-timestamp";
+/// selects the signing scheme ("v1" or "v2"); requests without it are treated as "v1"
+pub const SCHEME_HEADER     : &str = "x-fitbod-access-scheme";
+/// optional: `key_id` of the key used to sign the request. if absent, all active keys are tried
+pub const KEY_ID_HEADER     : &str = "x-fitbod-access-key-id";
+/// `admin_id` of an admin signing a request on behalf of the user in the request body
+pub const ADMIN_ID_HEADER   : &str = "x-fitbod-admin-id";
+/// unix timestamp of the server, included in every response so clients can correct clock skew
+pub const SERVER_TIME_HEADER: &str = "x-fitbod-server-time";
 pub const API_VERSION       : &str = "v1";
 
 /// an api request to save a workout to the database
@@ -12,17 +25,77 @@ pub const API_VERSION       : &str = "v1";
 pub struct NewWorkoutsRequest {
     pub user_id: Uuid,
     pub items: Vec<Workout>,
+    /// what to do with items that overlap other workouts of the user
+    #[serde(default)]
+    pub overlap: OverlapPolicy,
 }
 
-/// api response to a `NewWorkoutRequest`
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// how a `NewWorkoutsRequest` handles an item that is in progress at the same time as another of
+/// the user's workouts, usually because one session was logged on two devices
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverlapPolicy {
+    /// save the item, listing the workouts it overlaps in `NewWorkoutResponse::Success`
+    #[default]
+    Accept,
+    /// don't save the item, see `ERR_OVERLAP`
+    Reject,
+    /// merge the item into the workout it overlaps (see `Workout::merged_with`). an item that
+    /// overlaps several workouts is rejected
+    Merge,
+}
+
+impl FromStr for OverlapPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "accept" => Ok(OverlapPolicy::Accept),
+            "reject" => Ok(OverlapPolicy::Reject),
+            "merge" => Ok(OverlapPolicy::Merge),
+            other => Err(format!("invalid overlap policy: {} (expected accept, reject or merge)", other)),
+        }
+    }
+}
+
+/// `err_code` in `NewWorkoutResponse::Error`: a different workout already exists for the user
+/// with the same `start_time`
+pub const ERR_START_TIME_CONFLICT   : u32 = 1001;
+/// `err_code` in `NewWorkoutResponse::Error`: a workout with the same `workout_id` already exists
+/// with a different `start_time`
+pub const ERR_WORKOUT_ID_CONFLICT   : u32 = 1002;
+/// `err_code` in `NewWorkoutResponse::Error`: the workout overlaps other workouts of the user, and
+/// the request's `OverlapPolicy` didn't allow saving or merging it
+pub const ERR_OVERLAP               : u32 = 1003;
+/// `err_code` in `NewWorkoutResponse::Error`: the workout overlaps one other workout, but merging
+/// them would give a workout that isn't valid, e.g. because it lasts too long
+pub const ERR_INVALID_MERGE         : u32 = 1004;
+
+/// api response to a `NewWorkoutRequest`, one per item, in the same order as the request items
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(tag = "result")]
 #[serde(rename_all = "snake_case")]
 pub enum NewWorkoutResponse {
+    /// workout was saved
     Success {
         workout_id: Uuid,
+        /// other workouts of the user that the workout overlaps
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        overlaps: Vec<Uuid>,
+    },
+
+    /// workout overlapped `merged_into` and was merged into it, see `OverlapPolicy::Merge`
+    Merged {
+        workout_id: Uuid,
+        merged_into: Uuid,
     },
 
+    /// workout was saved previously, nothing was changed
+    Duplicate {
+        workout_id: Uuid,
+    },
+
+    /// workout was not saved. `err_code` is one of the `ERR_*` constants
     Error {
         workout_id: Uuid,
         err_code: u32,
@@ -30,6 +103,41 @@ pub enum NewWorkoutResponse {
     },
 }
 
+/// api request to change the start and/or end time of an existing workout. omitted fields
+/// are left unchanged
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateWorkoutRequest {
+    pub user_id: Uuid,
+    pub workout_id: Uuid,
+    #[serde(default)]
+    pub start_time: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub end_time: Option<DateTime<Utc>>,
+    /// `null` removes the workout's category
+    #[serde(default, deserialize_with = "double_option", skip_serializing_if = "Option::is_none")]
+    pub category: Option<Option<Category>>,
+    /// replaces all tags of the workout
+    #[serde(default)]
+    pub tags: Option<Vec<String>>,
+    /// an empty note removes the workout's note
+    #[serde(default)]
+    pub note: Option<String>,
+}
+
+/// api request to fetch a single workout, including start and end times
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetWorkoutRequest {
+    pub user_id: Uuid,
+    pub workout_id: Uuid,
+}
+
+/// api request to delete a workout
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeleteWorkoutRequest {
+    pub user_id: Uuid,
+    pub workout_id: Uuid,
+}
+
 /// api request to list workouts for a user
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ListWorkoutsRequest {
@@ -40,14 +148,71 @@ pub struct ListWorkoutsRequest {
     pub end: Option<DateTime<Utc>>,
     #[serde(default)]
     pub limit: Option<usize>,
+    /// "desc" (newest first, the default) or "asc"
+    #[serde(default)]
+    pub order: Order,
+    /// "starts_within" (the default) or "overlaps", see `MatchMode`
+    #[serde(default)]
+    pub mode: MatchMode,
+    /// continue a previous listing, from the `cursor` of its last response
+    #[serde(default)]
+    pub cursor: Option<Cursor>,
+    /// IANA time zone name for item dates. defaults to the user's time zone
+    #[serde(default)]
+    pub tz: Option<Tz>,
+    /// only workouts in this category
+    #[serde(default)]
+    pub category: Option<Category>,
+    /// only workouts with all of these tags
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// opaque position in a workouts listing: the `start_time` and `workout_id` of the last workout
+/// on a page, as url-safe base64
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct Cursor {
+    pub start_time: DateTime<Utc>,
+    pub workout_id: Uuid,
 }
 
 /// listed workout in api response `ListWorkoutsResponse`
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ListWorkoutsItem {
     pub workout_id: Uuid,
+    /// date of `start_time` in the user's (or requested) time zone
     pub date: NaiveDate,
     pub duration_minutes: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category: Option<Category>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(flatten)]
+    pub summary: WorkoutSummary,
+}
+
+/// totals over a workout's exercises. all zero for workouts without exercises
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct WorkoutSummary {
+    #[serde(default)]
+    pub n_exercises: usize,
+    #[serde(default)]
+    pub n_sets: usize,
+    #[serde(default)]
+    pub total_reps: u64,
+    /// sum of reps times weight over all sets, in kg
+    #[serde(default)]
+    pub total_volume_kg: f64,
+}
+
+/// api response to `GetWorkoutRequest`: the workout with its exercises, plus summary totals
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkoutDetail {
+    #[serde(flatten)]
+    pub workout: Workout,
+    #[serde(flatten)]
+    pub summary: WorkoutSummary,
 }
 
 /// api response to `ListWorkoutsRequest`
@@ -56,6 +221,84 @@ pub struct ListWorkoutsResponse {
     pub user_id: Uuid,
     pub n_items: usize,
     pub items: Vec<ListWorkoutsItem>,
+    /// present if there may be more workouts: pass it as `cursor` in the next request to get
+    /// the next page
+    #[serde(default)]
+    pub cursor: Option<Cursor>,
+    /// time zone of item dates. missing from responses of older servers, whose dates are UTC
+    #[serde(default = "utc")]
+    pub tz: Tz,
+}
+
+/// api request for statistics over a user's workouts that started in `start..end`. all
+/// workouts are included if both are omitted
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkoutStatsRequest {
+    pub user_id: Uuid,
+    #[serde(default)]
+    pub start: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub end: Option<DateTime<Utc>>,
+    /// IANA time zone name that days and weeks follow. defaults to the user's time zone
+    #[serde(default)]
+    pub tz: Option<Tz>,
+}
+
+/// api response to `WorkoutStatsRequest`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkoutStatsResponse {
+    pub user_id: Uuid,
+    pub start: Option<DateTime<Utc>>,
+    pub end: Option<DateTime<Utc>>,
+    /// missing from responses of older servers, which count days and weeks in UTC
+    #[serde(default = "utc")]
+    pub tz: Tz,
+    #[serde(flatten)]
+    pub stats: WorkoutStats,
+}
+
+/// api request for a series of workout counts and minutes per day, week or month
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AggregateWorkoutsRequest {
+    pub user_id: Uuid,
+    pub bucket: BucketSize,
+    /// defaults to the start of the earliest workout's bucket
+    #[serde(default)]
+    pub start: Option<DateTime<Utc>>,
+    /// defaults to now
+    #[serde(default)]
+    pub end: Option<DateTime<Utc>>,
+    /// IANA time zone name (e.g. "Asia/Tokyo") that bucket boundaries follow. defaults to the
+    /// user's time zone
+    #[serde(default)]
+    pub tz: Option<Tz>,
+}
+
+/// api request to set the user's time zone, used for workout dates unless a request specifies
+/// one. if `tz` is omitted, the current time zone is returned unchanged
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserTimeZoneRequest {
+    pub user_id: Uuid,
+    /// IANA time zone name, e.g. "America/Chicago"
+    #[serde(default)]
+    pub tz: Option<Tz>,
+}
+
+/// api response to `UserTimeZoneRequest`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserTimeZoneResponse {
+    pub user_id: Uuid,
+    pub tz: Tz,
+}
+
+/// api response to `AggregateWorkoutsRequest`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AggregateWorkoutsResponse {
+    pub user_id: Uuid,
+    pub bucket: BucketSize,
+    pub tz: Tz,
+    pub n_buckets: usize,
+    pub buckets: Vec<WorkoutBucket>,
 }
 
 /// api response representing various kinds of events
@@ -64,6 +307,10 @@ pub struct ListWorkoutsResponse {
 #[serde(rename_all = "snake_case")]
 pub enum Event {
     NewWorkout(ListWorkoutsItem),
+    WorkoutUpdated(ListWorkoutsItem),
+    WorkoutDeleted {
+        workout_id: Uuid,
+    },
     DopamineShot {
         message: String
     },
@@ -73,6 +320,57 @@ pub enum Event {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SubscribeEventsRequest {
     pub user_id: Uuid,
+    /// how long to wait for new events before returning an empty list. defaults to
+    /// `DEFAULT_SUBSCRIBE_TIMEOUT_SECS`, and is capped at `MAX_SUBSCRIBE_TIMEOUT_SECS`
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+}
+
+pub const DEFAULT_SUBSCRIBE_TIMEOUT_SECS    : u64 = 30;
+pub const MAX_SUBSCRIBE_TIMEOUT_SECS        : u64 = 120;
+
+/// first message a client sends on an `events/ws` websocket connection.
+///
+/// `signature` is a v2 signature over `request` (the body), using the method (`GET`), path
+/// and host of the websocket upgrade request. once verified, the server sends each new `Event`
+/// as a json text message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventStreamHandshake {
+    pub timestamp: i64,
+    /// base64-encoded ed25519 signature
+    pub signature: String,
+    #[serde(default)]
+    pub key_id: Option<Uuid>,
+    /// json-encoded `SubscribeEventsRequest`, exactly as signed
+    pub request: String,
+}
+
+/// query string of `GET events/sse`, for clients that can't set request headers, e.g. a
+/// browser `EventSource`. the first four fields are those of `EventStreamHandshake`, signed
+/// the same way, with the path `/api/v1/events/sse` and the host of the request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventStreamQuery {
+    pub timestamp: i64,
+    /// base64-encoded ed25519 signature
+    pub signature: String,
+    #[serde(default)]
+    pub key_id: Option<Uuid>,
+    /// json-encoded `SubscribeEventsRequest`, exactly as signed
+    pub request: String,
+    /// id of the last event received before reconnecting, like the `Last-Event-ID` header
+    #[serde(default)]
+    pub last_event_id: Option<u64>,
+}
+
+impl EventStreamQuery {
+    pub fn handshake(&self) -> EventStreamHandshake {
+        EventStreamHandshake {
+            timestamp: self.timestamp,
+            signature: self.signature.clone(),
+            key_id: self.key_id,
+            request: self.request.clone(),
+        }
+    }
 }
 
 /// api response that contains a list of new events for a user
@@ -83,17 +381,152 @@ pub struct NewEvents {
     pub items: Vec<Event>,
 }
 
-impl<'a> From<&'a Workout> for ListWorkoutsItem {
+/// api request to register a new user. must be signed with the private key matching `key`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegisterUserRequest {
+    pub email: String,
+    /// base64-encoded ed25519 public key
+    pub key: String,
+}
+
+/// api response to `RegisterUserRequest`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegisterUserResponse {
+    pub user_id: Uuid,
+    pub email: String,
+    pub key_id: Uuid,
+    pub created: DateTime<Utc>,
+}
+
+/// api request to add a public key for a user. must be signed by one of the user's existing keys
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NewKeyRequest {
+    pub user_id: Uuid,
+    /// base64-encoded ed25519 public key
+    pub key: String,
+    #[serde(default)]
+    pub expires: Option<DateTime<Utc>>,
+}
+
+/// api request to list a user's keys, including expired and revoked keys
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListKeysRequest {
+    pub user_id: Uuid,
+}
+
+/// api request to revoke one of a user's keys
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RevokeKeyRequest {
+    pub user_id: Uuid,
+    pub key_id: Uuid,
+}
+
+/// key in api responses `NewKeyRequest` and `ListKeysResponse`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyItem {
+    pub key_id: Uuid,
+    /// base64-encoded ed25519 public key
+    pub key: String,
+    pub created: DateTime<Utc>,
+    pub expires: Option<DateTime<Utc>>,
+    pub revoked: Option<DateTime<Utc>>,
+}
+
+/// api response to `ListKeysRequest`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListKeysResponse {
+    pub user_id: Uuid,
+    pub n_items: usize,
+    pub items: Vec<KeyItem>,
+}
+
+impl<'a> From<&'a UserKey> for KeyItem {
+    fn from(user_key: &'a UserKey) -> Self {
+        Self {
+            key_id: user_key.key_id,
+            key: base64::encode(&user_key.key[..]),
+            created: user_key.created,
+            expires: user_key.expires,
+            revoked: user_key.revoked,
+        }
+    }
+}
+
+impl ListWorkoutsItem {
+    /// `date` is the date of `start_time` in `tz`
+    pub fn new(workout: &Workout, tz: Tz) -> Self {
+        Self {
+            workout_id: workout.workout_id,
+            date: workout.start_time.with_timezone(&tz).date_naive(),
+            duration_minutes: workout.duration_minutes(),
+            category: workout.category,
+            tags: workout.tags.clone(),
+            summary: WorkoutSummary::from(workout),
+        }
+    }
+}
+
+impl<'a> From<&'a Workout> for WorkoutSummary {
     fn from(workout: &'a Workout) -> Self {
-        let &Workout { workout_id, start_time: start, end_time: end, .. } = workout;
+        let sets = workout.exercises.iter().flat_map(|x| x.sets.iter());
         Self {
-            workout_id,
-            date: start.date().naive_local(),
-            duration_minutes: ((end - start).num_seconds() as f64 / 60.0).round() as u32,
+            n_exercises: workout.exercises.len(),
+            n_sets: sets.clone().count(),
+            total_reps: sets.clone().map(|s| s.reps as u64).sum(),
+            // not `sum()`, which is -0.0 for no sets
+            total_volume_kg: sets.fold(0.0, |total, s| total + s.volume_kg()),
         }
     }
 }
 
+impl From<Workout> for WorkoutDetail {
+    fn from(workout: Workout) -> Self {
+        let summary = WorkoutSummary::from(&workout);
+        Self { workout, summary }
+    }
+}
+
+impl UpdateWorkoutRequest {
+    /// `existing` with the requested changes applied
+    pub fn apply(&self, existing: &Workout) -> Workout {
+        Workout {
+            start_time: self.start_time.unwrap_or(existing.start_time),
+            end_time: self.end_time.unwrap_or(existing.end_time),
+            category: self.category.unwrap_or(existing.category),
+            tags: self.tags.clone().unwrap_or_else(|| existing.tags.clone()),
+            note: match &self.note {
+                Some(note) if note.is_empty() => None,
+                Some(note) => Some(note.clone()),
+                None => existing.note.clone(),
+            },
+            ..existing.clone()
+        }
+    }
+}
+
+/// deserializes a field that is present, even as `null`, as `Some`, so with `#[serde(default)]`
+/// an omitted field (`None`) can be told apart from an explicit `null` (`Some(None)`)
+fn double_option<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+    where T: Deserialize<'de>,
+          D: serde::Deserializer<'de>
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// default time zone of responses that don't name one
+fn utc() -> Tz {
+    chrono_tz::UTC
+}
+
+impl SubscribeEventsRequest {
+    pub fn timeout(&self) -> std::time::Duration {
+        let secs = self.timeout_secs
+            .unwrap_or(DEFAULT_SUBSCRIBE_TIMEOUT_SECS)
+            .min(MAX_SUBSCRIBE_TIMEOUT_SECS);
+        std::time::Duration::from_secs(secs)
+    }
+}
+
 impl From<Uuid> for ListWorkoutsRequest {
     fn from(user_id: Uuid) -> Self {
         Self {
@@ -101,6 +534,240 @@ impl From<Uuid> for ListWorkoutsRequest {
             start: None,
             end: None,
             limit: None,
+            order: Order::default(),
+            mode: MatchMode::default(),
+            cursor: None,
+            tz: None,
+            category: None,
+            tags: Vec::new(),
         }
     }
 }
+
+impl ListWorkoutsRequest {
+    pub fn query(&self) -> WorkoutQuery {
+        WorkoutQuery {
+            start: self.start,
+            end: self.end,
+            order: self.order,
+            mode: self.mode,
+            limit: self.limit,
+            cursor: self.cursor,
+            category: self.category,
+            tags: self.tags.clone(),
+        }
+    }
+}
+
+impl ListWorkoutsResponse {
+    /// build a response page from `workouts`, which should be fetched with a limit one higher
+    /// than `limit`, so that it is known whether there are more. item dates are in `tz`
+    pub fn new(user_id: Uuid, mut workouts: Vec<Workout>, limit: Option<usize>, tz: Tz) -> Self {
+        let cursor = match limit {
+            Some(limit) if workouts.len() > limit => {
+                workouts.truncate(limit);
+                workouts.last().map(Cursor::from)
+            }
+
+            _ => None,
+        };
+        let items: Vec<ListWorkoutsItem> = workouts.iter().map(|w| ListWorkoutsItem::new(w, tz)).collect();
+        Self {
+            user_id,
+            n_items: items.len(),
+            items,
+            cursor,
+            tz,
+        }
+    }
+}
+
+impl WorkoutStatsRequest {
+    /// selects the workouts in range, oldest first, as `WorkoutStats::compute` expects
+    pub fn query(&self) -> WorkoutQuery {
+        WorkoutQuery {
+            start: self.start,
+            end: self.end,
+            order: Order::Asc,
+            ..Default::default()
+        }
+    }
+}
+
+impl Cursor {
+    /// whether `workout` comes after this position when listing newest first
+    pub fn is_before(&self, workout: &Workout) -> bool {
+        (workout.start_time, workout.workout_id) < (self.start_time, self.workout_id)
+    }
+
+    /// whether `workout` comes after this position when listing oldest first
+    pub fn is_after(&self, workout: &Workout) -> bool {
+        (workout.start_time, workout.workout_id) > (self.start_time, self.workout_id)
+    }
+
+    pub fn encode(&self) -> String {
+        let mut buf = Vec::with_capacity(28);
+        buf.extend_from_slice(&self.start_time.timestamp().to_be_bytes());
+        buf.extend_from_slice(&self.start_time.timestamp_subsec_nanos().to_be_bytes());
+        buf.extend_from_slice(self.workout_id.as_bytes());
+        base64::encode_config(&buf, base64::URL_SAFE_NO_PAD)
+    }
+
+    pub fn decode(encoded: &str) -> Option<Self> {
+        let buf = base64::decode_config(encoded, base64::URL_SAFE_NO_PAD).ok()?;
+        if buf.len() != 28 {
+            return None
+        }
+        let secs = i64::from_be_bytes(<[u8; 8]>::try_from(&buf[..8]).ok()?);
+        let nanos = u32::from_be_bytes(<[u8; 4]>::try_from(&buf[8..12]).ok()?);
+        let start_time = Utc.timestamp_opt(secs, nanos).single()?;
+        let workout_id = Uuid::from_slice(&buf[12..]).ok()?;
+        Some(Self { start_time, workout_id })
+    }
+}
+
+impl<'a> From<&'a Workout> for Cursor {
+    fn from(workout: &'a Workout) -> Self {
+        Self { start_time: workout.start_time, workout_id: workout.workout_id }
+    }
+}
+
+impl From<Cursor> for String {
+    fn from(cursor: Cursor) -> Self {
+        cursor.encode()
+    }
+}
+
+impl TryFrom<String> for Cursor {
+    type Error = String;
+
+    fn try_from(encoded: String) -> Result<Self, Self::Error> {
+        encoded.parse()
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = String;
+
+    fn from_str(encoded: &str) -> Result<Self, Self::Err> {
+        Self::decode(encoded).ok_or_else(|| format!("invalid cursor: {}", encoded))
+    }
+}
+
+#[cfg(test)]
+#[allow(unused)]
+mod tests {
+    use super::*;
+    use crate::{Exercise, ExerciseSet, WeightUnit};
+
+    #[test]
+    fn exercises_round_trip_and_are_optional() {
+        let user_id = Uuid::new_v4();
+        let start_time = Utc.with_ymd_and_hms(2021, 7, 27, 6, 30, 0).unwrap();
+        let plain = Workout {
+            user_id,
+            workout_id: Uuid::new_v4(),
+            start_time,
+            end_time: start_time + chrono::Duration::minutes(50),
+            exercises: Vec::new(),
+            category: None,
+            tags: Vec::new(),
+            note: None,
+        };
+        let detailed = Workout {
+            workout_id: Uuid::new_v4(),
+            exercises: vec![
+                Exercise {
+                    name: "deadlift".to_string(),
+                    sets: vec![
+                        ExerciseSet { reps: 5, weight: Some(140.0), unit: WeightUnit::Kg, rpe: Some(8.0) },
+                        ExerciseSet { reps: 3, weight: Some(315.0), unit: WeightUnit::Lb, rpe: None },
+                    ],
+                },
+                Exercise {
+                    name: "push-up".to_string(),
+                    sets: vec![ExerciseSet { reps: 20, weight: None, unit: WeightUnit::Kg, rpe: None }],
+                },
+            ],
+            ..plain.clone()
+        };
+
+        let req = NewWorkoutsRequest { user_id, items: vec![plain.clone(), detailed.clone()], overlap: Default::default() };
+        let json = serde_json::to_string(&req).unwrap();
+        let parsed: NewWorkoutsRequest = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.items, vec![plain.clone(), detailed.clone()]);
+
+        // workouts without exercises serialize exactly as before
+        let plain_json = serde_json::to_value(&plain).unwrap();
+        assert_eq!(plain_json.as_object().unwrap().len(), 4);
+        assert!(plain_json.get("exercises").is_none());
+        let minimal = r#"{"name": "plank", "sets": [{"reps": 1}]}"#;
+        let exercise: Exercise = serde_json::from_str(minimal).unwrap();
+        assert_eq!(exercise.sets[0], ExerciseSet { reps: 1, weight: None, unit: WeightUnit::Kg, rpe: None });
+
+        let summary = WorkoutSummary::from(&detailed);
+        assert_eq!(summary.n_exercises, 2);
+        assert_eq!(summary.n_sets, 3);
+        assert_eq!(summary.total_reps, 28);
+        assert!((summary.total_volume_kg - (700.0 + 3.0 * 315.0 * crate::KG_PER_LB)).abs() < 1e-9);
+        assert_eq!(WorkoutSummary::from(&plain), WorkoutSummary::default());
+        assert_eq!(serde_json::to_value(WorkoutSummary::from(&plain)).unwrap()["total_volume_kg"].to_string(), "0.0");
+
+        // list items keep their existing fields, and items from before the summary fields still
+        // parse
+        let item = serde_json::to_value(ListWorkoutsItem::new(&detailed, chrono_tz::UTC)).unwrap();
+        for field in &["workout_id", "date", "duration_minutes", "n_exercises", "n_sets", "total_reps", "total_volume_kg"] {
+            assert!(item.get(field).is_some(), "{}", field);
+        }
+        let old_item = r#"{"workout_id": "0f8fad5b-d9cb-469f-a165-70867728950e", "date": "2021-07-27", "duration_minutes": 50}"#;
+        let parsed: ListWorkoutsItem = serde_json::from_str(old_item).unwrap();
+        assert_eq!(parsed.summary, WorkoutSummary::default());
+
+        let detail = serde_json::to_value(WorkoutDetail::from(detailed.clone())).unwrap();
+        assert_eq!(detail["exercises"][0]["name"], "deadlift");
+        assert_eq!(detail["n_sets"], 3);
+        let parsed: WorkoutDetail = serde_json::from_value(detail).unwrap();
+        assert_eq!(parsed.workout, detailed);
+    }
+
+    #[test]
+    fn update_keeps_omitted_fields_and_clears_null_category() {
+        let start_time = Utc.with_ymd_and_hms(2021, 7, 27, 6, 30, 0).unwrap();
+        let existing = Workout {
+            user_id: Uuid::new_v4(),
+            workout_id: Uuid::new_v4(),
+            start_time,
+            end_time: start_time + chrono::Duration::minutes(50),
+            exercises: Vec::new(),
+            category: Some(Category::Strength),
+            tags: vec!["gym".to_string()],
+            note: Some("felt strong".to_string()),
+        };
+        let update = |fields: &str| -> Workout {
+            let json = format!(r#"{{"user_id": "{}", "workout_id": "{}"{}}}"#, existing.user_id, existing.workout_id, fields);
+            serde_json::from_str::<UpdateWorkoutRequest>(&json).unwrap().apply(&existing)
+        };
+
+        assert_eq!(update(""), existing);
+        assert_eq!(update(r#", "category": "cardio""#).category, Some(Category::Cardio));
+        let cleared = update(r#", "category": null, "note": """#);
+        assert_eq!(cleared, Workout { category: None, note: None, ..existing.clone() });
+
+        // omitted and cleared categories serialize differently
+        let req: UpdateWorkoutRequest = serde_json::from_str(&format!(
+            r#"{{"user_id": "{}", "workout_id": "{}", "category": null}}"#, existing.user_id, existing.workout_id)).unwrap();
+        assert_eq!(req.category, Some(None));
+        assert!(serde_json::to_value(&req).unwrap()["category"].is_null());
+        let req = UpdateWorkoutRequest { category: None, ..req };
+        assert!(serde_json::to_value(&req).unwrap().get("category").is_none());
+    }
+
+    #[test]
+    fn list_response_of_older_server_parses() {
+        let user_id = Uuid::new_v4();
+        let json = format!(r#"{{"user_id": "{}", "n_items": 0, "items": []}}"#, user_id);
+        let resp: ListWorkoutsResponse = serde_json::from_str(&json).unwrap();
+        assert!(resp.cursor.is_none());
+        assert_eq!(resp.tz, chrono_tz::UTC);
+    }
+}
@@ -1,4 +1,6 @@
+use std::convert::TryInto;
 use rand::prelude::*;
+use crypto::digest::Digest;
 
 pub type PrivateKey     = [u8; 64];
 pub type PublicKey      = [u8; 32];
@@ -16,7 +18,7 @@ pub fn verify_request(sig: &[u8], timestamp: &[u8], body: &[u8], pub_key: &[u8],
     buf.extend_from_slice(timestamp);
     buf.extend_from_slice(body);
     let n = buf.len();
-    if let Err(_) = base64::decode_config_buf(sig, base64::STANDARD, buf) {
+    if base64::decode_config_buf(sig, base64::STANDARD, buf).is_err() {
         return false
     }
     let msg = &buf[..n];
@@ -29,15 +31,182 @@ pub fn verify_request(sig: &[u8], timestamp: &[u8], body: &[u8], pub_key: &[u8],
     crypto::ed25519::verify(msg, pub_key, decoded_sig)
 }
 
+/// request signing scheme, selected by the `x-fitbod-access-scheme` header
+///
+/// - `V1`: signature over `timestamp || body` (the default when the header is absent)
+/// - `V2`: signature over a canonical string that also covers method, path and host (see
+///   `canonical_request_v2`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureScheme {
+    V1,
+    V2,
+}
+
+impl SignatureScheme {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SignatureScheme::V1 => "v1",
+            SignatureScheme::V2 => "v2",
+        }
+    }
+
+    pub fn from_header(value: &[u8]) -> Option<Self> {
+        match value {
+            b"v1" => Some(SignatureScheme::V1),
+            b"v2" => Some(SignatureScheme::V2),
+            _ => None,
+        }
+    }
+}
+
+/// the parts of an http request, besides timestamp and body, covered by a v2 signature
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestTarget<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    pub host: &'a str,
+}
+
+/// build the string signed under the v2 scheme:
+///
+/// ```text
+/// FITBOD-V2\n
+/// <timestamp>\n
+/// <METHOD>\n
+/// <path>\n
+/// <host>\n
+/// <hex-encoded sha256 of body>
+/// ```
+///
+/// method is upper-cased and host is lower-cased before signing, since both are
+/// case-insensitive in http.
+pub fn canonical_request_v2(timestamp: &[u8], target: &RequestTarget, body: &[u8], buf: &mut Vec<u8>) {
+    let body_hash = sha256_hex(body);
+
+    buf.clear();
+    buf.extend_from_slice(b"FITBOD-V2\n");
+    buf.extend_from_slice(timestamp);
+    buf.push(b'\n');
+    buf.extend_from_slice(target.method.to_ascii_uppercase().as_bytes());
+    buf.push(b'\n');
+    buf.extend_from_slice(target.path.as_bytes());
+    buf.push(b'\n');
+    buf.extend_from_slice(target.host.to_ascii_lowercase().as_bytes());
+    buf.push(b'\n');
+    buf.extend_from_slice(body_hash.as_bytes());
+}
+
+/// check v2 request signature using provided public key
+pub fn verify_request_v2(sig: &[u8], timestamp: &[u8], target: &RequestTarget, body: &[u8], pub_key: &[u8], buf: &mut Vec<u8>) -> bool {
+    let decoded_sig = match decode_signature(sig) {
+        Some(decoded_sig) => decoded_sig,
+        None => return false,
+    };
+    canonical_request_v2(timestamp, target, body, buf);
+
+    debug_assert_eq!(pub_key.len(), 32);
+
+    crypto::ed25519::verify(&buf[..], pub_key, &decoded_sig[..])
+}
+
+/// hex-encoded sha256 digest
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    let mut hasher = crypto::sha2::Sha256::new();
+    hasher.input(bytes);
+    hasher.result_str()
+}
+
+/// parse the decimal unix timestamp sent in the `x-fitbod-access-timestamp` header
+pub fn parse_timestamp(timestamp: &[u8]) -> Option<i64> {
+    std::str::from_utf8(timestamp).ok()?
+        .parse()
+        .ok()
+}
+
+/// decode base64-encoded signature, returning `None` if it is not a 64-byte ed25519 signature
+pub fn decode_signature(sig: &[u8]) -> Option<[u8; 64]> {
+    let decoded = base64::decode(sig).ok()?;
+    decoded.try_into().ok()
+}
+
 /// generate base64-encoded signature using provided private key
 pub fn sign_request(unix_timestamp: i64, request_body: &str, priv_key: &PrivateKey) -> String {
     let signature_contents = format!("{}{}", unix_timestamp, request_body);
     let sig = crypto::ed25519::signature(signature_contents.as_bytes(), &priv_key[..]);
-    let encoded_sig = base64::encode(&sig[..]);
-    encoded_sig
+    base64::encode(&sig[..])
+}
+
+/// builder for signing a request under either scheme
+///
+/// ```
+/// let (priv_key, _) = fitbod::auth::gen_keypair();
+/// let sig = fitbod::auth::RequestSigner::new(1627062582, r#"{"user_id":"3a2cbc79-00e5-4598-a5b2-74c5059724af"}"#)
+///     .path("/api/v1/workouts/list")
+///     .host("fitbod.me")
+///     .sign(&priv_key);
+/// assert_eq!(base64::decode(&sig).unwrap().len(), 64);
+/// ```
+#[derive(Debug, Clone)]
+pub struct RequestSigner<'a> {
+    scheme: SignatureScheme,
+    timestamp: i64,
+    method: &'a str,
+    path: &'a str,
+    host: &'a str,
+    body: &'a str,
+}
+
+impl<'a> RequestSigner<'a> {
+    /// defaults to the v2 scheme and the `POST` method
+    pub fn new(unix_timestamp: i64, request_body: &'a str) -> Self {
+        Self {
+            scheme: SignatureScheme::V2,
+            timestamp: unix_timestamp,
+            method: "POST",
+            path: "/",
+            host: "",
+            body: request_body,
+        }
+    }
+
+    pub fn scheme(mut self, scheme: SignatureScheme) -> Self {
+        self.scheme = scheme;
+        self
+    }
+
+    pub fn method(mut self, method: &'a str) -> Self {
+        self.method = method;
+        self
+    }
+
+    pub fn path(mut self, path: &'a str) -> Self {
+        self.path = path;
+        self
+    }
+
+    pub fn host(mut self, host: &'a str) -> Self {
+        self.host = host;
+        self
+    }
+
+    /// generate base64-encoded signature using provided private key
+    pub fn sign(&self, priv_key: &PrivateKey) -> String {
+        match self.scheme {
+            SignatureScheme::V1 => sign_request(self.timestamp, self.body, priv_key),
+
+            SignatureScheme::V2 => {
+                let timestamp = self.timestamp.to_string();
+                let target = RequestTarget { method: self.method, path: self.path, host: self.host };
+                let mut buf = Vec::new();
+                canonical_request_v2(timestamp.as_bytes(), &target, self.body.as_bytes(), &mut buf);
+                let sig = crypto::ed25519::signature(&buf[..], &priv_key[..]);
+                base64::encode(&sig[..])
+            }
+        }
+    }
 }
 
-#[allow(unused)]
+#[allow(unused, clippy::bool_assert_comparison)]
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -56,6 +225,35 @@ mod tests {
         assert_eq!(verify_request(sig.as_bytes(), ts_str.as_bytes(), body.as_bytes(), &pub_key[..], &mut buf), true);
     }
 
+    #[test]
+    fn v2_signature_covers_method_path_and_host() {
+        let (priv_key, pub_key) = gen_keypair();
+        let ts = Utc::now().timestamp();
+        let body = r#"{"user_id":"3a2cbc79-00e5-4598-a5b2-74c5059724af"}"#;
+        let sig = RequestSigner::new(ts, body)
+            .path("/api/v1/workouts/list")
+            .host("fitbod.me")
+            .sign(&priv_key);
+        let ts_str = ts.to_string();
+        let mut buf = Vec::new();
+        let verify = |method, path, host, body: &str, buf: &mut Vec<u8>| {
+            let target = RequestTarget { method, path, host };
+            verify_request_v2(sig.as_bytes(), ts_str.as_bytes(), &target, body.as_bytes(), &pub_key[..], buf)
+        };
+        assert!(verify("POST", "/api/v1/workouts/list", "fitbod.me", body, &mut buf));
+        assert!(verify("post", "/api/v1/workouts/list", "FITBOD.me", body, &mut buf));
+        assert!(!verify("POST", "/api/v1/workouts/new", "fitbod.me", body, &mut buf));
+        assert!(!verify("GET", "/api/v1/workouts/list", "fitbod.me", body, &mut buf));
+        assert!(!verify("POST", "/api/v1/workouts/list", "evil.example", body, &mut buf));
+        assert!(!verify("POST", "/api/v1/workouts/list", "fitbod.me", "{}", &mut buf));
+        // a v2 signature is not valid under v1, and vice versa
+        assert!(!verify_request(sig.as_bytes(), ts_str.as_bytes(), body.as_bytes(), &pub_key[..], &mut buf));
+        let v1_sig = RequestSigner::new(ts, body).scheme(SignatureScheme::V1).sign(&priv_key);
+        assert_eq!(v1_sig, sign_request(ts, body, &priv_key));
+        let target = RequestTarget { method: "POST", path: "/", host: "" };
+        assert!(!verify_request_v2(v1_sig.as_bytes(), ts_str.as_bytes(), &target, body.as_bytes(), &pub_key[..], &mut buf));
+    }
+
     #[test]
     fn check_ed25519_sig_example_in_api_docs() {
         let priv_key_encoded = "jCNLYN8zGyiVM7omRHGlY1iyJuvAZBWZGuN+9TjaWJTSzZ3oEvXq7QNHTwwD785/rBnmRCPkl2D68lRyvBWHUg==";
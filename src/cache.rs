@@ -1,20 +1,435 @@
-use std::sync::{Arc, RwLock};
-use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock, Mutex};
+use std::convert::TryInto;
+use std::collections::{BTreeMap, VecDeque};
 use serde::{Serialize, Deserialize};
 use uuid::Uuid;
 use chrono::prelude::*;
-use hashbrown::HashMap;
-use crate::auth::PublicKey;
-use crate::{Workout, UserId};
+use chrono_tz::Tz;
+use hashbrown::{HashMap, HashSet};
+use crate::auth::{PublicKey, RequestTarget, SignatureScheme};
+use crate::{Workout, UserId, UserKey};
+use crate::admin::{AdminAccess, AuditEntry};
+use crate::api::{NewWorkoutResponse, OverlapPolicy, EventStreamHandshake, SubscribeEventsRequest};
+use crate::events::{EventQueues, StreamClosed};
+use crate::query::WorkoutQuery;
+use crate::stats::{Aggregation, WorkoutBucket, WorkoutStats};
 
-pub type UserKeys = Arc<RwLock<HashMap<Uuid, [u8; 32]>>>;
-pub type UserWorkouts = Arc<RwLock<HashMap<Uuid, BTreeMap<DateTime<Utc>, Workout>>>>;
+pub type UserKeys = Arc<RwLock<HashMap<Uuid, Vec<UserKey>>>>;
+pub type UserWorkouts = Arc<RwLock<HashMap<Uuid, UserWorkoutCache>>>;
+/// time zones of users whose time zone isn't UTC
+pub type UserTimeZones = Arc<RwLock<HashMap<Uuid, Tz>>>;
+/// per-user (timestamp, decoded signature) of recently verified requests, oldest first
+pub type SeenSignatures = Arc<Mutex<HashMap<Uuid, VecDeque<(i64, [u8; 64])>>>>;
+/// locks of users whose workouts are being written, see `Cache::lock_user_writes`
+pub type UserWriteLocks = Arc<Mutex<HashMap<Uuid, Arc<tokio::sync::Mutex<()>>>>>;
+
+/// settings controlling how strictly signed requests are checked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuthConfig {
+    /// max allowed difference (in seconds) between the request timestamp and server time,
+    /// in either direction
+    pub max_clock_skew: i64,
+    /// max number of recently seen signatures remembered per user for replay detection. once
+    /// full, the oldest entry is evicted.
+    pub max_seen_signatures: usize,
+    /// whether requests signed with the legacy v1 scheme (`timestamp || body`) are accepted
+    pub allow_v1_signatures: bool,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            max_clock_skew: 30,
+            max_seen_signatures: 1024,
+            allow_v1_signatures: true,
+        }
+    }
+}
+
+/// one user's cached workouts, ordered by `start_time`, plus an index from `workout_id` to
+/// `start_time` so lookups by id don't scan
+#[derive(Debug, Clone, Default)]
+pub struct UserWorkoutCache {
+    by_start_time: BTreeMap<DateTime<Utc>, Workout>,
+    start_times: HashMap<Uuid, DateTime<Utc>>,
+    /// duration in seconds of the longest workout inserted so far (not lowered on removal), which
+    /// bounds how far back a workout overlapping a given time can start
+    longest_secs: i64,
+}
+
+impl UserWorkoutCache {
+    pub fn get(&self, workout_id: &Uuid) -> Option<&Workout> {
+        let start_time = self.start_times.get(workout_id)?;
+        self.by_start_time.get(start_time)
+    }
+
+    pub fn get_at(&self, start_time: &DateTime<Utc>) -> Option<&Workout> {
+        self.by_start_time.get(start_time)
+    }
+
+    /// the caller must check that neither `workout_id` nor `start_time` is already cached
+    fn insert(&mut self, workout: Workout) {
+        debug_assert!(self.get(&workout.workout_id).is_none());
+        debug_assert!(self.get_at(&workout.start_time).is_none());
+        self.longest_secs = self.longest_secs.max((workout.end_time - workout.start_time).num_seconds());
+        self.start_times.insert(workout.workout_id, workout.start_time);
+        self.by_start_time.insert(workout.start_time, workout);
+    }
+
+    fn remove(&mut self, workout_id: &Uuid) -> Option<Workout> {
+        let start_time = self.start_times.remove(workout_id)?;
+        self.by_start_time.remove(&start_time)
+    }
+
+    /// workouts with `start_time` in `range`, in ascending order
+    pub fn range<R>(&self, range: R) -> impl DoubleEndedIterator<Item = &Workout>
+        where R: std::ops::RangeBounds<DateTime<Utc>>
+    {
+        self.by_start_time.range(range).map(|(_, w)| w)
+    }
+
+    /// workouts that overlap `workout` (see `Workout::overlaps`), other than itself, in ascending
+    /// order
+    pub fn overlapping<'a>(&'a self, workout: &'a Workout) -> impl Iterator<Item = &'a Workout> {
+        let earliest = (workout.start_time - chrono::Duration::seconds(self.longest_secs)).min(workout.end_time);
+        self.range(earliest..workout.end_time)
+            .filter(move |w| w.workout_id != workout.workout_id && w.overlaps(workout))
+    }
+
+    /// make the changes worked out by `PendingWorkouts`. workouts already cached with the same
+    /// `workout_id`, e.g. by a read that missed the cache and fetched them from the db after
+    /// they were saved, are replaced
+    fn apply(&mut self, pending: &PendingWrite) {
+        for workout in pending.merged.iter().chain(pending.inserted.iter()) {
+            self.remove(&workout.workout_id);
+        }
+        for workout in pending.merged.iter().chain(pending.inserted.iter()) {
+            if self.get_at(&workout.start_time).is_none() {
+                self.insert(workout.clone());
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        debug_assert_eq!(self.by_start_time.len(), self.start_times.len());
+        self.by_start_time.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_start_time.is_empty()
+    }
+}
+
+/// a user's cached workouts as they would be after caching a batch of new ones, without
+/// changing them. only the workouts the batch adds or changes are copied, into `changed`
+struct PendingWorkouts<'a> {
+    cached: Option<&'a UserWorkoutCache>,
+    changed: UserWorkoutCache,
+    /// ids of cached workouts that were changed (and are in `changed`) or removed
+    hidden: HashSet<Uuid>,
+}
+
+impl<'a> PendingWorkouts<'a> {
+    fn new(cached: Option<&'a UserWorkoutCache>) -> Self {
+        Self { cached, changed: UserWorkoutCache::default(), hidden: HashSet::new() }
+    }
+
+    fn get(&self, workout_id: &Uuid) -> Option<&Workout> {
+        self.changed.get(workout_id).or_else(|| {
+            self.cached?.get(workout_id).filter(|w| ! self.hidden.contains(&w.workout_id))
+        })
+    }
+
+    fn get_at(&self, start_time: &DateTime<Utc>) -> Option<&Workout> {
+        self.changed.get_at(start_time).or_else(|| {
+            self.cached?.get_at(start_time).filter(|w| ! self.hidden.contains(&w.workout_id))
+        })
+    }
+
+    fn insert(&mut self, workout: Workout) {
+        debug_assert!(self.get(&workout.workout_id).is_none());
+        debug_assert!(self.get_at(&workout.start_time).is_none());
+        self.changed.insert(workout);
+    }
+
+    fn remove(&mut self, workout_id: &Uuid) -> Option<Workout> {
+        if let Some(workout) = self.changed.remove(workout_id) {
+            return Some(workout)
+        }
+        let workout = self.cached?.get(workout_id)?;
+        if self.hidden.insert(*workout_id) { Some(workout.clone()) } else { None }
+    }
+
+    /// ids of workouts that overlap `workout`, see `UserWorkoutCache::overlapping`
+    fn overlapping(&self, workout: &Workout) -> Vec<Uuid> {
+        let mut overlapping: Vec<&Workout> = self.cached.into_iter()
+            .flat_map(|cached| cached.overlapping(workout))
+            .filter(|w| ! self.hidden.contains(&w.workout_id))
+            .chain(self.changed.overlapping(workout))
+            .collect();
+        overlapping.sort_unstable_by_key(|w| w.start_time);
+        overlapping.into_iter().map(|w| w.workout_id).collect()
+    }
+
+    /// insert a workout whose `workout_id` isn't taken, dealing with overlapping workouts as
+    /// `overlap` says. its `start_time` may only be taken already when merging. the id of a
+    /// workout that is merged into is added to `merged_ids`, unless it is there already
+    fn insert_new(&mut self, workout: &Workout, overlap: OverlapPolicy, merged_ids: &mut Vec<Uuid>) -> CacheOutcome {
+        let overlapping = self.overlapping(workout);
+
+        if overlap == OverlapPolicy::Merge && overlapping.len() == 1 {
+            let merged = self.get(&overlapping[0]).unwrap().merged_with(workout);
+            if let Err(e) = merged.validate_duration() {
+                return CacheOutcome::InvalidMerge { merged_into: overlapping[0], error: e.error }
+            }
+            return match self.get_at(&merged.start_time) {
+                Some(other) if other.workout_id != merged.workout_id => {
+                    CacheOutcome::StartTimeConflict { existing_workout_id: other.workout_id }
+                }
+
+                _ => {
+                    self.remove(&merged.workout_id).unwrap();
+                    if ! merged_ids.contains(&merged.workout_id) {
+                        merged_ids.push(merged.workout_id);
+                    }
+                    self.insert(merged);
+                    CacheOutcome::Merged { merged_into: overlapping[0] }
+                }
+            }
+        }
+
+        match (overlap, overlapping.is_empty(), self.get_at(&workout.start_time)) {
+            (OverlapPolicy::Reject, false, _) | (OverlapPolicy::Merge, false, _) => {
+                CacheOutcome::Overlap { overlapping_workout_ids: overlapping }
+            }
+
+            (_, _, Some(existing)) => CacheOutcome::StartTimeConflict { existing_workout_id: existing.workout_id },
+
+            (_, true, None) => {
+                self.insert(workout.clone());
+                CacheOutcome::Inserted
+            }
+
+            (OverlapPolicy::Accept, false, None) => {
+                self.insert(workout.clone());
+                CacheOutcome::InsertedOverlapping { overlapping_workout_ids: overlapping }
+            }
+        }
+    }
+
+    /// insert workouts with `insert_new`, unless their `workout_id` or `start_time` is taken,
+    /// and return the outcome for each item, plus what changed. see `Cache::try_cache_new_workouts`
+    fn insert_new_workouts(mut self, user_id: Uuid, workouts: &[Workout], overlap: OverlapPolicy) -> PendingWrite {
+        let mut outcomes = Vec::with_capacity(workouts.len());
+        let mut merged_ids = Vec::new();
+
+        for workout in workouts {
+            debug_assert_eq!(workout.user_id, user_id);
+
+            let outcome = match self.get_at(&workout.start_time) {
+                Some(existing) if existing.workout_id == workout.workout_id => CacheOutcome::Duplicate,
+
+                // a workout starting at the same time overlaps, so it may be merged into
+                Some(existing) if overlap != OverlapPolicy::Merge => {
+                    CacheOutcome::StartTimeConflict { existing_workout_id: existing.workout_id }
+                }
+
+                _ => {
+                    match self.get(&workout.workout_id) {
+                        Some(existing) => CacheOutcome::WorkoutIdConflict { existing_start_time: existing.start_time },
+
+                        None => self.insert_new(workout, overlap, &mut merged_ids),
+                    }
+                }
+            };
+
+            outcomes.push(outcome);
+        }
+
+        // later items may have been merged into earlier ones, so what is saved is what ended up
+        // in the cache
+        let inserted_ids: Vec<Uuid> = workouts.iter()
+            .zip(outcomes.iter())
+            .filter(|(_, outcome)| outcome.is_inserted())
+            .map(|(workout, _)| workout.workout_id)
+            .collect();
+        let inserted: Vec<Workout> = inserted_ids.iter()
+            .filter_map(|id| self.get(id).cloned())
+            .collect();
+        let merged: Vec<Workout> = merged_ids.iter()
+            .filter(|id| ! inserted_ids.contains(id))
+            .filter_map(|id| self.get(id).cloned())
+            .collect();
+
+        PendingWrite { user_id, outcomes, inserted, merged }
+    }
+}
+
+/// outcome of caching a single workout, see `Cache::try_cache_new_workouts`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CacheOutcome {
+    /// previously unseen workout, now cached
+    Inserted,
+    /// previously unseen workout, now cached, that overlaps other cached workouts
+    InsertedOverlapping {
+        overlapping_workout_ids: Vec<Uuid>,
+    },
+    /// previously unseen workout, merged into the cached workout it overlaps
+    Merged {
+        merged_into: Uuid,
+    },
+    /// not cached, since it overlaps other cached workouts
+    Overlap {
+        overlapping_workout_ids: Vec<Uuid>,
+    },
+    /// not cached, since merging it into the cached workout it overlaps would give an invalid
+    /// workout, e.g. one that lasts too long
+    InvalidMerge {
+        merged_into: Uuid,
+        error: String,
+    },
+    /// the same `workout_id` is already cached at the same `start_time`
+    Duplicate,
+    /// a different workout is already cached at the same `start_time`
+    StartTimeConflict {
+        existing_workout_id: Uuid,
+    },
+    /// the same `workout_id` is already cached with a different `start_time`
+    WorkoutIdConflict {
+        existing_start_time: DateTime<Utc>,
+    },
+}
+
+impl CacheOutcome {
+    /// whether the workout was cached as a workout of its own
+    pub fn is_inserted(&self) -> bool {
+        matches!(self, CacheOutcome::Inserted | CacheOutcome::InsertedOverlapping { .. })
+    }
+
+    pub fn to_response(&self, workout_id: Uuid) -> NewWorkoutResponse {
+        match *self {
+            CacheOutcome::Inserted => NewWorkoutResponse::Success { workout_id, overlaps: Vec::new() },
+
+            CacheOutcome::InsertedOverlapping { ref overlapping_workout_ids } => {
+                NewWorkoutResponse::Success { workout_id, overlaps: overlapping_workout_ids.clone() }
+            }
+
+            CacheOutcome::Merged { merged_into } => NewWorkoutResponse::Merged { workout_id, merged_into },
+
+            CacheOutcome::Overlap { ref overlapping_workout_ids } => {
+                let ids: Vec<String> = overlapping_workout_ids.iter().map(|id| id.to_string()).collect();
+                NewWorkoutResponse::Error {
+                    workout_id,
+                    err_code: crate::api::ERR_OVERLAP,
+                    msg: format!("workout overlaps workout(s) {}", ids.join(", ")),
+                }
+            }
+
+            CacheOutcome::InvalidMerge { merged_into, ref error } => {
+                NewWorkoutResponse::Error {
+                    workout_id,
+                    err_code: crate::api::ERR_INVALID_MERGE,
+                    msg: format!("merging into workout {} would give an invalid workout: {}", merged_into, error),
+                }
+            }
+
+            CacheOutcome::Duplicate => NewWorkoutResponse::Duplicate { workout_id },
+
+            CacheOutcome::StartTimeConflict { existing_workout_id } => {
+                NewWorkoutResponse::Error {
+                    workout_id,
+                    err_code: crate::api::ERR_START_TIME_CONFLICT,
+                    msg: format!("workout {} already exists with the same start_time", existing_workout_id),
+                }
+            }
+
+            CacheOutcome::WorkoutIdConflict { existing_start_time } => {
+                NewWorkoutResponse::Error {
+                    workout_id,
+                    err_code: crate::api::ERR_WORKOUT_ID_CONFLICT,
+                    msg: format!("workout_id already exists with start_time {}", existing_start_time.to_rfc3339()),
+                }
+            }
+        }
+    }
+}
+
+/// changes to the cache made for new workouts, which `Cache::write_new_workouts` saves to the db
+/// before making them
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingWrite {
+    pub user_id: Uuid,
+    /// one per item, in the same order as the items
+    pub outcomes: Vec<CacheOutcome>,
+    /// newly cached workouts, including what later items were merged into them
+    pub inserted: Vec<Workout>,
+    /// previously cached workouts that items were merged into, as they are now
+    pub merged: Vec<Workout>,
+}
+
+/// held while a user's workouts are written, see `Cache::lock_user_writes`
+pub struct UserWriteGuard {
+    user_id: Uuid,
+    locks: UserWriteLocks,
+    guard: Option<tokio::sync::OwnedMutexGuard<()>>,
+}
+
+impl Drop for UserWriteGuard {
+    fn drop(&mut self) {
+        let mut locks = self.locks.lock().unwrap();
+        // one reference is in `locks` and one in `guard`, any other is a request waiting for it
+        if locks.get(&self.user_id).map(|lock| Arc::strong_count(lock) == 2).unwrap_or(false) {
+            locks.remove(&self.user_id);
+        }
+        self.guard.take();
+    }
+}
+
+/// outcome of `Cache::update_workout`
+#[derive(Debug, Clone, PartialEq)]
+pub enum UpdateOutcome {
+    /// workout was updated, `previous` is what it replaced
+    Updated {
+        previous: Workout,
+    },
+    /// no cached workout with the `workout_id` for the user
+    NotFound,
+    /// a different workout is already cached at the new `start_time`
+    StartTimeConflict {
+        existing_workout_id: Uuid,
+    },
+}
+
+/// signature headers of an http request, plus what they were computed over
+struct HttpSignature<'a> {
+    sig: &'a [u8],
+    timestamp: &'a [u8],
+    key_id: Option<Uuid>,
+    /// `None` for the v1 scheme, which only covers timestamp and body
+    target: Option<RequestTarget<'a>>,
+    body: &'a [u8],
+}
+
+impl<'a> HttpSignature<'a> {
+    fn verify(&self, public_key: &PublicKey, buf: &mut Vec<u8>) -> bool {
+        match self.target {
+            None => crate::auth::verify_request(self.sig, self.timestamp, self.body, &public_key[..], buf),
+            Some(ref target) => crate::auth::verify_request_v2(self.sig, self.timestamp, target, self.body, &public_key[..], buf),
+        }
+    }
+}
 
 #[derive(Clone, Default)]
 pub struct Cache {
     keys: UserKeys,
     workouts: UserWorkouts,
-    buf: Vec<u8>,
+    time_zones: UserTimeZones,
+    seen_sigs: SeenSignatures,
+    write_locks: UserWriteLocks,
+    auth_config: AuthConfig,
+    /// `None` unless admin access was explicitly enabled at startup
+    admin: Option<Arc<AdminAccess>>,
+    events: EventQueues,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -23,27 +438,243 @@ pub enum AuthError {
     MissingHeader(&'static str),
     InvalidSignature,
     ParseError(String),
+    InvalidTimestamp,
+    /// request timestamp is older than the allowed clock skew window
+    StaleTimestamp {
+        timestamp: i64,
+        server_time: i64,
+    },
+    /// request timestamp is further in the future than the allowed clock skew window
+    FutureTimestamp {
+        timestamp: i64,
+        server_time: i64,
+    },
+    /// an identical signature was already accepted inside the clock skew window
+    ReplayedRequest,
+    /// unknown value in the scheme header, or a scheme that has been disabled
+    UnsupportedScheme(String),
+    /// key id in the key id header is not an active key for the user
+    KeyNotFound(Uuid),
+    /// request has an admin id header, but the server was not started with admin access enabled
+    AdminAccessDisabled,
+    AdminNotFound(Uuid),
+    /// admin request was otherwise valid, but could not be written to the audit log
+    AuditLogFailed(String),
 }
 
 impl Cache {
-    pub fn insert_key(&self, user_id: Uuid, key: PublicKey) -> Option<PublicKey> {
-        self.keys.write()
-            .unwrap()
-            .insert(user_id, key)
+    /// add key to the user's key set, replacing (and returning) any existing entry with the
+    /// same `key_id`
+    pub fn insert_key(&self, key: UserKey) -> Option<UserKey> {
+        let mut write_lock = self.keys.write().unwrap();
+        let user_keys = write_lock.entry(key.user_id).or_default();
+        match user_keys.iter_mut().find(|k| k.key_id == key.key_id) {
+            Some(existing) => Some(std::mem::replace(existing, key)),
+            None => {
+                user_keys.push(key);
+                None
+            }
+        }
+    }
+
+    /// remove key from the user's key set, e.g. after it has been revoked. any event streams
+    /// authenticated with the key are closed.
+    pub fn remove_key(&self, user_id: &Uuid, key_id: &Uuid) -> Option<UserKey> {
+        let removed = {
+            let mut write_lock = self.keys.write().unwrap();
+            let user_keys = write_lock.get_mut(user_id)?;
+            let i = user_keys.iter().position(|k| k.key_id == *key_id)?;
+            user_keys.remove(i)
+        };
+        self.events.close_streams(*user_id, *key_id, StreamClosed::KeyRevoked);
+        Some(removed)
+    }
+
+    /// whether `key_id` can currently be used to sign requests for the user, i.e. it is neither
+    /// revoked nor expired
+    pub fn key_is_active(&self, user_id: &Uuid, key_id: &Uuid) -> bool {
+        let now = Utc::now();
+        self.keys.read().unwrap()
+            .get(user_id)
+            .map(|user_keys| user_keys.iter().any(|k| k.key_id == *key_id && k.is_active_at(now)))
+            .unwrap_or(false)
+    }
+
+    /// keys that can currently be used to sign requests for the user
+    pub fn active_keys(&self, user_id: &Uuid) -> Vec<UserKey> {
+        let now = Utc::now();
+        self.keys.read().unwrap()
+            .get(user_id)
+            .map(|user_keys| {
+                user_keys.iter()
+                    .filter(|k| k.is_active_at(now))
+                    .cloned()
+                    .collect()
+            }).unwrap_or_default()
+    }
+
+    pub fn new(auth_config: AuthConfig) -> Self {
+        Self {
+            auth_config,
+            ..Default::default()
+        }
+    }
+
+    pub fn auth_config(&self) -> &AuthConfig {
+        &self.auth_config
+    }
+
+    pub fn events(&self) -> &EventQueues {
+        &self.events
+    }
+
+    /// allow signed admin requests made on behalf of users. without this, requests carrying
+    /// the admin id header are always rejected.
+    pub fn with_admin_access(mut self, admin: AdminAccess) -> Self {
+        self.admin = Some(Arc::new(admin));
+        self
     }
 
     pub fn verify_request(&self, user_id: Uuid, sig: &[u8], timestamp: &[u8], body: &[u8]) -> Result<(), AuthError> {
-        match self.keys.read().unwrap().get(&user_id) {
-            Some(public_key) => {
-                let mut buf = Vec::with_capacity(timestamp.len() + body.len());
-                match crate::auth::verify_request(sig, timestamp, body, &public_key[..], &mut buf) {
-                    true => Ok(()),
-                    false => Err(AuthError::InvalidSignature),
-                }
+        self.verify_request_at(user_id, sig, timestamp, body, Utc::now().timestamp())
+    }
+
+    /// like `verify_request`, but with the server time provided (unix timestamp, in seconds)
+    pub fn verify_request_at(
+        &self,
+        user_id: Uuid,
+        sig: &[u8],
+        timestamp: &[u8],
+        body: &[u8],
+        server_time: i64,
+    ) -> Result<(), AuthError> {
+        if ! self.auth_config.allow_v1_signatures {
+            return Err(AuthError::UnsupportedScheme(SignatureScheme::V1.as_str().to_string()))
+        }
+        let mut buf = Vec::with_capacity(timestamp.len() + body.len());
+        self.verify_signed(user_id, None, sig, timestamp, server_time, |public_key| {
+            crate::auth::verify_request(sig, timestamp, body, &public_key[..], &mut buf)
+        }).map(|_| ())
+    }
+
+    /// verify a request signed with the v2 (canonical request) scheme
+    pub fn verify_request_v2(
+        &self,
+        user_id: Uuid,
+        sig: &[u8],
+        timestamp: &[u8],
+        target: &RequestTarget,
+        body: &[u8],
+    ) -> Result<(), AuthError> {
+        self.verify_request_v2_at(user_id, sig, timestamp, target, body, Utc::now().timestamp())
+    }
+
+    /// like `verify_request_v2`, but with the server time provided (unix timestamp, in seconds)
+    pub fn verify_request_v2_at(
+        &self,
+        user_id: Uuid,
+        sig: &[u8],
+        timestamp: &[u8],
+        target: &RequestTarget,
+        body: &[u8],
+        server_time: i64,
+    ) -> Result<(), AuthError> {
+        let mut buf = Vec::with_capacity(128);
+        self.verify_signed(user_id, None, sig, timestamp, server_time, |public_key| {
+            crate::auth::verify_request_v2(sig, timestamp, target, body, &public_key[..], &mut buf)
+        }).map(|_| ())
+    }
+
+    /// checks shared by both signing schemes: timestamp window, key lookup, and replay.
+    /// returns the `key_id` of the key that verified the signature.
+    ///
+    /// if `key_id` is `None`, the signature is accepted if it matches any of the user's
+    /// active keys.
+    fn verify_signed<F>(
+        &self,
+        user_id: Uuid,
+        key_id: Option<Uuid>,
+        sig: &[u8],
+        timestamp: &[u8],
+        server_time: i64,
+        mut check_sig: F,
+    ) -> Result<Uuid, AuthError>
+        where F: FnMut(&PublicKey) -> bool
+    {
+        let ts = self.check_timestamp(timestamp, server_time)?;
+
+        let now = Utc.timestamp_opt(server_time, 0).unwrap();
+
+        let verified_key_id = match self.keys.read().unwrap().get(&user_id) {
+            Some(user_keys) => {
+                let mut active = user_keys.iter().filter(|k| k.is_active_at(now));
+                let verified = match key_id {
+                    Some(key_id) => {
+                        let user_key = active.find(|k| k.key_id == key_id)
+                            .ok_or(AuthError::KeyNotFound(key_id))?;
+                        Some(user_key).filter(|k| check_sig(&k.key))
+                    }
+
+                    None => active.find(|k| check_sig(&k.key)),
+                };
+                verified.map(|k| k.key_id).ok_or(AuthError::InvalidSignature)?
             }
 
-            None => Err(AuthError::UserNotFound(user_id))
+            None => return Err(AuthError::UserNotFound(user_id))
+        };
+
+        self.check_replay(user_id, sig, ts, server_time)?;
+
+        Ok(verified_key_id)
+    }
+
+    fn check_timestamp(&self, timestamp: &[u8], server_time: i64) -> Result<i64, AuthError> {
+        let ts = crate::auth::parse_timestamp(timestamp)
+            .ok_or(AuthError::InvalidTimestamp)?;
+
+        if ts < server_time - self.auth_config.max_clock_skew {
+            return Err(AuthError::StaleTimestamp { timestamp: ts, server_time })
+        }
+
+        if ts > server_time + self.auth_config.max_clock_skew {
+            return Err(AuthError::FutureTimestamp { timestamp: ts, server_time })
         }
+
+        Ok(ts)
+    }
+
+    /// records the (already verified) signature, returning an error if it was seen before.
+    ///
+    /// signatures older than the clock skew window are dropped first, since any request
+    /// carrying them would be rejected as stale anyway.
+    fn check_replay(&self, user_id: Uuid, sig: &[u8], ts: i64, server_time: i64) -> Result<(), AuthError> {
+        let decoded_sig = crate::auth::decode_signature(sig)
+            .ok_or(AuthError::InvalidSignature)?;
+
+        let mut lock = self.seen_sigs.lock().unwrap();
+
+        let seen = lock.entry(user_id).or_default();
+
+        let oldest_allowed = server_time - self.auth_config.max_clock_skew;
+        while matches!(seen.front(), Some((t, _)) if *t < oldest_allowed) {
+            seen.pop_front();
+        }
+
+        if seen.iter().any(|(_, s)| s[..] == decoded_sig[..]) {
+            return Err(AuthError::ReplayedRequest)
+        }
+
+        while ! seen.is_empty() && seen.len() >= self.auth_config.max_seen_signatures {
+            seen.pop_front();
+        }
+
+        if self.auth_config.max_seen_signatures > 0 {
+            // keep deque sorted by timestamp so pruning from the front stays correct
+            let idx = seen.iter().rposition(|(t, _)| *t <= ts).map(|i| i + 1).unwrap_or(0);
+            seen.insert(idx, (ts, decoded_sig));
+        }
+
+        Ok(())
     }
 
     pub fn parse_and_verify_request<T>(&self, sig: &[u8], timestamp: &[u8], body: &[u8]) -> Result<T, AuthError>
@@ -61,82 +692,383 @@ impl Cache {
 
     pub fn parse_and_verify_http_request<T>(&self, req: &http::Request<bytes::Bytes>) -> Result<T, AuthError>
         where T: UserId + for<'de> Deserialize<'de>
+    {
+        self.parse_and_verify_http_request_with_key_id(req)
+            .map(|(parsed, _)| parsed)
+    }
+
+    /// like `parse_and_verify_http_request`, also returning the id of the key that signed the
+    /// request (for admin requests, the admin id)
+    pub fn parse_and_verify_http_request_with_key_id<T>(&self, req: &http::Request<bytes::Bytes>) -> Result<(T, Uuid), AuthError>
+        where T: UserId + for<'de> Deserialize<'de>
     {
         let parsed: T = serde_json::from_slice(&req.body().slice(..))
             .map_err(|e| AuthError::ParseError(format!("failed to parse request body: {}", e)))?;
 
-        if req.headers().get("x-fitbod-god-mode").is_some() {
-            if self.key_exists(&parsed.user_id()) {
-                return Ok(parsed)
-            } else {
-                return Err(AuthError::UserNotFound(parsed.user_id()))
-            }
+        if req.headers().contains_key(crate::ADMIN_ID_HEADER) {
+            let admin_id = self.verify_admin_http_request(req, parsed.user_id())?;
+            return Ok((parsed, admin_id))
         }
 
+        let signed = self.http_signature(req)?;
+
+        let user_id = parsed.user_id();
+
+        let mut buf = Vec::with_capacity(128);
+        let key_id = self.verify_signed(user_id, signed.key_id, signed.sig, signed.timestamp, Utc::now().timestamp(), |public_key| {
+            signed.verify(public_key, &mut buf)
+        })?;
+
+        Ok((parsed, key_id))
+    }
+
+    /// verify the handshake that opens an event stream websocket. `target` is the method, path
+    /// and host of the websocket upgrade request, which the handshake is signed over (v2 scheme
+    /// only). returns the parsed request and the `key_id` of the key that signed it.
+    pub fn verify_event_stream_handshake(
+        &self,
+        handshake: &EventStreamHandshake,
+        target: &RequestTarget,
+    ) -> Result<(SubscribeEventsRequest, Uuid), AuthError> {
+        let parsed: SubscribeEventsRequest = serde_json::from_str(&handshake.request)
+            .map_err(|e| AuthError::ParseError(format!("failed to parse handshake request: {}", e)))?;
+
+        let sig = handshake.signature.as_bytes();
+        let timestamp = handshake.timestamp.to_string();
+        let body = handshake.request.as_bytes();
+
+        let mut buf = Vec::with_capacity(128);
+        let key_id = self.verify_signed(parsed.user_id, handshake.key_id, sig, timestamp.as_bytes(), Utc::now().timestamp(), |public_key| {
+            crate::auth::verify_request_v2(sig, timestamp.as_bytes(), target, body, &public_key[..], &mut buf)
+        })?;
+
+        Ok((parsed, key_id))
+    }
+
+    /// verify a `RegisterUserRequest`, which must be signed with the private key matching the
+    /// public key being registered (proof of possession). returns the parsed request and the
+    /// decoded public key.
+    ///
+    /// signatures are not recorded for replay detection, since the user does not exist yet;
+    /// replaying a registration fails anyway, on the unique `email` constraint.
+    pub fn parse_and_verify_registration_request(
+        &self,
+        req: &http::Request<bytes::Bytes>,
+    ) -> Result<(crate::api::RegisterUserRequest, PublicKey), AuthError> {
+        let parsed: crate::api::RegisterUserRequest = serde_json::from_slice(&req.body().slice(..))
+            .map_err(|e| AuthError::ParseError(format!("failed to parse request body: {}", e)))?;
+
+        let public_key: PublicKey = base64::decode(&parsed.key).ok()
+            .and_then(|x| x.try_into().ok())
+            .ok_or_else(|| AuthError::ParseError("key must be a base64-encoded 32-byte ed25519 public key".to_string()))?;
+
+        let signed = self.http_signature(req)?;
+
+        self.check_timestamp(signed.timestamp, Utc::now().timestamp())?;
+
+        let mut buf = Vec::with_capacity(128);
+        if ! signed.verify(&public_key, &mut buf) {
+            return Err(AuthError::InvalidSignature)
+        }
+
+        Ok((parsed, public_key))
+    }
+
+    /// verify request signed by an admin acting on behalf of `user_id`, and write it to the
+    /// audit log. the request is rejected if the audit log entry cannot be written. returns the
+    /// admin id.
+    fn verify_admin_http_request(&self, req: &http::Request<bytes::Bytes>, user_id: Uuid) -> Result<Uuid, AuthError> {
+        let admin = self.admin.as_ref().ok_or(AuthError::AdminAccessDisabled)?;
+
+        let admin_id = req.headers().get(crate::ADMIN_ID_HEADER)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| Uuid::parse_str(x).ok())
+            .ok_or_else(|| AuthError::ParseError(format!("invalid {} header", crate::ADMIN_ID_HEADER)))?;
+
+        let signed = self.http_signature(req)?;
+
+        let server_time = Utc::now().timestamp();
+        let ts = self.check_timestamp(signed.timestamp, server_time)?;
+
+        let admin_key = admin.key(&admin_id).ok_or(AuthError::AdminNotFound(admin_id))?;
+
+        let mut buf = Vec::with_capacity(128);
+        if ! signed.verify(admin_key, &mut buf) {
+            return Err(AuthError::InvalidSignature)
+        }
+
+        self.check_replay(admin_id, signed.sig, ts, server_time)?;
+
+        if ! self.key_exists(&user_id) {
+            return Err(AuthError::UserNotFound(user_id))
+        }
+
+        let entry = AuditEntry {
+            time: Utc.timestamp_opt(server_time, 0).unwrap(),
+            admin_id,
+            user_id,
+            method: req.method().to_string(),
+            path: req.uri().path().to_string(),
+            body_sha256: crate::auth::sha256_hex(signed.body),
+        };
+
+        admin.record(&entry)
+            .map_err(|e| AuthError::AuditLogFailed(e.to_string()))?;
+
+        Ok(admin_id)
+    }
+
+    /// extract signature-related headers from http request
+    fn http_signature<'a>(&self, req: &'a http::Request<bytes::Bytes>) -> Result<HttpSignature<'a>, AuthError> {
         let sig = req.headers().get(crate::SIG_HEADER)
             .map(|x| x.as_bytes())
-            .ok_or_else(|| AuthError::MissingHeader(crate::SIG_HEADER))?;
+            .ok_or(AuthError::MissingHeader(crate::SIG_HEADER))?;
 
         let timestamp = req.headers().get(crate::TIMESTAMP_HEADER)
             .map(|x| x.as_bytes())
-            .ok_or_else(|| AuthError::MissingHeader(crate::TIMESTAMP_HEADER))?;
+            .ok_or(AuthError::MissingHeader(crate::TIMESTAMP_HEADER))?;
 
-        let body = &req.body().slice(..);
+        let key_id = match req.headers().get(crate::KEY_ID_HEADER) {
+            Some(value) => {
+                let key_id = std::str::from_utf8(value.as_bytes()).ok()
+                    .and_then(|x| Uuid::parse_str(x).ok())
+                    .ok_or_else(|| AuthError::ParseError(format!("invalid {} header", crate::KEY_ID_HEADER)))?;
+                Some(key_id)
+            }
 
-        let user_id = parsed.user_id();
+            None => None,
+        };
 
-        self.verify_request(user_id, sig, timestamp, body)?;
+        let scheme = match req.headers().get(crate::SCHEME_HEADER) {
+            Some(value) => {
+                SignatureScheme::from_header(value.as_bytes())
+                    .ok_or_else(|| AuthError::UnsupportedScheme(String::from_utf8_lossy(value.as_bytes()).into_owned()))?
+            }
 
-        Ok(parsed)
+            None => SignatureScheme::V1,
+        };
+
+        let target = match scheme {
+            SignatureScheme::V1 => {
+                if ! self.auth_config.allow_v1_signatures {
+                    return Err(AuthError::UnsupportedScheme(SignatureScheme::V1.as_str().to_string()))
+                }
+                None
+            }
+
+            SignatureScheme::V2 => {
+                let host = req.headers().get(http::header::HOST)
+                    .and_then(|x| x.to_str().ok())
+                    .or_else(|| req.uri().host())
+                    .ok_or(AuthError::MissingHeader("host"))?;
+
+                Some(RequestTarget {
+                    method: req.method().as_str(),
+                    path: req.uri().path(),
+                    host,
+                })
+            }
+        };
+
+        Ok(HttpSignature { sig, timestamp, key_id, target, body: &req.body()[..] })
     }
 
-    /// returns a list of previously unseen (un-cached) workouts
+    /// cache workouts loaded from the db and return the previously unseen (un-cached) ones.
+    /// they were checked when they were saved, so unlike `try_cache_workouts` this doesn't look
+    /// for overlapping workouts, which would slow down loading many of them
     pub fn cache_workouts(&self, user_id: Uuid, workouts: &mut [Workout]) -> Vec<Workout> {
-        workouts.sort_unstable_by(|a, b| a.start_time.cmp(&b.start_time));
+        workouts.sort_unstable_by_key(|x| x.start_time);
 
         let mut write_lock = self.workouts.write().unwrap();
+        let user_cache = write_lock
+            .entry(user_id)
+            .or_default();
 
+        let mut inserted = Vec::new();
+        for workout in workouts.iter() {
+            debug_assert_eq!(workout.user_id, user_id);
+            if user_cache.get_at(&workout.start_time).is_none() && user_cache.get(&workout.workout_id).is_none() {
+                user_cache.insert(workout.clone());
+                inserted.push(workout.clone());
+            }
+        }
+        inserted
+    }
+
+    /// cache workouts, accepting overlapping ones, and return the outcome for each item, in the
+    /// same order as `workouts`. see `try_cache_new_workouts`
+    pub fn try_cache_workouts(&self, user_id: Uuid, workouts: &[Workout]) -> Vec<CacheOutcome> {
+        self.try_cache_new_workouts(user_id, workouts, OverlapPolicy::Accept)
+    }
+
+    /// cache workouts, returning the outcome for each item, in the same order as `workouts`.
+    /// items overlapping cached workouts are handled according to `overlap`.
+    ///
+    /// when two items in `workouts` conflict with each other, the first one wins. items are
+    /// checked for overlaps with earlier items too, which they may be merged into
+    pub fn try_cache_new_workouts(&self, user_id: Uuid, workouts: &[Workout], overlap: OverlapPolicy) -> Vec<CacheOutcome> {
+        let mut write_lock = self.workouts.write().unwrap();
         let user_cache = write_lock
             .entry(user_id)
             .or_default();
 
-        let mut new_workouts = Vec::new();
+        let pending = PendingWorkouts::new(Some(user_cache)).insert_new_workouts(user_id, workouts, overlap);
+        user_cache.apply(&pending);
+        pending.outcomes
+    }
 
-        for workout in workouts {
-            debug_assert_eq!(workout.user_id, user_id);
+    /// wait until no other request is writing the user's workouts, and keep them from doing so
+    /// until the guard is dropped. writes check new and changed workouts against the cache, so
+    /// this keeps what they checked from changing while they wait for the db
+    pub async fn lock_user_writes(&self, user_id: Uuid) -> UserWriteGuard {
+        let lock = self.write_locks.lock().unwrap()
+            .entry(user_id)
+            .or_default()
+            .clone();
 
-            let _entry = user_cache.entry(workout.start_time)
-                .or_insert_with(|| {
-                    new_workouts.push(workout.clone());
-                    workout.clone()
-                });
+        UserWriteGuard {
+            user_id,
+            locks: self.write_locks.clone(),
+            guard: Some(lock.lock_owned().await),
         }
-
-        new_workouts
     }
 
-    pub fn get_cached_workouts(
+    /// work out what caching workouts like `try_cache_new_workouts` would change, save the new
+    /// and changed workouts with `save(inserted, merged)` (see `PendingWrite`), and only then
+    /// change the cache. until the save commits, the workouts aren't visible to reads, and other
+    /// writes of the user's workouts wait, so a retry of the same items during a save that fails
+    /// sees them as new, rather than as duplicates of workouts that were never saved
+    pub async fn write_new_workouts<F, Fut, E>(
         &self,
-        user_id: &Uuid,
-        start: Option<DateTime<Utc>>,
-        end: Option<DateTime<Utc>>,
-        limit: Option<usize>,
-    ) -> Option<Vec<Workout>> {
+        user_id: Uuid,
+        workouts: &[Workout],
+        overlap: OverlapPolicy,
+        save: F,
+    ) -> Result<PendingWrite, E>
+        where F: FnOnce(Vec<Workout>, Vec<Workout>) -> Fut,
+              Fut: std::future::Future<Output = Result<(), E>>
+    {
+        let _writing = self.lock_user_writes(user_id).await;
+
+        let pending = {
+            let read_lock = self.workouts.read().unwrap();
+            PendingWorkouts::new(read_lock.get(&user_id)).insert_new_workouts(user_id, workouts, overlap)
+        };
+        if pending.inserted.is_empty() && pending.merged.is_empty() {
+            return Ok(pending)
+        }
+
+        save(pending.inserted.clone(), pending.merged.clone()).await?;
+
+        self.workouts.write().unwrap()
+            .entry(user_id)
+            .or_default()
+            .apply(&pending);
+
+        Ok(pending)
+    }
+
+    /// replace the cached workout with the same `workout_id`, moving it to its new position if
+    /// `start_time` changed. the change is applied under one write lock, so readers see either
+    /// the old workout or the new one.
+    pub fn update_workout(&self, workout: &Workout) -> UpdateOutcome {
+        let mut write_lock = self.workouts.write().unwrap();
+
+        let user_cache = match write_lock.get_mut(&workout.user_id) {
+            Some(user_cache) => user_cache,
+            None => return UpdateOutcome::NotFound,
+        };
+
+        let previous_start_time = match user_cache.get(&workout.workout_id) {
+            Some(existing) => existing.start_time,
+            None => return UpdateOutcome::NotFound,
+        };
+
+        if previous_start_time != workout.start_time {
+            if let Some(existing) = user_cache.get_at(&workout.start_time) {
+                return UpdateOutcome::StartTimeConflict { existing_workout_id: existing.workout_id }
+            }
+        }
+
+        let previous = user_cache.remove(&workout.workout_id).unwrap();
+        user_cache.insert(workout.clone());
+
+        UpdateOutcome::Updated { previous }
+    }
+
+    /// remove workout from cache, returning it if it was cached
+    pub fn remove_workout(&self, user_id: &Uuid, workout_id: &Uuid) -> Option<Workout> {
+        let mut write_lock = self.workouts.write().unwrap();
+        write_lock.get_mut(user_id)?.remove(workout_id)
+    }
+
+    /// cached workout with `workout_id`, if it belongs to the user
+    pub fn get_cached_workout(&self, user_id: &Uuid, workout_id: &Uuid) -> Option<Workout> {
+        self.workouts.read().unwrap()
+            .get(user_id)?
+            .get(workout_id)
+            .cloned()
+    }
+
+    /// cached workouts selected by `query`, or `None` if the user's workouts aren't cached
+    pub fn get_cached_workouts(&self, user_id: &Uuid, query: &WorkoutQuery) -> Option<Vec<Workout>> {
         let read_lock = self.workouts.read().unwrap();
 
         let user_cache = read_lock.get(user_id)?;
 
-        let start   = start.unwrap_or_else(|| Utc.ymd(1970, 1, 1).and_hms(0, 0, 0));
-        let end     = end  .unwrap_or_else(|| Utc.ymd(2142, 7, 27).and_hms(0, 0, 0));
-        let limit   = limit.unwrap_or(usize::MAX);
+        match query.start_time_bounds() {
+            Some(bounds) => Some(query.select(user_cache.range(bounds))),
+            None => Some(Vec::new()),
+        }
+    }
 
-        let items = user_cache.range(start..end)
-            .map(|(_, x)| x.clone())
-            .rev()
-            .take(limit)
-            .collect();
+    /// the user's time zone, UTC unless set otherwise
+    pub fn time_zone(&self, user_id: &Uuid) -> Tz {
+        self.time_zones.read().unwrap()
+            .get(user_id)
+            .cloned()
+            .unwrap_or(chrono_tz::UTC)
+    }
 
-        Some(items)
+    pub fn set_time_zone(&self, user_id: Uuid, tz: Tz) {
+        let mut write_lock = self.time_zones.write().unwrap();
+        if tz == chrono_tz::UTC {
+            write_lock.remove(&user_id);
+        } else {
+            write_lock.insert(user_id, tz);
+        }
+    }
+
+    /// stats over the cached workouts selected by `query` (disregarding order and limit), or
+    /// `None` if the user's workouts aren't cached. see `WorkoutStats::compute` for `tz` and
+    /// `today`
+    pub fn get_workout_stats(&self, user_id: &Uuid, query: &WorkoutQuery, tz: Tz, today: NaiveDate) -> Option<WorkoutStats> {
+        let read_lock = self.workouts.read().unwrap();
+
+        let user_cache = read_lock.get(user_id)?;
+
+        let stats = match query.start_time_bounds() {
+            Some(bounds) => WorkoutStats::compute(user_cache.range(bounds).filter(|w| query.matches(w)), tz, today),
+            None => WorkoutStats::compute(std::iter::empty(), tz, today),
+        };
+
+        Some(stats)
+    }
+
+    /// cached workouts aggregated per `aggregation`, or `None` if the user's workouts aren't
+    /// cached
+    pub fn aggregate_workouts(&self, user_id: &Uuid, aggregation: &Aggregation) -> Option<Result<Vec<WorkoutBucket>, String>> {
+        let read_lock = self.workouts.read().unwrap();
+
+        let user_cache = read_lock.get(user_id)?;
+
+        let query = aggregation.query();
+        let buckets = match query.start_time_bounds() {
+            Some(bounds) => aggregation.aggregate(user_cache.range(bounds).filter(|w| query.matches(w))),
+            None => aggregation.aggregate(std::iter::empty()),
+        };
+
+        Some(buckets)
     }
 
     pub fn key_exists(&self, user_id: &Uuid) -> bool {
@@ -155,10 +1087,22 @@ impl Cache {
     }
 }
 
-#[allow(unused)]
+#[allow(unused, clippy::bool_assert_comparison, clippy::redundant_pattern_matching)]
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::api::Cursor;
+    use crate::Exercise;
+
+    /// newest first, starting within `start..end`
+    fn query(
+        start: Option<DateTime<Utc>>,
+        end: Option<DateTime<Utc>>,
+        limit: Option<usize>,
+        cursor: Option<Cursor>,
+    ) -> WorkoutQuery {
+        WorkoutQuery { start, end, limit, cursor, ..Default::default() }
+    }
 
     #[test]
     fn sanity_check_verify_request() {
@@ -168,7 +1112,7 @@ mod tests {
         assert_eq!(cache.workouts_exist(&user_id), false);
         assert!(matches!(cache.n_cached_workouts(&user_id), None));
         let (priv_key, pub_key) = crate::auth::gen_keypair();
-        assert!(matches!(cache.insert_key(user_id, pub_key), None));
+        assert!(matches!(cache.insert_key(UserKey::new(user_id, pub_key)), None));
         assert_eq!(cache.key_exists(&user_id), true);
 
         let req = crate::api::ListWorkoutsRequest::from(user_id);
@@ -193,18 +1137,780 @@ mod tests {
         assert!(matches!(res, Err(AuthError::ParseError(_))));
     }
 
+    #[test]
+    fn verify_request_rejects_stale_future_and_replayed_requests() {
+        let cache = Cache::new(AuthConfig { max_clock_skew: 30, max_seen_signatures: 2, ..Default::default() });
+        let user_id = Uuid::new_v4();
+        let (priv_key, pub_key) = crate::auth::gen_keypair();
+        cache.insert_key(UserKey::new(user_id, pub_key));
+
+        let now = Utc::now().timestamp();
+        let body = serde_json::to_string(&crate::api::ListWorkoutsRequest::from(user_id)).unwrap();
+        let signed = |ts: i64| (crate::auth::sign_request(ts, &body, &priv_key), ts.to_string());
+
+        let (sig, ts) = signed(now - 31);
+        assert!(matches!(
+            cache.verify_request_at(user_id, sig.as_bytes(), ts.as_bytes(), body.as_bytes(), now),
+            Err(AuthError::StaleTimestamp { timestamp, server_time }) if timestamp == now - 31 && server_time == now
+        ));
+
+        let (sig, ts) = signed(now + 31);
+        assert!(matches!(
+            cache.verify_request_at(user_id, sig.as_bytes(), ts.as_bytes(), body.as_bytes(), now),
+            Err(AuthError::FutureTimestamp { .. })
+        ));
+
+        assert!(matches!(
+            cache.verify_request_at(user_id, sig.as_bytes(), "not a number".as_bytes(), body.as_bytes(), now),
+            Err(AuthError::InvalidTimestamp)
+        ));
+
+        // within the window: accepted once, then rejected as a replay
+        let (sig, ts) = signed(now - 30);
+        assert!(cache.verify_request_at(user_id, sig.as_bytes(), ts.as_bytes(), body.as_bytes(), now).is_ok());
+        assert!(matches!(
+            cache.verify_request_at(user_id, sig.as_bytes(), ts.as_bytes(), body.as_bytes(), now),
+            Err(AuthError::ReplayedRequest)
+        ));
+
+        // once the window has moved past the timestamp, the request is stale instead
+        assert!(matches!(
+            cache.verify_request_at(user_id, sig.as_bytes(), ts.as_bytes(), body.as_bytes(), now + 1),
+            Err(AuthError::StaleTimestamp { .. })
+        ));
+
+        // per-user capacity is bounded
+        for dt in 0..5 {
+            let (sig, ts) = signed(now + dt);
+            assert!(cache.verify_request_at(user_id, sig.as_bytes(), ts.as_bytes(), body.as_bytes(), now).is_ok());
+        }
+        assert_eq!(cache.seen_sigs.lock().unwrap().get(&user_id).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn parse_and_verify_http_request_supports_v1_and_v2_schemes() {
+        let cache = Cache::default();
+        let user_id = Uuid::new_v4();
+        let (priv_key, pub_key) = crate::auth::gen_keypair();
+        cache.insert_key(UserKey::new(user_id, pub_key));
+
+        let body = serde_json::to_string(&crate::api::ListWorkoutsRequest::from(user_id)).unwrap();
+        let ts = Utc::now().timestamp();
+
+        let build = |path: &str, sig: &str, scheme: Option<&str>| {
+            let mut builder = http::Request::builder()
+                .method("POST")
+                .uri(path)
+                .header(http::header::HOST, "fitbod.me")
+                .header(crate::SIG_HEADER, sig)
+                .header(crate::TIMESTAMP_HEADER, ts.to_string());
+            if let Some(scheme) = scheme {
+                builder = builder.header(crate::SCHEME_HEADER, scheme);
+            }
+            builder.body(bytes::Bytes::from(body.clone())).unwrap()
+        };
+
+        // v1: no scheme header
+        let v1_sig = crate::auth::sign_request(ts, &body, &priv_key);
+        let req = build("/api/v1/workouts/list", &v1_sig, None);
+        assert!(cache.parse_and_verify_http_request::<crate::api::ListWorkoutsRequest>(&req).is_ok());
+
+        let v2_sig = crate::auth::RequestSigner::new(ts, &body)
+            .path("/api/v1/workouts/list")
+            .host("fitbod.me")
+            .sign(&priv_key);
+
+        // v2 signature sent to a different path is rejected
+        let req = build("/api/v1/workouts/new", &v2_sig, Some("v2"));
+        assert!(matches!(
+            cache.parse_and_verify_http_request::<crate::api::ListWorkoutsRequest>(&req),
+            Err(AuthError::InvalidSignature)
+        ));
+
+        let req = build("/api/v1/workouts/list", &v2_sig, Some("v2"));
+        assert!(cache.parse_and_verify_http_request::<crate::api::ListWorkoutsRequest>(&req).is_ok());
+
+        let req = build("/api/v1/workouts/list", &v2_sig, Some("v3"));
+        assert!(matches!(
+            cache.parse_and_verify_http_request::<crate::api::ListWorkoutsRequest>(&req),
+            Err(AuthError::UnsupportedScheme(_))
+        ));
+
+        // v1 can be switched off once clients have migrated
+        let cache = Cache::new(AuthConfig { allow_v1_signatures: false, ..Default::default() });
+        cache.insert_key(UserKey::new(user_id, pub_key));
+        let req = build("/api/v1/workouts/list", &v1_sig, None);
+        assert!(matches!(
+            cache.parse_and_verify_http_request::<crate::api::ListWorkoutsRequest>(&req),
+            Err(AuthError::UnsupportedScheme(_))
+        ));
+    }
+
+    #[test]
+    fn verify_request_with_multiple_keys_and_key_ids() {
+        let cache = Cache::default();
+        let user_id = Uuid::new_v4();
+        let (priv_a, pub_a) = crate::auth::gen_keypair();
+        let (priv_b, pub_b) = crate::auth::gen_keypair();
+        let key_a = UserKey::new(user_id, pub_a);
+        let key_b = UserKey::new(user_id, pub_b);
+        cache.insert_key(key_a.clone());
+        cache.insert_key(key_b.clone());
+        assert_eq!(cache.active_keys(&user_id).len(), 2);
+
+        let body = serde_json::to_string(&crate::api::ListWorkoutsRequest::from(user_id)).unwrap();
+        let ts = Utc::now().timestamp();
+
+        let build = |sig: &str, key_id: Option<Uuid>| {
+            let mut builder = http::Request::builder()
+                .method("POST")
+                .uri("/api/v1/workouts/list")
+                .header(crate::SIG_HEADER, sig)
+                .header(crate::TIMESTAMP_HEADER, ts.to_string());
+            if let Some(key_id) = key_id {
+                builder = builder.header(crate::KEY_ID_HEADER, key_id.to_string());
+            }
+            builder.body(bytes::Bytes::from(body.clone())).unwrap()
+        };
+        let verify = |req| cache.parse_and_verify_http_request::<crate::api::ListWorkoutsRequest>(&req);
+
+        // without a key id, any active key is accepted
+        let sig_a = crate::auth::sign_request(ts, &body, &priv_a);
+        assert!(verify(build(&sig_a, None)).is_ok());
+
+        // key id must match the key that produced the signature
+        let sig_b = crate::auth::sign_request(ts, &body, &priv_b);
+        assert!(matches!(verify(build(&sig_b, Some(key_a.key_id))), Err(AuthError::InvalidSignature)));
+        assert!(verify(build(&sig_b, Some(key_b.key_id))).is_ok());
+
+        let unknown = Uuid::new_v4();
+        assert!(matches!(verify(build(&sig_b, Some(unknown))), Err(AuthError::KeyNotFound(k)) if k == unknown));
+
+        // revoked and expired keys are not used
+        let (priv_c, pub_c) = crate::auth::gen_keypair();
+        let sig_c = crate::auth::sign_request(ts, &body, &priv_c);
+        let key_c = UserKey { expires: Some(Utc::now() - chrono::Duration::seconds(1)), ..UserKey::new(user_id, pub_c) };
+        cache.insert_key(key_c.clone());
+        assert!(matches!(verify(build(&sig_c, Some(key_c.key_id))), Err(AuthError::KeyNotFound(_))));
+        assert_eq!(cache.active_keys(&user_id).len(), 2);
+
+        assert_eq!(cache.remove_key(&user_id, &key_a.key_id), Some(key_a.clone()));
+        let body_ts = ts - 1;
+        let sig_a = crate::auth::sign_request(body_ts, &body, &priv_a);
+        let req = http::Request::builder()
+            .method("POST")
+            .uri("/api/v1/workouts/list")
+            .header(crate::SIG_HEADER, sig_a)
+            .header(crate::TIMESTAMP_HEADER, body_ts.to_string())
+            .body(bytes::Bytes::from(body.clone()))
+            .unwrap();
+        assert!(matches!(verify(req), Err(AuthError::InvalidSignature)));
+    }
+
+    #[test]
+    fn event_stream_handshake_is_verified_and_revocation_closes_stream() {
+        let cache = Cache::default();
+        let user_id = Uuid::new_v4();
+        let (priv_a, pub_a) = crate::auth::gen_keypair();
+        let (priv_b, pub_b) = crate::auth::gen_keypair();
+        let key_a = UserKey::new(user_id, pub_a);
+        let key_b = UserKey::new(user_id, pub_b);
+        cache.insert_key(key_a.clone());
+        cache.insert_key(key_b.clone());
+
+        let request = serde_json::to_string(&SubscribeEventsRequest { user_id, timeout_secs: None }).unwrap();
+        let ts = Utc::now().timestamp();
+        let target = RequestTarget { method: "GET", path: "/api/v1/events/ws", host: "fitbod.me" };
+        let handshake = |priv_key, path, key_id| {
+            let signature = crate::auth::RequestSigner::new(ts, &request)
+                .method("GET")
+                .path(path)
+                .host("fitbod.me")
+                .sign(priv_key);
+            EventStreamHandshake { timestamp: ts, signature, key_id, request: request.clone() }
+        };
+
+        // signature must cover the websocket path
+        let wrong_path = handshake(&priv_a, "/api/v1/events/subscribe", None);
+        assert!(matches!(cache.verify_event_stream_handshake(&wrong_path, &target), Err(AuthError::InvalidSignature)));
+
+        // verified key id is returned, with or without the key id given
+        let (req, key_id) = cache.verify_event_stream_handshake(&handshake(&priv_a, target.path, None), &target).unwrap();
+        assert_eq!(req.user_id, user_id);
+        assert_eq!(key_id, key_a.key_id);
+        let (_, key_id) = cache.verify_event_stream_handshake(&handshake(&priv_b, target.path, Some(key_b.key_id)), &target).unwrap();
+        assert_eq!(key_id, key_b.key_id);
+
+        // replayed handshake is rejected
+        assert!(matches!(
+            cache.verify_event_stream_handshake(&handshake(&priv_b, target.path, Some(key_b.key_id)), &target),
+            Err(AuthError::ReplayedRequest)
+        ));
+
+        let mut stream_a = cache.events().subscribe(user_id, key_a.key_id);
+        let mut stream_b = cache.events().subscribe(user_id, key_b.key_id);
+        cache.remove_key(&user_id, &key_a.key_id);
+        assert_eq!(stream_a.closed.try_recv(), Ok(StreamClosed::KeyRevoked));
+        assert!(stream_b.closed.try_recv().is_err());
+        assert_eq!(cache.events().n_streams(&user_id), 1);
+    }
+
+    #[test]
+    fn registration_request_requires_proof_of_possession() {
+        let cache = Cache::default();
+        let (priv_key, pub_key) = crate::auth::gen_keypair();
+        let (other_priv_key, _) = crate::auth::gen_keypair();
+        let body = serde_json::to_string(&crate::api::RegisterUserRequest {
+            email: "new-user@example.com".to_string(),
+            key: base64::encode(&pub_key[..]),
+        }).unwrap();
+        let ts = Utc::now().timestamp();
+
+        let build = |priv_key: &crate::auth::PrivateKey| {
+            let sig = crate::auth::RequestSigner::new(ts, &body)
+                .path("/api/v1/users/register")
+                .host("fitbod.me")
+                .sign(priv_key);
+            http::Request::builder()
+                .method("POST")
+                .uri("/api/v1/users/register")
+                .header(http::header::HOST, "fitbod.me")
+                .header(crate::SCHEME_HEADER, "v2")
+                .header(crate::SIG_HEADER, sig)
+                .header(crate::TIMESTAMP_HEADER, ts.to_string())
+                .body(bytes::Bytes::from(body.clone()))
+                .unwrap()
+        };
+
+        let (req, key) = cache.parse_and_verify_registration_request(&build(&priv_key)).unwrap();
+        assert_eq!(req.email, "new-user@example.com");
+        assert_eq!(key, pub_key);
+
+        // signed by a key other than the one being registered
+        assert!(matches!(
+            cache.parse_and_verify_registration_request(&build(&other_priv_key)),
+            Err(AuthError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn admin_requests_require_admin_access_and_are_audited() {
+        let user_id = Uuid::new_v4();
+        let (_, user_pub_key) = crate::auth::gen_keypair();
+        let admin_id = Uuid::new_v4();
+        let (admin_priv_key, admin_pub_key) = crate::auth::gen_keypair();
+
+        let body = serde_json::to_string(&crate::api::ListWorkoutsRequest::from(user_id)).unwrap();
+        let ts = Utc::now().timestamp();
+        let build = |admin_id: Uuid| {
+            let sig = crate::auth::sign_request(ts, &body, &admin_priv_key);
+            http::Request::builder()
+                .method("POST")
+                .uri("/api/v1/workouts/list")
+                .header(crate::ADMIN_ID_HEADER, admin_id.to_string())
+                .header(crate::SIG_HEADER, sig)
+                .header(crate::TIMESTAMP_HEADER, ts.to_string())
+                .body(bytes::Bytes::from(body.clone()))
+                .unwrap()
+        };
+        let verify = |cache: &Cache, req| cache.parse_and_verify_http_request::<crate::api::ListWorkoutsRequest>(&req);
+
+        // disabled by default
+        let cache = Cache::default();
+        cache.insert_key(UserKey::new(user_id, user_pub_key));
+        assert!(matches!(verify(&cache, build(admin_id)), Err(AuthError::AdminAccessDisabled)));
+
+        let audit_log_path = std::env::temp_dir().join(format!("fitbod-audit-{}.log", Uuid::new_v4()));
+        let mut admin_keys = HashMap::new();
+        admin_keys.insert(admin_id, admin_pub_key);
+        let admin = AdminAccess::open(admin_keys, &audit_log_path).unwrap();
+        let cache = Cache::default().with_admin_access(admin);
+        cache.insert_key(UserKey::new(user_id, user_pub_key));
+
+        let unknown_admin_id = Uuid::new_v4();
+        assert!(matches!(verify(&cache, build(unknown_admin_id)), Err(AuthError::AdminNotFound(id)) if id == unknown_admin_id));
+
+        assert!(verify(&cache, build(admin_id)).is_ok());
+        assert!(matches!(verify(&cache, build(admin_id)), Err(AuthError::ReplayedRequest)));
+
+        let audit_log = std::fs::read_to_string(&audit_log_path).unwrap();
+        std::fs::remove_file(&audit_log_path).unwrap();
+        let entries: Vec<AuditEntry> = audit_log.lines().map(|x| serde_json::from_str(x).unwrap()).collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].admin_id, admin_id);
+        assert_eq!(entries[0].user_id, user_id);
+        assert_eq!(entries[0].path, "/api/v1/workouts/list");
+        assert_eq!(entries[0].body_sha256, crate::auth::sha256_hex(body.as_bytes()));
+    }
+
+    #[test]
+    fn try_cache_workouts_reports_duplicates_and_conflicts() {
+        let cache = Cache::default();
+        let user_id = Uuid::new_v4();
+        let t0 = Utc.with_ymd_and_hms(2021, 7, 27, 6, 30, 0).unwrap();
+        let t1 = Utc.with_ymd_and_hms(2021, 7, 28, 6, 30, 0).unwrap();
+        let get_workout = |t| -> Workout {
+            Workout {
+                user_id,
+                workout_id: Uuid::new_v4(),
+                start_time: t,
+                end_time: t + chrono::Duration::hours(1),
+                exercises: Vec::new(),
+                category: None,
+                tags: Vec::new(),
+                note: None,
+            }
+        };
+
+        let w0 = get_workout(t0);
+        let w0_same_start = get_workout(t0);
+        let w0_moved = Workout { start_time: t1, end_time: t1 + chrono::Duration::hours(1), ..w0.clone() };
+        let w1 = get_workout(t1);
+
+        assert_eq!(cache.try_cache_workouts(user_id, std::slice::from_ref(&w0)), vec![CacheOutcome::Inserted]);
+
+        assert_eq!(
+            cache.try_cache_workouts(user_id, &[w0.clone(), w0_same_start.clone(), w0_moved.clone(), w1.clone(), w1.clone()]),
+            vec![
+                CacheOutcome::Duplicate,
+                CacheOutcome::StartTimeConflict { existing_workout_id: w0.workout_id },
+                CacheOutcome::WorkoutIdConflict { existing_start_time: t0 },
+                CacheOutcome::Inserted,
+                CacheOutcome::Duplicate,
+            ]
+        );
+        assert_eq!(cache.get_cached_workouts(&user_id, &query(None, None, None, None)).unwrap(), vec![w1.clone(), w0.clone()]);
+
+        assert!(matches!(
+            CacheOutcome::StartTimeConflict { existing_workout_id: w0.workout_id }.to_response(w0_same_start.workout_id),
+            NewWorkoutResponse::Error { workout_id, err_code: crate::api::ERR_START_TIME_CONFLICT, .. } if workout_id == w0_same_start.workout_id
+        ));
+    }
+
+    #[test]
+    fn overlapping_workouts_are_accepted_rejected_or_merged() {
+        let cache = Cache::default();
+        let at = |h: u32, m: u32| Utc.with_ymd_and_hms(2021, 7, 27, h, m, 0).unwrap();
+        let squat = Exercise { name: "squat".to_string(), sets: Vec::new() };
+        let lunge = Exercise { name: "lunge".to_string(), sets: Vec::new() };
+        let get_workout = |user_id, start_time, end_time| -> Workout {
+            Workout {
+                user_id,
+                workout_id: Uuid::new_v4(),
+                start_time,
+                end_time,
+                exercises: Vec::new(),
+                category: None,
+                tags: Vec::new(),
+                note: None,
+            }
+        };
+        let cached = |user_id: Uuid| cache.get_cached_workouts(&user_id, &query(None, None, None, None)).unwrap();
+
+        // w0 10:00-11:00 and w1 12:00-13:00 are cached for each user below
+        let setup = || {
+            let user_id = Uuid::new_v4();
+            let w0 = Workout {
+                exercises: vec![squat.clone()],
+                tags: vec!["legs".to_string()],
+                ..get_workout(user_id, at(10, 0), at(11, 0))
+            };
+            let w1 = get_workout(user_id, at(12, 0), at(13, 0));
+            cache.try_cache_workouts(user_id, &[w0.clone(), w1.clone()]);
+            (user_id, w0, w1)
+        };
+
+        // overlaps w0 only
+        let a = |user_id| Workout {
+            exercises: vec![squat.clone(), lunge.clone()],
+            tags: vec!["legs".to_string(), "pr".to_string()],
+            ..get_workout(user_id, at(10, 30), at(11, 15))
+        };
+        // overlaps w0 and w1
+        let b = |user_id| get_workout(user_id, at(10, 45), at(12, 30));
+        // starts when w1 ends
+        let c = |user_id| get_workout(user_id, at(13, 0), at(14, 0));
+
+        let (user_id, w0, w1) = setup();
+        let (a, b, c) = (a(user_id), b(user_id), c(user_id));
+        assert_eq!(
+            cache.try_cache_new_workouts(user_id, &[a.clone(), b.clone(), c.clone()], OverlapPolicy::Reject),
+            vec![
+                CacheOutcome::Overlap { overlapping_workout_ids: vec![w0.workout_id] },
+                CacheOutcome::Overlap { overlapping_workout_ids: vec![w0.workout_id, w1.workout_id] },
+                CacheOutcome::Inserted,
+            ]
+        );
+        assert_eq!(cached(user_id), vec![c.clone(), w1.clone(), w0.clone()]);
+        assert!(matches!(
+            CacheOutcome::Overlap { overlapping_workout_ids: vec![w0.workout_id] }.to_response(a.workout_id),
+            NewWorkoutResponse::Error { err_code: crate::api::ERR_OVERLAP, .. }
+        ));
+
+        // a workout that started the evening before is found too
+        let (user_id, w0, _) = setup();
+        let a = Workout { user_id, ..a };
+        let long = get_workout(user_id, at(10, 30) - chrono::Duration::hours(14), at(10, 30));
+        let short = get_workout(user_id, at(10, 20), at(10, 40));
+        assert_eq!(
+            cache.try_cache_new_workouts(user_id, &[a.clone(), long.clone(), short.clone()], OverlapPolicy::Accept),
+            vec![
+                CacheOutcome::InsertedOverlapping { overlapping_workout_ids: vec![w0.workout_id] },
+                CacheOutcome::InsertedOverlapping { overlapping_workout_ids: vec![w0.workout_id] },
+                CacheOutcome::InsertedOverlapping { overlapping_workout_ids: vec![long.workout_id, w0.workout_id, a.workout_id] },
+            ]
+        );
+        assert_eq!(
+            CacheOutcome::InsertedOverlapping { overlapping_workout_ids: vec![w0.workout_id] }.to_response(a.workout_id),
+            NewWorkoutResponse::Success { workout_id: a.workout_id, overlaps: vec![w0.workout_id] }
+        );
+
+        let (user_id, w0, w1) = setup();
+        let (a, b, c) = (Workout { user_id, ..a }, Workout { user_id, ..b }, Workout { user_id, ..c });
+        let same_start_as_w1 = get_workout(user_id, at(12, 0), at(12, 20));
+        assert_eq!(
+            cache.try_cache_new_workouts(user_id, &[a.clone(), b.clone(), c.clone(), same_start_as_w1], OverlapPolicy::Merge),
+            vec![
+                CacheOutcome::Merged { merged_into: w0.workout_id },
+                CacheOutcome::Overlap { overlapping_workout_ids: vec![w0.workout_id, w1.workout_id] },
+                CacheOutcome::Inserted,
+                CacheOutcome::Merged { merged_into: w1.workout_id },
+            ]
+        );
+        let merged = Workout {
+            end_time: a.end_time,
+            exercises: vec![squat.clone(), lunge.clone()],
+            tags: vec!["legs".to_string(), "pr".to_string()],
+            ..w0.clone()
+        };
+        assert_eq!(cached(user_id), vec![c.clone(), w1.clone(), merged.clone()]);
+
+        // merging the same workout again changes nothing
+        assert_eq!(
+            cache.try_cache_new_workouts(user_id, std::slice::from_ref(&a), OverlapPolicy::Merge),
+            vec![CacheOutcome::Merged { merged_into: w0.workout_id }]
+        );
+        assert_eq!(cached(user_id), vec![c, w1, merged]);
+
+        // merging may not give a workout longer than a workout may be sent as
+        let user_id = Uuid::new_v4();
+        let w0 = get_workout(user_id, at(10, 0), at(11, 0));
+        cache.try_cache_workouts(user_id, std::slice::from_ref(&w0));
+        let max_duration = chrono::Duration::hours(crate::validate::MAX_WORKOUT_DURATION_HOURS);
+        let too_long = get_workout(user_id, at(10, 30), at(10, 1) + max_duration);
+        assert!(too_long.validate_duration().is_ok());
+        let outcomes = cache.try_cache_new_workouts(user_id, std::slice::from_ref(&too_long), OverlapPolicy::Merge);
+        assert!(matches!(
+            outcomes[..],
+            [CacheOutcome::InvalidMerge { merged_into, .. }] if merged_into == w0.workout_id
+        ));
+        assert!(matches!(
+            outcomes[0].to_response(too_long.workout_id),
+            NewWorkoutResponse::Error { err_code: crate::api::ERR_INVALID_MERGE, .. }
+        ));
+        assert_eq!(cached(user_id), vec![w0]);
+    }
+
+    #[test]
+    fn failed_write_is_undone_and_items_can_be_sent_again() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let cache = Cache::default();
+            let user_id = Uuid::new_v4();
+            let at = |h: u32, m: u32| Utc.with_ymd_and_hms(2021, 7, 27, h, m, 0).unwrap();
+            let get_workout = |start_time, end_time| -> Workout {
+                Workout {
+                    user_id,
+                    workout_id: Uuid::new_v4(),
+                    start_time,
+                    end_time,
+                    exercises: Vec::new(),
+                    category: None,
+                    tags: Vec::new(),
+                    note: None,
+                }
+            };
+            let cached = || cache.get_cached_workouts(&user_id, &query(None, None, None, None)).unwrap();
+
+            let w0 = get_workout(at(10, 0), at(11, 0));
+            cache.try_cache_workouts(user_id, std::slice::from_ref(&w0));
+
+            // a is new, b starts before w0 and is merged into it, c is merged into a
+            let a = get_workout(at(12, 0), at(13, 0));
+            let b = Workout { tags: vec!["legs".to_string()], ..get_workout(at(9, 30), at(10, 30)) };
+            let c = get_workout(at(12, 30), at(13, 30));
+            let items = vec![a.clone(), b.clone(), c.clone()];
+
+            let result = cache.write_new_workouts(user_id, &items, OverlapPolicy::Merge, |_, _| async {
+                Err("connection reset")
+            }).await;
+            assert_eq!(result, Err("connection reset"));
+            assert_eq!(cached(), vec![w0.clone()]);
+
+            let saved = std::sync::Mutex::new(None);
+            let written = cache.write_new_workouts(user_id, &items, OverlapPolicy::Merge, |inserted, merged| async {
+                *saved.lock().unwrap() = Some((inserted, merged));
+                Ok::<(), ()>(())
+            }).await.unwrap();
+
+            let a_merged = Workout { end_time: c.end_time, ..a.clone() };
+            let w0_merged = Workout { start_time: b.start_time, tags: b.tags.clone(), ..w0.clone() };
+            assert_eq!(written.outcomes, vec![
+                CacheOutcome::Inserted,
+                CacheOutcome::Merged { merged_into: w0.workout_id },
+                CacheOutcome::Merged { merged_into: a.workout_id },
+            ]);
+            assert_eq!(saved.lock().unwrap().take(), Some((vec![a_merged.clone()], vec![w0_merged.clone()])));
+            assert_eq!((written.inserted, written.merged), (vec![a_merged.clone()], vec![w0_merged.clone()]));
+            assert_eq!(cached(), vec![a_merged.clone(), w0_merged.clone()]);
+
+            // nothing to save when all items are duplicates
+            let written = cache.write_new_workouts(user_id, std::slice::from_ref(&a), OverlapPolicy::Merge, |_, _| async {
+                Err("not called")
+            }).await.unwrap();
+            assert_eq!(written.outcomes, vec![CacheOutcome::Duplicate]);
+
+            // a user who wasn't cached is not cached after a failed write either
+            let other_user = Uuid::new_v4();
+            let d = Workout { user_id: other_user, ..get_workout(at(8, 0), at(9, 0)) };
+            let result = cache.write_new_workouts(other_user, &[d], OverlapPolicy::Accept, |_, _| async {
+                Err("connection reset")
+            }).await;
+            assert!(result.is_err());
+            assert!(!cache.workouts_exist(&other_user));
+        });
+    }
+
+    #[test]
+    fn retry_during_failing_write_is_saved_once_it_fails() {
+        use futures::FutureExt;
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let cache = Cache::default();
+            let user_id = Uuid::new_v4();
+            let t0 = Utc.with_ymd_and_hms(2021, 7, 27, 10, 0, 0).unwrap();
+            let w0 = Workout {
+                user_id,
+                workout_id: Uuid::new_v4(),
+                start_time: t0,
+                end_time: t0 + chrono::Duration::hours(1),
+                exercises: Vec::new(),
+                category: None,
+                tags: Vec::new(),
+                note: None,
+            };
+            let cached = || cache.get_cached_workouts(&user_id, &query(None, None, None, None));
+
+            let (saving_tx, saving_rx) = tokio::sync::oneshot::channel();
+            let (fail_tx, fail_rx) = tokio::sync::oneshot::channel();
+            let first = cache.write_new_workouts(user_id, std::slice::from_ref(&w0), OverlapPolicy::Accept, |_, _| async move {
+                saving_tx.send(()).unwrap();
+                fail_rx.await.unwrap();
+                Err("connection reset")
+            });
+
+            let retry_during_save = async {
+                saving_rx.await.unwrap();
+                // not visible while it's being saved
+                assert_eq!(cached(), None);
+
+                let mut retry = Box::pin(cache.write_new_workouts(user_id, std::slice::from_ref(&w0), OverlapPolicy::Accept, |inserted, _| async move {
+                    assert_eq!(inserted.len(), 1);
+                    Ok::<(), &str>(())
+                }));
+                assert!((&mut retry).now_or_never().is_none());
+
+                fail_tx.send(()).unwrap();
+                retry.await
+            };
+
+            let (first, retry) = tokio::join!(first, retry_during_save);
+            assert_eq!(first.map(|w| w.outcomes), Err("connection reset"));
+            assert_eq!(retry.map(|w| w.outcomes), Ok(vec![CacheOutcome::Inserted]));
+            assert_eq!(cached(), Some(vec![w0.clone()]));
+
+            // the user's write lock isn't kept once nobody is writing
+            assert!(cache.write_locks.lock().unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn update_workout_rekeys_cache_and_enforces_unique_start_time() {
+        let cache = Cache::default();
+        let user_id = Uuid::new_v4();
+        let t0 = Utc.with_ymd_and_hms(2021, 7, 27, 6, 30, 0).unwrap();
+        let t1 = Utc.with_ymd_and_hms(2021, 7, 28, 6, 30, 0).unwrap();
+        let t2 = Utc.with_ymd_and_hms(2021, 7, 29, 6, 30, 0).unwrap();
+        let get_workout = |t| -> Workout {
+            Workout {
+                user_id,
+                workout_id: Uuid::new_v4(),
+                start_time: t,
+                end_time: t + chrono::Duration::hours(1),
+                exercises: Vec::new(),
+                category: None,
+                tags: Vec::new(),
+                note: None,
+            }
+        };
+        let w0 = get_workout(t0);
+        let w1 = get_workout(t1);
+        cache.try_cache_workouts(user_id, &[w0.clone(), w1.clone()]);
+
+        // moving w0 onto w1's start_time is rejected, and changes nothing
+        let w0_onto_w1 = Workout { start_time: t1, ..w0.clone() };
+        assert_eq!(cache.update_workout(&w0_onto_w1), UpdateOutcome::StartTimeConflict { existing_workout_id: w1.workout_id });
+        assert_eq!(cache.get_cached_workouts(&user_id, &query(None, None, None, None)).unwrap(), vec![w1.clone(), w0.clone()]);
+
+        // changing only end_time keeps the position
+        let w0_longer = Workout { end_time: t0 + chrono::Duration::hours(2), ..w0.clone() };
+        assert_eq!(cache.update_workout(&w0_longer), UpdateOutcome::Updated { previous: w0.clone() });
+        assert_eq!(cache.get_cached_workout(&user_id, &w0.workout_id), Some(w0_longer.clone()));
+
+        // moving w0 past w1 re-keys it
+        let w0_moved = Workout { start_time: t2, end_time: t2 + chrono::Duration::hours(1), ..w0.clone() };
+        assert_eq!(cache.update_workout(&w0_moved), UpdateOutcome::Updated { previous: w0_longer.clone() });
+        assert_eq!(cache.get_cached_workouts(&user_id, &query(None, None, None, None)).unwrap(), vec![w0_moved.clone(), w1.clone()]);
+        assert_eq!(cache.get_cached_workouts(&user_id, &query(Some(t0), Some(t1), None, None)).unwrap(), vec![]);
+        assert_eq!(cache.n_cached_workouts(&user_id), Some(2));
+
+        // the old start_time is free again
+        let w2 = get_workout(t0);
+        assert_eq!(cache.try_cache_workouts(user_id, std::slice::from_ref(&w2)), vec![CacheOutcome::Inserted]);
+
+        // other users' workouts can't be updated or removed
+        let other = Workout { user_id: Uuid::new_v4(), ..w1.clone() };
+        assert_eq!(cache.update_workout(&other), UpdateOutcome::NotFound);
+        assert_eq!(cache.remove_workout(&other.user_id, &w1.workout_id), None);
+        assert_eq!(cache.get_cached_workout(&other.user_id, &w1.workout_id), None);
+
+        assert_eq!(cache.remove_workout(&user_id, &w1.workout_id), Some(w1.clone()));
+        assert_eq!(cache.remove_workout(&user_id, &w1.workout_id), None);
+        assert_eq!(cache.update_workout(&w1), UpdateOutcome::NotFound);
+        assert_eq!(cache.get_cached_workouts(&user_id, &query(None, None, None, None)).unwrap(), vec![w0_moved.clone(), w2.clone()]);
+    }
+
+    #[test]
+    fn get_cached_workout_uses_workout_id_index() {
+        let cache = Cache::default();
+        let user_a = Uuid::new_v4();
+        let user_b = Uuid::new_v4();
+        let t0 = Utc.with_ymd_and_hms(2021, 7, 27, 6, 30, 0).unwrap();
+        let workouts: Vec<Workout> = (0..100)
+            .map(|i| {
+                let start_time = t0 + chrono::Duration::days(i);
+                Workout { user_id: user_a, workout_id: Uuid::new_v4(), start_time, end_time: start_time + chrono::Duration::hours(1), exercises: Vec::new(), category: None, tags: Vec::new(), note: None }
+            }).collect();
+        cache.try_cache_workouts(user_a, &workouts);
+        cache.try_cache_workouts(user_b, &[Workout { user_id: user_b, workout_id: Uuid::new_v4(), ..workouts[0].clone() }]);
+
+        for w in &workouts {
+            assert_eq!(cache.get_cached_workout(&user_a, &w.workout_id).as_ref(), Some(w));
+            assert_eq!(cache.get_cached_workout(&user_b, &w.workout_id), None);
+        }
+
+        let read_lock = cache.workouts.read().unwrap();
+        let user_cache = read_lock.get(&user_a).unwrap();
+        assert_eq!(user_cache.len(), 100);
+        assert_eq!(user_cache.get_at(&workouts[5].start_time), Some(&workouts[5]));
+        assert_eq!(user_cache.start_times.len(), user_cache.by_start_time.len());
+    }
+
+    #[test]
+    fn user_time_zone_sets_local_dates() {
+        let cache = Cache::default();
+        let user_id = Uuid::new_v4();
+        let chicago: Tz = "America/Chicago".parse().unwrap();
+        assert_eq!(cache.time_zone(&user_id), chrono_tz::UTC);
+        cache.set_time_zone(user_id, chicago);
+        assert_eq!(cache.time_zone(&user_id), chicago);
+        assert_eq!(cache.time_zone(&Uuid::new_v4()), chrono_tz::UTC);
+
+        // 8pm in chicago is 1am the next day in utc
+        let start_time = Utc.with_ymd_and_hms(2021, 7, 28, 1, 0, 0).unwrap();
+        let workout = Workout { user_id, workout_id: Uuid::new_v4(), start_time, end_time: start_time + chrono::Duration::minutes(45), exercises: Vec::new(), category: None, tags: Vec::new(), note: None };
+        let tz = cache.time_zone(&user_id);
+        assert_eq!(crate::api::ListWorkoutsItem::new(&workout, tz).date, NaiveDate::from_ymd_opt(2021, 7, 27).unwrap());
+        assert_eq!(crate::api::ListWorkoutsItem::new(&workout, chrono_tz::UTC).date, NaiveDate::from_ymd_opt(2021, 7, 28).unwrap());
+        let resp = crate::api::ListWorkoutsResponse::new(user_id, vec![workout.clone()], None, tz);
+        assert_eq!(resp.items[0].date, NaiveDate::from_ymd_opt(2021, 7, 27).unwrap());
+
+        cache.try_cache_workouts(user_id, std::slice::from_ref(&workout));
+        let today = NaiveDate::from_ymd_opt(2021, 7, 27).unwrap();
+        let stats = cache.get_workout_stats(&user_id, &WorkoutQuery::default(), tz, today).unwrap();
+        assert_eq!(stats.current_daily_streak, 1);
+        let stats = cache.get_workout_stats(&user_id, &WorkoutQuery::default(), chrono_tz::UTC, today).unwrap();
+        assert_eq!(stats.current_daily_streak, 0);
+
+        cache.set_time_zone(user_id, chrono_tz::UTC);
+        assert_eq!(cache.time_zone(&user_id), chrono_tz::UTC);
+        assert!(cache.time_zones.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn cursor_pagination_walks_full_history() {
+        let cache = Cache::default();
+        let user_id = Uuid::new_v4();
+        let t0 = Utc.with_ymd_and_hms(2021, 7, 27, 6, 30, 0).unwrap();
+        let mut workouts: Vec<Workout> = (0..25)
+            .map(|i| {
+                let start_time = t0 + chrono::Duration::hours(i * 12);
+                Workout { user_id, workout_id: Uuid::new_v4(), start_time, end_time: start_time + chrono::Duration::hours(1), exercises: Vec::new(), category: None, tags: Vec::new(), note: None }
+            }).collect();
+        cache.try_cache_workouts(user_id, &workouts);
+        workouts.reverse();
+
+        let cursor = Cursor::from(&workouts[0]);
+        let encoded = serde_json::to_string(&cursor).unwrap();
+        assert_eq!(serde_json::from_str::<Cursor>(&encoded).unwrap(), cursor);
+        assert!(serde_json::from_str::<Cursor>(r#""not-a-cursor""#).is_err());
+
+        let limit = 10;
+        let mut pages = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = cache.get_cached_workouts(&user_id, &query(None, None, Some(limit + 1), cursor)).unwrap();
+            let resp = crate::api::ListWorkoutsResponse::new(user_id, page.clone(), Some(limit), chrono_tz::UTC);
+            pages.extend(page.into_iter().take(limit));
+            match resp.cursor {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(pages, workouts);
+
+        // cursor combines with start/end filters, and a cursor at an exact start_time boundary
+        // excludes that workout
+        let cursor = Cursor::from(&workouts[5]);
+        let end = workouts[2].start_time;
+        let start = workouts[9].start_time;
+        assert_eq!(
+            cache.get_cached_workouts(&user_id, &query(Some(start), Some(end), None, Some(cursor))).unwrap(),
+            &workouts[6..=9]
+        );
+        let past_end = Cursor::from(&workouts[0]);
+        assert_eq!(
+            cache.get_cached_workouts(&user_id, &query(Some(start), Some(end), None, Some(past_end))).unwrap(),
+            &workouts[3..=9]
+        );
+        let before_start = Cursor::from(&workouts[20]);
+        assert_eq!(cache.get_cached_workouts(&user_id, &query(Some(start), Some(end), None, Some(before_start))).unwrap(), vec![]);
+        assert_eq!(cache.get_cached_workouts(&user_id, &query(Some(end), Some(start), None, None)).unwrap(), vec![]);
+    }
+
     #[test]
     fn sanity_check_cache_and_retrieve_workouts() {
         let cache = Cache::default();
         let user_id = Uuid::new_v4();
         let (priv_key, pub_key) = crate::auth::gen_keypair();
-        cache.insert_key(user_id, pub_key);
+        cache.insert_key(UserKey::new(user_id, pub_key));
         assert_eq!(cache.workouts_exist(&user_id), false);
-        assert!(matches!(cache.get_cached_workouts(&user_id, None, None, None), None));
+        assert!(matches!(cache.get_cached_workouts(&user_id, &query(None, None, None, None)), None));
 
-        let t0 = Utc.ymd(2021, 7, 27).and_hms(6, 30, 0);
-        let t1 = Utc.ymd(2021, 7, 28).and_hms(6, 30, 0);
-        let t2 = Utc.ymd(2021, 7, 29).and_hms(6, 30, 0);
+        let t0 = Utc.with_ymd_and_hms(2021, 7, 27, 6, 30, 0).unwrap();
+        let t1 = Utc.with_ymd_and_hms(2021, 7, 28, 6, 30, 0).unwrap();
+        let t2 = Utc.with_ymd_and_hms(2021, 7, 29, 6, 30, 0).unwrap();
 
         let get_workout = |t| -> Workout {
             Workout {
@@ -212,6 +1918,10 @@ mod tests {
                 workout_id: Uuid::new_v4(),
                 start_time: t,
                 end_time: t + chrono::Duration::hours(1),
+                exercises: Vec::new(),
+                category: None,
+                tags: Vec::new(),
+                note: None,
             }
         };
 
@@ -224,41 +1934,41 @@ mod tests {
         assert_eq!(cache.workouts_exist(&user_id), true);
         assert!(matches!(cache.n_cached_workouts(&user_id), Some(1)));
 
-        assert!(cache.get_cached_workouts(&user_id, None, None, None).is_some());
-        assert_eq!(cache.get_cached_workouts(&user_id, None, None, None).unwrap(), vec![w0.clone()]);
+        assert!(cache.get_cached_workouts(&user_id, &query(None, None, None, None)).is_some());
+        assert_eq!(cache.get_cached_workouts(&user_id, &query(None, None, None, None)).unwrap(), vec![w0.clone()]);
 
         // check filtering that results in 0 rows returned
-        assert!(cache.get_cached_workouts(&user_id, None, None, Some(0)).is_some());
-        assert_eq!(cache.get_cached_workouts(&user_id, None, None, Some(0)).unwrap(), Vec::new());
-        assert_eq!(cache.get_cached_workouts(&user_id, Some(t1), None, None).unwrap(), Vec::new());
-        assert_eq!(cache.get_cached_workouts(&user_id, None, Some(t0), None).unwrap(), Vec::new());
+        assert!(cache.get_cached_workouts(&user_id, &query(None, None, Some(0), None)).is_some());
+        assert_eq!(cache.get_cached_workouts(&user_id, &query(None, None, Some(0), None)).unwrap(), Vec::new());
+        assert_eq!(cache.get_cached_workouts(&user_id, &query(Some(t1), None, None, None)).unwrap(), Vec::new());
+        assert_eq!(cache.get_cached_workouts(&user_id, &query(None, Some(t0), None, None)).unwrap(), Vec::new());
 
         // cache [w0, w1]
         assert_eq!(cache.cache_workouts(user_id, &mut [w0.clone(), w1.clone()][..]), vec![w1.clone()]);
-        assert_eq!(cache.get_cached_workouts(&user_id, None, None, None).unwrap(), vec![w1.clone(), w0.clone()]);
+        assert_eq!(cache.get_cached_workouts(&user_id, &query(None, None, None, None)).unwrap(), vec![w1.clone(), w0.clone()]);
         assert!(matches!(cache.n_cached_workouts(&user_id), Some(2)));
 
         // cache [w0, w1, w2]
         assert_eq!(cache.cache_workouts(user_id, &mut [w0.clone(), w1.clone(), w2.clone()][..]), vec![w2.clone()]);
-        assert_eq!(cache.get_cached_workouts(&user_id, None, None, None).unwrap(), vec![w2.clone(), w1.clone(), w0.clone()]);
+        assert_eq!(cache.get_cached_workouts(&user_id, &query(None, None, None, None)).unwrap(), vec![w2.clone(), w1.clone(), w0.clone()]);
         assert!(matches!(cache.n_cached_workouts(&user_id), Some(3)));
 
         // check filtering by limit
-        assert_eq!(cache.get_cached_workouts(&user_id, None, None, Some(0)).unwrap(), Vec::new());
-        assert_eq!(cache.get_cached_workouts(&user_id, None, None, Some(1)).unwrap(), vec![w2.clone()]);
-        assert_eq!(cache.get_cached_workouts(&user_id, None, None, Some(2)).unwrap(), vec![w2.clone(), w1.clone()]);
-        assert_eq!(cache.get_cached_workouts(&user_id, None, None, Some(3)).unwrap(), vec![w2.clone(), w1.clone(), w0.clone()]);
+        assert_eq!(cache.get_cached_workouts(&user_id, &query(None, None, Some(0), None)).unwrap(), Vec::new());
+        assert_eq!(cache.get_cached_workouts(&user_id, &query(None, None, Some(1), None)).unwrap(), vec![w2.clone()]);
+        assert_eq!(cache.get_cached_workouts(&user_id, &query(None, None, Some(2), None)).unwrap(), vec![w2.clone(), w1.clone()]);
+        assert_eq!(cache.get_cached_workouts(&user_id, &query(None, None, Some(3), None)).unwrap(), vec![w2.clone(), w1.clone(), w0.clone()]);
 
         // check filtering by start
-        assert_eq!(cache.get_cached_workouts(&user_id, Some(t0), None, None).unwrap(), vec![w2.clone(), w1.clone(), w0.clone()]);
-        assert_eq!(cache.get_cached_workouts(&user_id, Some(t1), None, None).unwrap(), vec![w2.clone(), w1.clone()]);
-        assert_eq!(cache.get_cached_workouts(&user_id, Some(t2), None, None).unwrap(), vec![w2.clone()]);
-        assert_eq!(cache.get_cached_workouts(&user_id, Some(t2 + chrono::Duration::hours(1)), None, None).unwrap(), Vec::new());
+        assert_eq!(cache.get_cached_workouts(&user_id, &query(Some(t0), None, None, None)).unwrap(), vec![w2.clone(), w1.clone(), w0.clone()]);
+        assert_eq!(cache.get_cached_workouts(&user_id, &query(Some(t1), None, None, None)).unwrap(), vec![w2.clone(), w1.clone()]);
+        assert_eq!(cache.get_cached_workouts(&user_id, &query(Some(t2), None, None, None)).unwrap(), vec![w2.clone()]);
+        assert_eq!(cache.get_cached_workouts(&user_id, &query(Some(t2 + chrono::Duration::hours(1)), None, None, None)).unwrap(), Vec::new());
 
         // check filtering by end
-        assert_eq!(cache.get_cached_workouts(&user_id, None, Some(t2 + chrono::Duration::hours(1)), None).unwrap(), vec![w2.clone(), w1.clone(), w0.clone()]);
-        assert_eq!(cache.get_cached_workouts(&user_id, None, Some(t2), None).unwrap(), vec![w1.clone(), w0.clone()]);
-        assert_eq!(cache.get_cached_workouts(&user_id, None, Some(t1), None).unwrap(), vec![w0.clone()]);
-        assert_eq!(cache.get_cached_workouts(&user_id, None, Some(t0), None).unwrap(), Vec::new());
+        assert_eq!(cache.get_cached_workouts(&user_id, &query(None, Some(t2 + chrono::Duration::hours(1)), None, None)).unwrap(), vec![w2.clone(), w1.clone(), w0.clone()]);
+        assert_eq!(cache.get_cached_workouts(&user_id, &query(None, Some(t2), None, None)).unwrap(), vec![w1.clone(), w0.clone()]);
+        assert_eq!(cache.get_cached_workouts(&user_id, &query(None, Some(t1), None, None)).unwrap(), vec![w0.clone()]);
+        assert_eq!(cache.get_cached_workouts(&user_id, &query(None, Some(t0), None, None)).unwrap(), Vec::new());
     }
 }
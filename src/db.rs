@@ -1,10 +1,78 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
-use sqlx::{Pool, Executor};
-use sqlx::postgres::Postgres;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use futures::future::{self, BoxFuture};
+use sqlx::{Pool, Executor, Transaction};
+use sqlx::error::DatabaseError;
+use sqlx::postgres::{Postgres, PgConnection};
 use chrono::prelude::*;
+use chrono_tz::Tz;
 use uuid::Uuid;
 use crate::auth::PublicKey;
-use crate::{Workout, User};
+use crate::migrate::{self, Version};
+use crate::{Workout, Exercise, ExerciseSet, User, UserKey};
+
+/// future returned by `Storage` methods
+pub type StorageFuture<'a, T> = BoxFuture<'a, Result<T, sqlx::Error>>;
+
+/// where users, their keys and workouts are persisted. `DataBase` is the postgres
+/// implementation, `MemoryStorage` keeps everything in memory, for tests and demos.
+///
+/// implementations fail the way postgres would: a duplicate `workout_id`, or `start_time` for
+/// the same user, is a unique violation (see `is_unique_violation`)
+pub trait Storage: Clone + Send + Sync + 'static {
+    /// currently active (not revoked, not expired) keys for all users
+    fn fetch_user_keys(&self) -> StorageFuture<'_, Vec<UserKey>>;
+
+    /// all keys for a user, including expired and revoked keys, oldest first
+    fn fetch_keys_for_user<'a>(&'a self, user_id: &'a Uuid) -> StorageFuture<'a, Vec<UserKey>>;
+
+    fn insert_user_key<'a>(&'a self, k: &'a UserKey) -> StorageFuture<'a, ()>;
+
+    /// mark key as revoked at `revoked`, unless it is the user's only active key. the check
+    /// and the update are atomic, so concurrent revocations can't revoke every key
+    fn revoke_user_key<'a>(&'a self, user_id: &'a Uuid, key_id: &'a Uuid, revoked: DateTime<Utc>) -> StorageFuture<'a, KeyRevocation>;
+
+    /// time zones of all users with a time zone other than UTC
+    fn fetch_user_time_zones(&self) -> StorageFuture<'_, Vec<(Uuid, Tz)>>;
+
+    /// returns `false` if the user does not exist
+    fn update_user_time_zone<'a>(&'a self, user_id: &'a Uuid, tz: Tz) -> StorageFuture<'a, bool>;
+
+    /// all workouts of users with a workout started in the last 7 days
+    fn fetch_recently_active_user_workouts(&self) -> StorageFuture<'_, Vec<Workout>>;
+
+    fn fetch_user_workouts<'a>(&'a self, user_id: &'a Uuid) -> StorageFuture<'a, Vec<Workout>>;
+
+    /// `None` if the user has no workout with `workout_id`
+    fn fetch_workout<'a>(&'a self, user_id: &'a Uuid, workout_id: &'a Uuid) -> StorageFuture<'a, Option<Workout>>;
+
+    /// insert `inserted` and replace `merged`, saving either all or none. fails with
+    /// `sqlx::Error::RowNotFound` if a workout in `merged` doesn't exist
+    fn save_new_workouts<'a>(&'a self, inserted: &'a [Workout], merged: &'a [Workout]) -> StorageFuture<'a, ()>;
+
+    /// replace an existing workout. returns `false` if the user has no workout with
+    /// `workout.workout_id`
+    fn update_workout<'a>(&'a self, workout: &'a Workout) -> StorageFuture<'a, bool>;
+
+    /// returns `false` if the user has no workout with `workout_id`
+    fn delete_workout<'a>(&'a self, user_id: &'a Uuid, workout_id: &'a Uuid) -> StorageFuture<'a, bool>;
+
+    /// insert user and its first key, saving both or neither
+    fn insert_user<'a>(&'a self, user: &'a User, key: &'a UserKey) -> StorageFuture<'a, ()>;
+}
+
+/// result of `Storage::revoke_user_key`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyRevocation {
+    Revoked,
+    /// no un-revoked key with the `key_id` exists for the user
+    NotFound,
+    /// the key is the user's only active key, and revoking it would lock the user out for good
+    OnlyActiveKey,
+}
 
 /// wrapper around postgres connection pool to encapsulate db-related functionality
 #[derive(Clone)]
@@ -18,59 +86,250 @@ impl DataBase {
         Ok(Self { pool })
     }
 
-    pub async fn fetch_user_keys(&self) -> Result<Vec<(Uuid, PublicKey)>, sqlx::Error> {
-        let user_keys: Vec<(Uuid, Vec<u8>)> = sqlx::query_as("select user_id, key from users")
+    /// version of the schema, from the `migrations` table. `None` if there is no such table
+    pub async fn schema_version(&self) -> Result<Option<Version>, sqlx::Error> {
+        let mut conn = self.pool.acquire().await?;
+        schema_version(&mut conn).await
+    }
+
+    /// apply pending `migrate::POSTGRESQL` migrations, each in its own transaction with its
+    /// record in the `migrations` table. returns the versions applied
+    pub async fn migrate(&self) -> Result<Vec<Version>, sqlx::Error> {
+        let mut applied = Vec::new();
+        loop {
+            let mut tx = self.pool.begin().await?;
+
+            // a concurrent `migrate` waits here until the migration is committed, then finds it
+            // already applied
+            tx.execute(
+                sqlx::query("select pg_advisory_xact_lock($1)").bind(MIGRATIONS_LOCK_ID)
+            ).await?;
+
+            let current = schema_version(&mut tx).await?;
+            let migration = match migrate::pending(migrate::POSTGRESQL, current).first() {
+                Some(m) => m,
+                None => return Ok(applied),
+            };
+
+            tx.execute(migration.sql).await?;
+            tx.execute(
+                sqlx::query("insert into migrations (version, descr) values ($1, $2)")
+                    .bind(migration.version.to_string())
+                    .bind(migration.descr)
+            ).await?;
+
+            tx.commit().await?;
+
+            applied.push(migration.version);
+        }
+    }
+
+    /// fetch currently active (not revoked, not expired) keys for all users
+    pub async fn fetch_user_keys(&self) -> Result<Vec<UserKey>, sqlx::Error> {
+        let rows: Vec<UserKeyRow> = sqlx::query_as(
+                "select key_id, user_id, key, created, expires, revoked \
+                 from user_keys \
+                 where revoked is null \
+                   and (expires is null or expires > now())")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.into_iter().map(user_key_from_row).collect())
+    }
+
+    /// fetch all keys for a user, including expired and revoked keys
+    pub async fn fetch_keys_for_user(&self, user_id: &Uuid) -> Result<Vec<UserKey>, sqlx::Error> {
+        let rows: Vec<UserKeyRow> = sqlx::query_as(
+                "select key_id, user_id, key, created, expires, revoked \
+                 from user_keys \
+                 where user_id = $1 \
+                 order by created")
+            .bind(user_id)
             .fetch_all(&self.pool)
             .await?;
-        let user_keys = user_keys.into_iter()
-            .map(|(user_id, public_key_vec)| {
-                let public_key: PublicKey = public_key_vec.try_into().expect("failed to convert Vec<u8> to PublicKey");
-                (user_id, public_key)
-            }).collect();
-        Ok(user_keys)
+        Ok(rows.into_iter().map(user_key_from_row).collect())
+    }
+
+    pub async fn insert_user_key(&self, k: &UserKey) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "insert into user_keys (key_id, user_id, key, created, expires, revoked) \
+             values ($1, $2, $3, $4, $5, $6)"
+        )
+            .bind(k.key_id)
+            .bind(k.user_id)
+            .bind(&k.key[..])
+            .bind(k.created)
+            .bind(k.expires)
+            .bind(k.revoked)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// mark key as revoked at `revoked`, unless it is the user's only active key (an expired
+    /// key can always be revoked)
+    pub async fn revoke_user_key(&self, user_id: &Uuid, key_id: &Uuid, revoked: DateTime<Utc>) -> Result<KeyRevocation, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        // a concurrent revocation for the same user waits here until this one commits, then
+        // sees the key revoked when checking for other active keys
+        tx.execute(
+            sqlx::query("select 1 from users where user_id = $1 for update").bind(user_id)
+        ).await?;
+
+        let result = sqlx::query(
+            "update user_keys set revoked = $3 \
+             where user_id = $1 and key_id = $2 and revoked is null \
+               and ((expires is not null and expires <= $3) or exists ( \
+                 select 1 from user_keys o \
+                 where o.user_id = $1 and o.key_id <> $2 and o.revoked is null \
+                   and (o.expires is null or o.expires > $3)))"
+        )
+            .bind(user_id)
+            .bind(key_id)
+            .bind(revoked)
+            .execute(&mut tx)
+            .await?;
+
+        let revocation = if result.rows_affected() > 0 {
+            KeyRevocation::Revoked
+        } else {
+            let (n,): (i64,) = sqlx::query_as(
+                    "select count(*) from user_keys where user_id = $1 and key_id = $2 and revoked is null")
+                .bind(user_id)
+                .bind(key_id)
+                .fetch_one(&mut tx)
+                .await?;
+            if n > 0 { KeyRevocation::OnlyActiveKey } else { KeyRevocation::NotFound }
+        };
+
+        tx.commit().await?;
+
+        Ok(revocation)
+    }
+
+    /// fetch time zones of all users with a time zone other than UTC. unrecognized time zone
+    /// names are skipped
+    pub async fn fetch_user_time_zones(&self) -> Result<Vec<(Uuid, Tz)>, sqlx::Error> {
+        let rows: Vec<(Uuid, String)> = sqlx::query_as(
+                "select user_id, tz from users where tz <> 'UTC'")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.into_iter().filter_map(|(user_id, tz)| Some((user_id, tz.parse().ok()?))).collect())
+    }
+
+    /// returns `false` if the user does not exist
+    pub async fn update_user_time_zone(&self, user_id: &Uuid, tz: Tz) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            "update users set tz = $2 where user_id = $1"
+        )
+            .bind(user_id)
+            .bind(tz.name())
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
     }
 
     pub async fn fetch_recently_active_user_workouts(&self) -> Result<Vec<Workout>, sqlx::Error> {
-        let workout_tuples: Vec<(Uuid, Uuid, DateTime<Utc>, DateTime<Utc>)> =
+        let workout_rows: Vec<WorkoutRow> =
             sqlx::query_as(
-                "select w.user_id, w.workout_id, w.start_time, w.end_time from workouts w \
+                "select w.user_id, w.workout_id, w.start_time, w.end_time, w.category, w.tags, w.note \
+                 from workouts w \
                  where w.user_id in ( \
                      select distinct(user_id) from workouts \
                      where start_time >= now() - interval '7 days' \
                  )")
             .fetch_all(&self.pool)
             .await?;
-        Ok(workout_tuples.into_iter().map(|(user_id, workout_id, start_time, end_time)| {
-            Workout { user_id, workout_id, start_time, end_time }
-        }).collect())
+        let mut workouts: Vec<Workout> = workout_rows.into_iter().map(workout_from_row).collect();
+        self.attach_exercises(&mut workouts[..]).await?;
+        Ok(workouts)
     }
 
     pub async fn fetch_user_workouts(&self, user_id: &Uuid) -> Result<Vec<Workout>, sqlx::Error> {
-        let workout_rows: Vec<(Uuid, DateTime<Utc>, DateTime<Utc>)> = sqlx::query_as(
-                "select workout_id, start_time, end_time \
+        let workout_rows: Vec<WorkoutRow> = sqlx::query_as(
+                "select user_id, workout_id, start_time, end_time, category, tags, note \
                  from workouts \
                  where user_id = $1")
             .bind(user_id)
             .fetch_all(&self.pool)
             .await?;
-        Ok(workout_rows.into_iter().map(|(workout_id, start_time, end_time)| {
-            Workout { user_id: *user_id, workout_id, start_time, end_time }
-        }).collect())
+        let mut workouts: Vec<Workout> = workout_rows.into_iter().map(workout_from_row).collect();
+        self.attach_exercises(&mut workouts[..]).await?;
+        Ok(workouts)
+    }
+
+    /// `None` if the user has no workout with `workout_id`
+    pub async fn fetch_workout(&self, user_id: &Uuid, workout_id: &Uuid) -> Result<Option<Workout>, sqlx::Error> {
+        let workout_row: Option<WorkoutRow> = sqlx::query_as(
+                "select user_id, workout_id, start_time, end_time, category, tags, note \
+                 from workouts \
+                 where user_id = $1 and workout_id = $2")
+            .bind(user_id)
+            .bind(workout_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        let mut workouts: Vec<Workout> = workout_row.into_iter().map(workout_from_row).collect();
+        self.attach_exercises(&mut workouts[..]).await?;
+        Ok(workouts.pop())
+    }
+
+    /// fill in `exercises` of `workouts` from the `exercises` and `exercise_sets` tables
+    async fn attach_exercises(&self, workouts: &mut [Workout]) -> Result<(), sqlx::Error> {
+        if workouts.is_empty() {
+            return Ok(())
+        }
+        let workout_ids: Vec<Uuid> = workouts.iter().map(|w| w.workout_id).collect();
+
+        let exercise_rows: Vec<(Uuid, String)> = sqlx::query_as(
+                "select workout_id, name \
+                 from exercises \
+                 where workout_id = any($1) \
+                 order by workout_id, position")
+            .bind(&workout_ids[..])
+            .fetch_all(&self.pool)
+            .await?;
+        if exercise_rows.is_empty() {
+            return Ok(())
+        }
+
+        let set_rows: Vec<ExerciseSetRow> = sqlx::query_as(
+                "select workout_id, exercise_position, reps, weight, unit, rpe \
+                 from exercise_sets \
+                 where workout_id = any($1) \
+                 order by workout_id, exercise_position, position")
+            .bind(&workout_ids[..])
+            .fetch_all(&self.pool)
+            .await?;
+
+        let mut exercises: HashMap<Uuid, Vec<Exercise>> = HashMap::new();
+        for (workout_id, name) in exercise_rows {
+            exercises.entry(workout_id).or_default().push(Exercise { name, sets: Vec::new() });
+        }
+        // positions are contiguous from 0, as written by `insert_workouts`
+        for (workout_id, exercise_position, reps, weight, unit, rpe) in set_rows {
+            let exercise = exercises.get_mut(&workout_id)
+                .and_then(|x| x.get_mut(exercise_position as usize));
+            if let Some(exercise) = exercise {
+                let unit = unit.parse().unwrap_or_default();
+                exercise.sets.push(ExerciseSet { reps: reps as u32, weight, unit, rpe });
+            }
+        }
+        for workout in workouts.iter_mut() {
+            if let Some(x) = exercises.remove(&workout.workout_id) {
+                workout.exercises = x;
+            }
+        }
+
+        Ok(())
     }
 
+    /// insert workouts one statement per row, failing on any duplicate. for large batches, see
+    /// `bulk_insert_workouts`
     pub async fn insert_workouts(&self, workouts: &[Workout]) -> Result<(), sqlx::Error> {
         let mut tx = self.pool.begin().await?;
 
         for w in workouts {
-            tx.execute(
-                sqlx::query(
-                    "insert into workouts (user_id, workout_id, start_time, end_time) values ($1, $2, $3, $4)"
-                )
-                    .bind(w.user_id)
-                    .bind(w.workout_id)
-                    .bind(w.start_time)
-                    .bind(w.end_time)
-            ).await?;
+            insert_workout(&mut tx, w).await?;
         }
 
         tx.commit().await?;
@@ -78,6 +337,70 @@ impl DataBase {
         Ok(())
     }
 
+    /// insert workouts with a few multi-row statements, in one transaction. duplicates are
+    /// handled like the cache does: a workout already saved with the same `workout_id` and
+    /// `start_time` is skipped, while a different workout at the same `start_time`, or the same
+    /// `workout_id` at a different `start_time`, fails with a unique violation (see
+    /// `is_unique_violation`) and nothing is saved. returns the ids of the workouts inserted
+    pub async fn bulk_insert_workouts(&self, workouts: &[Workout]) -> Result<Vec<Uuid>, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let inserted = bulk_insert_workouts(&mut tx, workouts).await?;
+
+        tx.commit().await?;
+
+        Ok(inserted)
+    }
+
+    /// insert `inserted` (see `bulk_insert_workouts`) and replace `merged` (see
+    /// `update_workout`) in one transaction, so either all are saved or none. fails with
+    /// `sqlx::Error::RowNotFound` if a workout in `merged` doesn't exist
+    pub async fn save_new_workouts(&self, inserted: &[Workout], merged: &[Workout]) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        bulk_insert_workouts(&mut tx, inserted).await?;
+
+        for w in merged {
+            if ! update_workout(&mut tx, w).await? {
+                return Err(sqlx::Error::RowNotFound)
+            }
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// replace an existing workout: its start and end time, category, tags, note and exercises.
+    /// returns `false` if the user has no workout with `workout.workout_id`. a `start_time`
+    /// already used by another of the user's workouts fails with a unique violation (see
+    /// `is_unique_violation`)
+    pub async fn update_workout(&self, workout: &Workout) -> Result<bool, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        if ! update_workout(&mut tx, workout).await? {
+            return Ok(false)
+        }
+
+        tx.commit().await?;
+
+        Ok(true)
+    }
+
+    /// returns `false` if the user has no workout with `workout_id`
+    pub async fn delete_workout(&self, user_id: &Uuid, workout_id: &Uuid) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            "delete from workouts where user_id = $1 and workout_id = $2"
+        )
+            .bind(user_id)
+            .bind(workout_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// insert users and their first key one statement per row. for large batches, see
+    /// `bulk_insert_users`
     pub async fn insert_users(&self, users: &[User]) -> Result<(), sqlx::Error> {
         let mut tx = self.pool.begin().await?;
 
@@ -93,10 +416,121 @@ impl DataBase {
                     .bind(&u.key[..])
                     .bind(created)
             ).await?;
+
+            tx.execute(
+                sqlx::query(
+                    "insert into user_keys (user_id, key, created) values ($1, $2, $3)"
+                )
+                    .bind(u.user_id)
+                    .bind(&u.key[..])
+                    .bind(created)
+            ).await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// insert users and their first key with a few multi-row statements, in one transaction.
+    /// like `bulk_insert_workouts`, a user already saved with the same `user_id`, `email` and
+    /// `key` is skipped, so an interrupted seeding can be run again, while any other conflict,
+    /// in the db or within `users` (see `dedup_users`), fails with a unique violation and nothing
+    /// is saved. returns the number of users inserted
+    pub async fn bulk_insert_users(&self, users: &[User]) -> Result<usize, sqlx::Error> {
+        let users = dedup_users(users)?;
+
+        let mut tx = self.pool.begin().await?;
+
+        let mut n_inserted = 0;
+        for chunk in users.chunks(BULK_INSERT_CHUNK_SIZE) {
+            let user_ids: Vec<Uuid> = chunk.iter().map(|u| u.user_id).collect();
+            let emails: Vec<&str> = chunk.iter().map(|u| &u.email[..]).collect();
+            let keys: Vec<&[u8]> = chunk.iter().map(|u| &u.key[..]).collect();
+            let created: Vec<DateTime<Utc>> = chunk.iter().map(|u| u.created).collect();
+
+            let inserted: Vec<(Uuid, Vec<u8>, DateTime<Utc>)> = sqlx::query_as(
+                    "insert into users (user_id, email, key, created) \
+                     select * from unnest($1::uuid[], $2::text[], $3::bytea[], $4::timestamptz[]) \
+                     on conflict (user_id) do nothing \
+                     returning user_id, key, created")
+                .bind(&user_ids)
+                .bind(&emails)
+                .bind(&keys)
+                .bind(&created)
+                .fetch_all(&mut tx)
+                .await?;
+
+            // skipped users are duplicates only if saved with the same email and key
+            if inserted.len() < chunk.len() {
+                let (n_conflicts,): (i64,) = sqlx::query_as(
+                        "select count(*) from users u \
+                         inner join unnest($1::uuid[], $2::text[], $3::bytea[]) as t(user_id, email, key) \
+                           on u.user_id = t.user_id \
+                         where u.email <> t.email or u.key <> t.key")
+                    .bind(&user_ids)
+                    .bind(&emails)
+                    .bind(&keys)
+                    .fetch_one(&mut tx)
+                    .await?;
+                if n_conflicts > 0 {
+                    return Err(ConstraintViolation::unique("users_pkey"))
+                }
+            }
+
+            // keys of the rows that were inserted, so a key is only ever saved for its own user
+            let key_user_ids: Vec<Uuid> = inserted.iter().map(|(user_id, _, _)| *user_id).collect();
+            let key_keys: Vec<&[u8]> = inserted.iter().map(|(_, key, _)| &key[..]).collect();
+            let key_created: Vec<DateTime<Utc>> = inserted.iter().map(|(_, _, created)| *created).collect();
+
+            tx.execute(
+                sqlx::query(
+                    "insert into user_keys (user_id, key, created) \
+                     select * from unnest($1::uuid[], $2::bytea[], $3::timestamptz[])"
+                )
+                    .bind(&key_user_ids)
+                    .bind(&key_keys)
+                    .bind(&key_created)
+            ).await?;
+
+            n_inserted += inserted.len();
         }
 
         tx.commit().await?;
 
+        Ok(n_inserted)
+    }
+
+    /// insert user and its first key in one transaction
+    pub async fn insert_user(&self, user: &User, key: &UserKey) -> Result<(), sqlx::Error> {
+        debug_assert_eq!(user.user_id, key.user_id);
+        debug_assert_eq!(user.key, key.key);
+
+        let mut tx = self.pool.begin().await?;
+
+        tx.execute(
+            sqlx::query(
+                "insert into users (user_id, email, key, created) values ($1, $2, $3, $4)"
+            )
+                .bind(user.user_id)
+                .bind(&user.email[..])
+                .bind(&user.key[..])
+                .bind(user.created)
+        ).await?;
+
+        tx.execute(
+            sqlx::query(
+                "insert into user_keys (key_id, user_id, key, created, expires) values ($1, $2, $3, $4, $5)"
+            )
+                .bind(key.key_id)
+                .bind(key.user_id)
+                .bind(&key.key[..])
+                .bind(key.created)
+                .bind(key.expires)
+        ).await?;
+
+        tx.commit().await?;
+
         Ok(())
     }
 
@@ -108,3 +542,862 @@ impl DataBase {
     }
 }
 
+impl Storage for DataBase {
+    fn fetch_user_keys(&self) -> StorageFuture<'_, Vec<UserKey>> {
+        Box::pin(DataBase::fetch_user_keys(self))
+    }
+
+    fn fetch_keys_for_user<'a>(&'a self, user_id: &'a Uuid) -> StorageFuture<'a, Vec<UserKey>> {
+        Box::pin(DataBase::fetch_keys_for_user(self, user_id))
+    }
+
+    fn insert_user_key<'a>(&'a self, k: &'a UserKey) -> StorageFuture<'a, ()> {
+        Box::pin(DataBase::insert_user_key(self, k))
+    }
+
+    fn revoke_user_key<'a>(&'a self, user_id: &'a Uuid, key_id: &'a Uuid, revoked: DateTime<Utc>) -> StorageFuture<'a, KeyRevocation> {
+        Box::pin(DataBase::revoke_user_key(self, user_id, key_id, revoked))
+    }
+
+    fn fetch_user_time_zones(&self) -> StorageFuture<'_, Vec<(Uuid, Tz)>> {
+        Box::pin(DataBase::fetch_user_time_zones(self))
+    }
+
+    fn update_user_time_zone<'a>(&'a self, user_id: &'a Uuid, tz: Tz) -> StorageFuture<'a, bool> {
+        Box::pin(DataBase::update_user_time_zone(self, user_id, tz))
+    }
+
+    fn fetch_recently_active_user_workouts(&self) -> StorageFuture<'_, Vec<Workout>> {
+        Box::pin(DataBase::fetch_recently_active_user_workouts(self))
+    }
+
+    fn fetch_user_workouts<'a>(&'a self, user_id: &'a Uuid) -> StorageFuture<'a, Vec<Workout>> {
+        Box::pin(DataBase::fetch_user_workouts(self, user_id))
+    }
+
+    fn fetch_workout<'a>(&'a self, user_id: &'a Uuid, workout_id: &'a Uuid) -> StorageFuture<'a, Option<Workout>> {
+        Box::pin(DataBase::fetch_workout(self, user_id, workout_id))
+    }
+
+    fn save_new_workouts<'a>(&'a self, inserted: &'a [Workout], merged: &'a [Workout]) -> StorageFuture<'a, ()> {
+        Box::pin(DataBase::save_new_workouts(self, inserted, merged))
+    }
+
+    fn update_workout<'a>(&'a self, workout: &'a Workout) -> StorageFuture<'a, bool> {
+        Box::pin(DataBase::update_workout(self, workout))
+    }
+
+    fn delete_workout<'a>(&'a self, user_id: &'a Uuid, workout_id: &'a Uuid) -> StorageFuture<'a, bool> {
+        Box::pin(DataBase::delete_workout(self, user_id, workout_id))
+    }
+
+    fn insert_user<'a>(&'a self, user: &'a User, key: &'a UserKey) -> StorageFuture<'a, ()> {
+        Box::pin(DataBase::insert_user(self, user, key))
+    }
+}
+
+/// database url that selects `MemoryStorage` instead of postgres
+pub const MEMORY_URL: &str = "memory:";
+
+/// `Storage` kept in memory, with the same constraints as the postgres schema. clones share
+/// the same data. nothing is persisted, so it's meant for tests and demos
+#[derive(Clone, Default)]
+pub struct MemoryStorage {
+    data: Arc<Mutex<MemoryData>>,
+}
+
+#[derive(Default)]
+struct MemoryData {
+    /// user_id -> (email, time zone)
+    users: HashMap<Uuid, (String, Tz)>,
+    keys: Vec<UserKey>,
+    workouts: HashMap<Uuid, Workout>,
+    /// when set, every operation fails as if the db were unreachable
+    unavailable: bool,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// make every following operation fail with `sqlx::Error::PoolClosed` (`true`), or
+    /// succeed again (`false`). for testing how failed writes are handled
+    pub fn set_unavailable(&self, unavailable: bool) {
+        self.data.lock().unwrap().unavailable = unavailable;
+    }
+
+    fn with<T, F>(&self, f: F) -> StorageFuture<'static, T>
+        where T: Send + 'static,
+              F: FnOnce(&mut MemoryData) -> Result<T, sqlx::Error>
+    {
+        let mut data = self.data.lock().unwrap();
+        let result = if data.unavailable { Err(sqlx::Error::PoolClosed) } else { f(&mut data) };
+        Box::pin(future::ready(result))
+    }
+}
+
+impl MemoryData {
+    fn user_workouts<'a>(&'a self, user_id: &'a Uuid) -> impl Iterator<Item = &'a Workout> + 'a {
+        self.workouts.values().filter(move |w| w.user_id == *user_id)
+    }
+
+    /// checks the constraints of the `workouts` table for `w`, ignoring the stored workout
+    /// with the same `workout_id` when `replacing`
+    fn check_workout(&self, w: &Workout, replacing: bool) -> Result<(), sqlx::Error> {
+        if ! self.users.contains_key(&w.user_id) {
+            return Err(ConstraintViolation::foreign_key("workouts_user_fkey"))
+        }
+        if ! replacing && self.workouts.contains_key(&w.workout_id) {
+            return Err(ConstraintViolation::unique("workouts_pkey"))
+        }
+        if self.user_workouts(&w.user_id).any(|x| x.start_time == w.start_time && x.workout_id != w.workout_id) {
+            return Err(ConstraintViolation::unique("user_start_uniq"))
+        }
+        Ok(())
+    }
+
+    fn workout_exists(&self, w: &Workout) -> bool {
+        self.workouts.get(&w.workout_id).map(|x| x.user_id == w.user_id).unwrap_or(false)
+    }
+
+    /// see `Storage::save_new_workouts`. like a rolled back transaction, nothing is saved if
+    /// any workout fails: the workouts saved before it are put back as they were
+    fn save_new_workouts(&mut self, inserted: &[Workout], merged: &[Workout]) -> Result<(), sqlx::Error> {
+        let mut saved = Vec::with_capacity(inserted.len() + merged.len());
+        let result = self.try_save_new_workouts(inserted, merged, &mut saved);
+        if result.is_err() {
+            for (workout_id, previous) in saved.into_iter().rev() {
+                match previous {
+                    Some(previous) => self.workouts.insert(workout_id, previous),
+                    None => self.workouts.remove(&workout_id),
+                };
+            }
+        }
+        result
+    }
+
+    /// adds the `workout_id` and previous version of each workout saved to `saved`, in order.
+    /// stops at the first workout that fails
+    fn try_save_new_workouts(
+        &mut self,
+        inserted: &[Workout],
+        merged: &[Workout],
+        saved: &mut Vec<(Uuid, Option<Workout>)>,
+    ) -> Result<(), sqlx::Error> {
+        for w in inserted {
+            self.check_workout(w, false)?;
+            saved.push((w.workout_id, self.workouts.insert(w.workout_id, w.clone())));
+        }
+        for w in merged {
+            if ! self.workout_exists(w) {
+                return Err(sqlx::Error::RowNotFound)
+            }
+            self.check_workout(w, true)?;
+            saved.push((w.workout_id, self.workouts.insert(w.workout_id, w.clone())));
+        }
+        Ok(())
+    }
+
+    fn check_key(&self, k: &UserKey) -> Result<(), sqlx::Error> {
+        if ! self.users.contains_key(&k.user_id) {
+            return Err(ConstraintViolation::foreign_key("user_keys_user_fkey"))
+        }
+        if self.keys.iter().any(|x| x.key_id == k.key_id) {
+            return Err(ConstraintViolation::unique("user_keys_pkey"))
+        }
+        if self.keys.iter().any(|x| x.user_id == k.user_id && x.key == k.key) {
+            return Err(ConstraintViolation::unique("user_key_uniq"))
+        }
+        Ok(())
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn fetch_user_keys(&self) -> StorageFuture<'_, Vec<UserKey>> {
+        let now = Utc::now();
+        self.with(|data| {
+            Ok(data.keys.iter()
+                .filter(|k| k.revoked.is_none() && k.expires.map(|x| x > now).unwrap_or(true))
+                .cloned()
+                .collect())
+        })
+    }
+
+    fn fetch_keys_for_user<'a>(&'a self, user_id: &'a Uuid) -> StorageFuture<'a, Vec<UserKey>> {
+        self.with(|data| {
+            let mut keys: Vec<UserKey> = data.keys.iter().filter(|k| k.user_id == *user_id).cloned().collect();
+            keys.sort_by_key(|k| k.created);
+            Ok(keys)
+        })
+    }
+
+    fn insert_user_key<'a>(&'a self, k: &'a UserKey) -> StorageFuture<'a, ()> {
+        self.with(|data| {
+            data.check_key(k)?;
+            data.keys.push(k.clone());
+            Ok(())
+        })
+    }
+
+    fn revoke_user_key<'a>(&'a self, user_id: &'a Uuid, key_id: &'a Uuid, revoked: DateTime<Utc>) -> StorageFuture<'a, KeyRevocation> {
+        self.with(|data| {
+            let is_only_active_key = |k: &UserKey| {
+                k.is_active_at(revoked)
+                    && ! data.keys.iter().any(|x| x.user_id == *user_id && x.key_id != *key_id && x.is_active_at(revoked))
+            };
+            let i = data.keys.iter()
+                .position(|k| k.user_id == *user_id && k.key_id == *key_id && k.revoked.is_none());
+            match i {
+                Some(i) if is_only_active_key(&data.keys[i]) => Ok(KeyRevocation::OnlyActiveKey),
+
+                Some(i) => {
+                    data.keys[i].revoked = Some(revoked);
+                    Ok(KeyRevocation::Revoked)
+                }
+
+                None => Ok(KeyRevocation::NotFound),
+            }
+        })
+    }
+
+    fn fetch_user_time_zones(&self) -> StorageFuture<'_, Vec<(Uuid, Tz)>> {
+        self.with(|data| {
+            Ok(data.users.iter()
+                .filter(|(_, (_, tz))| *tz != Tz::UTC)
+                .map(|(user_id, (_, tz))| (*user_id, *tz))
+                .collect())
+        })
+    }
+
+    fn update_user_time_zone<'a>(&'a self, user_id: &'a Uuid, tz: Tz) -> StorageFuture<'a, bool> {
+        self.with(|data| {
+            match data.users.get_mut(user_id) {
+                Some(user) => {
+                    user.1 = tz;
+                    Ok(true)
+                }
+
+                None => Ok(false),
+            }
+        })
+    }
+
+    fn fetch_recently_active_user_workouts(&self) -> StorageFuture<'_, Vec<Workout>> {
+        let since = Utc::now() - chrono::Duration::days(7);
+        self.with(|data| {
+            let active: HashSet<Uuid> = data.workouts.values()
+                .filter(|w| w.start_time >= since)
+                .map(|w| w.user_id)
+                .collect();
+            Ok(data.workouts.values().filter(|w| active.contains(&w.user_id)).cloned().collect())
+        })
+    }
+
+    fn fetch_user_workouts<'a>(&'a self, user_id: &'a Uuid) -> StorageFuture<'a, Vec<Workout>> {
+        self.with(|data| Ok(data.user_workouts(user_id).cloned().collect()))
+    }
+
+    fn fetch_workout<'a>(&'a self, user_id: &'a Uuid, workout_id: &'a Uuid) -> StorageFuture<'a, Option<Workout>> {
+        self.with(|data| Ok(data.workouts.get(workout_id).filter(|w| w.user_id == *user_id).cloned()))
+    }
+
+    fn save_new_workouts<'a>(&'a self, inserted: &'a [Workout], merged: &'a [Workout]) -> StorageFuture<'a, ()> {
+        self.with(|data| data.save_new_workouts(inserted, merged))
+    }
+
+    fn update_workout<'a>(&'a self, workout: &'a Workout) -> StorageFuture<'a, bool> {
+        self.with(|data| {
+            if ! data.workout_exists(workout) {
+                return Ok(false)
+            }
+            data.check_workout(workout, true)?;
+            data.workouts.insert(workout.workout_id, workout.clone());
+            Ok(true)
+        })
+    }
+
+    fn delete_workout<'a>(&'a self, user_id: &'a Uuid, workout_id: &'a Uuid) -> StorageFuture<'a, bool> {
+        self.with(|data| {
+            match data.workouts.get(workout_id) {
+                Some(w) if w.user_id == *user_id => {
+                    data.workouts.remove(workout_id);
+                    Ok(true)
+                }
+
+                _ => Ok(false),
+            }
+        })
+    }
+
+    fn insert_user<'a>(&'a self, user: &'a User, key: &'a UserKey) -> StorageFuture<'a, ()> {
+        debug_assert_eq!(user.user_id, key.user_id);
+        debug_assert_eq!(user.key, key.key);
+
+        self.with(|data| {
+            if data.users.contains_key(&user.user_id) {
+                return Err(ConstraintViolation::unique("users_pkey"))
+            }
+            if data.users.values().any(|(email, _)| *email == user.email) {
+                return Err(ConstraintViolation::unique("users_email_key"))
+            }
+            data.users.insert(user.user_id, (user.email.clone(), Tz::UTC));
+            if let Err(e) = data.check_key(key) {
+                data.users.remove(&user.user_id);
+                return Err(e)
+            }
+            data.keys.push(key.clone());
+            Ok(())
+        })
+    }
+}
+
+/// constraint violation found by this module rather than reported by postgres, e.g. by
+/// `MemoryStorage` or the duplicate checks of the bulk inserts, with the postgres SQLSTATE code
+#[derive(Debug)]
+struct ConstraintViolation {
+    code: &'static str,
+    constraint: &'static str,
+    message: String,
+}
+
+impl ConstraintViolation {
+    fn unique(constraint: &'static str) -> sqlx::Error {
+        Self::error("23505", constraint, "duplicate key value violates unique constraint")
+    }
+
+    fn foreign_key(constraint: &'static str) -> sqlx::Error {
+        Self::error("23503", constraint, "insert or update violates foreign key constraint")
+    }
+
+    fn error(code: &'static str, constraint: &'static str, message: &str) -> sqlx::Error {
+        let message = format!("{} \"{}\"", message, constraint);
+        sqlx::Error::Database(Box::new(Self { code, constraint, message }))
+    }
+}
+
+impl fmt::Display for ConstraintViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ConstraintViolation {}
+
+impl DatabaseError for ConstraintViolation {
+    fn message(&self) -> &str {
+        &self.message
+    }
+
+    fn code(&self) -> Option<Cow<'_, str>> {
+        Some(Cow::Borrowed(self.code))
+    }
+
+    fn as_error(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
+        self
+    }
+
+    fn as_error_mut(&mut self) -> &mut (dyn std::error::Error + Send + Sync + 'static) {
+        self
+    }
+
+    fn into_error(self: Box<Self>) -> Box<dyn std::error::Error + Send + Sync + 'static> {
+        self
+    }
+
+    fn constraint(&self) -> Option<&str> {
+        Some(self.constraint)
+    }
+}
+
+/// most rows bound to one statement by the bulk inserts
+const BULK_INSERT_CHUNK_SIZE: usize = 10_000;
+
+/// `users` without repeated rows, in the same order, for `DataBase::bulk_insert_users`. the
+/// same `user_id` with a different email or key fails with a unique violation, rather than
+/// one of the rows being dropped, or both keys being saved for the user
+fn dedup_users(users: &[User]) -> Result<Vec<&User>, sqlx::Error> {
+    let mut seen: HashMap<Uuid, &User> = HashMap::with_capacity(users.len());
+    let mut deduped = Vec::with_capacity(users.len());
+    for u in users {
+        match seen.get(&u.user_id) {
+            Some(first) if first.email == u.email && first.key == u.key => {}
+
+            Some(_) => return Err(ConstraintViolation::unique("users_pkey")),
+
+            None => {
+                seen.insert(u.user_id, u);
+                deduped.push(u);
+            }
+        }
+    }
+    Ok(deduped)
+}
+
+/// see `DataBase::bulk_insert_workouts`
+async fn bulk_insert_workouts(tx: &mut Transaction<'_, Postgres>, workouts: &[Workout]) -> Result<Vec<Uuid>, sqlx::Error> {
+    let mut inserted_ids = Vec::with_capacity(workouts.len());
+
+    for chunk in workouts.chunks(BULK_INSERT_CHUNK_SIZE) {
+        let user_ids: Vec<Uuid> = chunk.iter().map(|w| w.user_id).collect();
+        let workout_ids: Vec<Uuid> = chunk.iter().map(|w| w.workout_id).collect();
+        let start_times: Vec<DateTime<Utc>> = chunk.iter().map(|w| w.start_time).collect();
+        let end_times: Vec<DateTime<Utc>> = chunk.iter().map(|w| w.end_time).collect();
+        let categories: Vec<Option<&str>> = chunk.iter().map(|w| w.category.map(|c| c.as_str())).collect();
+        // arrays of arrays must be rectangular in postgres, so tags are passed as json
+        let tags: Vec<String> = chunk.iter()
+            .map(|w| serde_json::to_string(&w.tags).expect("failed to serialize tags"))
+            .collect();
+        let notes: Vec<Option<&str>> = chunk.iter().map(|w| w.note.as_deref()).collect();
+
+        // a different workout at the same start time still fails on user_start_uniq
+        let inserted: Vec<(Uuid,)> = sqlx::query_as(
+                "insert into workouts (user_id, workout_id, start_time, end_time, category, tags, note) \
+                 select t.user_id, t.workout_id, t.start_time, t.end_time, t.category, \
+                        array(select jsonb_array_elements_text(t.tags::jsonb)), t.note \
+                 from unnest($1::uuid[], $2::uuid[], $3::timestamptz[], $4::timestamptz[], $5::text[], $6::text[], $7::text[]) \
+                   as t(user_id, workout_id, start_time, end_time, category, tags, note) \
+                 on conflict (workout_id) do nothing \
+                 returning workout_id")
+            .bind(&user_ids)
+            .bind(&workout_ids)
+            .bind(&start_times)
+            .bind(&end_times)
+            .bind(&categories)
+            .bind(&tags)
+            .bind(&notes)
+            .fetch_all(&mut *tx)
+            .await?;
+
+        // skipped workouts are duplicates only if saved with the same user and start time
+        if inserted.len() < chunk.len() {
+            let (n_conflicts,): (i64,) = sqlx::query_as(
+                    "select count(*) from workouts w \
+                     inner join unnest($1::uuid[], $2::uuid[], $3::timestamptz[]) as t(user_id, workout_id, start_time) \
+                       on w.workout_id = t.workout_id \
+                     where w.user_id <> t.user_id or w.start_time <> t.start_time")
+                .bind(&user_ids)
+                .bind(&workout_ids)
+                .bind(&start_times)
+                .fetch_one(&mut *tx)
+                .await?;
+            if n_conflicts > 0 {
+                return Err(ConstraintViolation::unique("workouts_pkey"))
+            }
+        }
+
+        // exercises of the first of several items with the same workout_id, which is the one
+        // inserted
+        let mut inserted: HashSet<Uuid> = inserted.into_iter().map(|(workout_id,)| workout_id).collect();
+        let new_workouts: Vec<&Workout> = chunk.iter()
+            .filter(|w| inserted.remove(&w.workout_id))
+            .collect();
+        bulk_insert_exercises(tx, &new_workouts).await?;
+
+        inserted_ids.extend(new_workouts.iter().map(|w| w.workout_id));
+    }
+
+    Ok(inserted_ids)
+}
+
+/// like `insert_exercises`, for all of `workouts` at once
+async fn bulk_insert_exercises(tx: &mut Transaction<'_, Postgres>, workouts: &[&Workout]) -> Result<(), sqlx::Error> {
+    let exercises = || workouts.iter()
+        .flat_map(|w| w.exercises.iter().enumerate().map(move |(i, x)| (w.workout_id, i as i32, x)));
+    if exercises().next().is_none() {
+        return Ok(())
+    }
+
+    let workout_ids: Vec<Uuid> = exercises().map(|(workout_id, _, _)| workout_id).collect();
+    let positions: Vec<i32> = exercises().map(|(_, i, _)| i).collect();
+    let names: Vec<&str> = exercises().map(|(_, _, x)| &x.name[..]).collect();
+    tx.execute(
+        sqlx::query(
+            "insert into exercises (workout_id, position, name) \
+             select * from unnest($1::uuid[], $2::int4[], $3::text[])"
+        )
+            .bind(&workout_ids)
+            .bind(&positions)
+            .bind(&names)
+    ).await?;
+
+    let sets = || exercises()
+        .flat_map(|(workout_id, i, x)| x.sets.iter().enumerate().map(move |(j, set)| (workout_id, i, j as i32, set)));
+    if sets().next().is_none() {
+        return Ok(())
+    }
+
+    let workout_ids: Vec<Uuid> = sets().map(|(workout_id, _, _, _)| workout_id).collect();
+    let exercise_positions: Vec<i32> = sets().map(|(_, i, _, _)| i).collect();
+    let positions: Vec<i32> = sets().map(|(_, _, j, _)| j).collect();
+    let reps: Vec<i32> = sets().map(|(_, _, _, set)| set.reps as i32).collect();
+    let weights: Vec<Option<f64>> = sets().map(|(_, _, _, set)| set.weight).collect();
+    let units: Vec<&str> = sets().map(|(_, _, _, set)| set.unit.as_str()).collect();
+    let rpes: Vec<Option<f32>> = sets().map(|(_, _, _, set)| set.rpe).collect();
+    tx.execute(
+        sqlx::query(
+            "insert into exercise_sets (workout_id, exercise_position, position, reps, weight, unit, rpe) \
+             select * from unnest($1::uuid[], $2::int4[], $3::int4[], $4::int4[], $5::float8[], $6::text[], $7::float4[])"
+        )
+            .bind(&workout_ids)
+            .bind(&exercise_positions)
+            .bind(&positions)
+            .bind(&reps)
+            .bind(&weights)
+            .bind(&units)
+            .bind(&rpes)
+    ).await?;
+
+    Ok(())
+}
+
+/// arbitrary key of the postgres advisory lock held while applying a migration
+const MIGRATIONS_LOCK_ID: i64 = 0x6669_7462_6f64;
+
+/// see `DataBase::schema_version`
+async fn schema_version(conn: &mut PgConnection) -> Result<Option<Version>, sqlx::Error> {
+    let (exists,): (bool,) = sqlx::query_as("select to_regclass('migrations') is not null")
+        .fetch_one(&mut *conn)
+        .await?;
+    if ! exists {
+        return Ok(None)
+    }
+    let rows: Vec<(String,)> = sqlx::query_as("select version from migrations")
+        .fetch_all(&mut *conn)
+        .await?;
+    Ok(migrate::current_version(rows.into_iter().map(|(version,)| version)))
+}
+
+async fn insert_workout(tx: &mut Transaction<'_, Postgres>, w: &Workout) -> Result<(), sqlx::Error> {
+    tx.execute(
+        sqlx::query(
+            "insert into workouts (user_id, workout_id, start_time, end_time, category, tags, note) \
+             values ($1, $2, $3, $4, $5, $6, $7)"
+        )
+            .bind(w.user_id)
+            .bind(w.workout_id)
+            .bind(w.start_time)
+            .bind(w.end_time)
+            .bind(w.category.map(|c| c.as_str()))
+            .bind(&w.tags[..])
+            .bind(w.note.as_deref())
+    ).await?;
+
+    insert_exercises(tx, w).await
+}
+
+/// see `DataBase::update_workout`
+async fn update_workout(tx: &mut Transaction<'_, Postgres>, w: &Workout) -> Result<bool, sqlx::Error> {
+    let result = tx.execute(
+        sqlx::query(
+            "update workouts set start_time = $3, end_time = $4, category = $5, tags = $6, note = $7 \
+             where user_id = $1 and workout_id = $2"
+        )
+            .bind(w.user_id)
+            .bind(w.workout_id)
+            .bind(w.start_time)
+            .bind(w.end_time)
+            .bind(w.category.map(|c| c.as_str()))
+            .bind(&w.tags[..])
+            .bind(w.note.as_deref())
+    ).await?;
+    if result.rows_affected() == 0 {
+        return Ok(false)
+    }
+
+    // sets are deleted with their exercises
+    tx.execute(
+        sqlx::query("delete from exercises where workout_id = $1").bind(w.workout_id)
+    ).await?;
+    insert_exercises(tx, w).await?;
+
+    Ok(true)
+}
+
+/// insert exercises and sets of `w`, numbering them in order from 0
+async fn insert_exercises(tx: &mut Transaction<'_, Postgres>, w: &Workout) -> Result<(), sqlx::Error> {
+    for (i, exercise) in w.exercises.iter().enumerate() {
+        tx.execute(
+            sqlx::query(
+                "insert into exercises (workout_id, position, name) values ($1, $2, $3)"
+            )
+                .bind(w.workout_id)
+                .bind(i as i32)
+                .bind(&exercise.name[..])
+        ).await?;
+
+        for (j, set) in exercise.sets.iter().enumerate() {
+            tx.execute(
+                sqlx::query(
+                    "insert into exercise_sets (workout_id, exercise_position, position, reps, weight, unit, rpe) \
+                     values ($1, $2, $3, $4, $5, $6, $7)"
+                )
+                    .bind(w.workout_id)
+                    .bind(i as i32)
+                    .bind(j as i32)
+                    .bind(set.reps as i32)
+                    .bind(set.weight)
+                    .bind(set.unit.as_str())
+                    .bind(set.rpe)
+            ).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// (user_id, workout_id, start_time, end_time, category, tags, note)
+type WorkoutRow = (Uuid, Uuid, DateTime<Utc>, DateTime<Utc>, Option<String>, Vec<String>, Option<String>);
+
+/// exercises are filled in separately, by `DataBase::attach_exercises`. an unrecognized
+/// category is treated as no category
+fn workout_from_row((user_id, workout_id, start_time, end_time, category, tags, note): WorkoutRow) -> Workout {
+    let category = category.and_then(|c| c.parse().ok());
+    Workout { user_id, workout_id, start_time, end_time, exercises: Vec::new(), category, tags, note }
+}
+
+/// (workout_id, exercise_position, reps, weight, unit, rpe)
+type ExerciseSetRow = (Uuid, i32, i32, Option<f64>, String, Option<f32>);
+
+type UserKeyRow = (Uuid, Uuid, Vec<u8>, DateTime<Utc>, Option<DateTime<Utc>>, Option<DateTime<Utc>>);
+
+fn user_key_from_row((key_id, user_id, key, created, expires, revoked): UserKeyRow) -> UserKey {
+    let key: PublicKey = key.try_into().expect("failed to convert Vec<u8> to PublicKey");
+    UserKey { key_id, user_id, key, created, expires, revoked }
+}
+
+/// whether `err` is a unique constraint violation (postgres error code 23505, or sqlite
+/// extended result code 2067 or 1555 for a unique or primary key constraint)
+pub fn is_unique_violation(err: &sqlx::Error) -> bool {
+    match err {
+        sqlx::Error::Database(e) => matches!(e.code().as_deref(), Some("23505") | Some("2067") | Some("1555")),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::OverlapPolicy;
+    use crate::cache::Cache;
+
+    fn user(email: &str) -> (User, UserKey) {
+        let user = User { user_id: Uuid::new_v4(), email: email.to_string(), key: rand::random(), created: Utc::now() };
+        let key = UserKey::new(user.user_id, user.key);
+        (user, key)
+    }
+
+    fn workout(user_id: Uuid, start_time: DateTime<Utc>) -> Workout {
+        Workout {
+            user_id,
+            workout_id: Uuid::new_v4(),
+            start_time,
+            end_time: start_time + chrono::Duration::hours(1),
+            exercises: Vec::new(),
+            category: None,
+            tags: Vec::new(),
+            note: None,
+        }
+    }
+
+    /// the postgres database in `DATABASE_URL`, or `None` to skip the test if there isn't one
+    async fn postgres() -> Option<DataBase> {
+        match std::env::var("DATABASE_URL") {
+            Ok(url) if url.starts_with("postgres") => Some(DataBase::new(&url).await.unwrap()),
+            _ => {
+                println!("skipping: DATABASE_URL must be a postgres url");
+                None
+            }
+        }
+    }
+
+    async fn delete_users(db: &DataBase, users: &[&User]) {
+        let user_ids: Vec<Uuid> = users.iter().map(|u| u.user_id).collect();
+        sqlx::query("delete from users where user_id = any($1)")
+            .bind(&user_ids)
+            .execute(db.pool())
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn postgres_bulk_insert_users_skips_saved_users() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = match postgres().await {
+                Some(db) => db,
+                None => return,
+            };
+            let new_user = || User { created: Utc::now().trunc_subsecs(6), ..user(&format!("{}@example.com", Uuid::new_v4())).0 };
+            let (a, b, c) = (new_user(), new_user(), new_user());
+
+            assert_eq!(db.bulk_insert_users(std::slice::from_ref(&a)).await.unwrap(), 1);
+            // a is skipped, as when seeding is run again
+            assert_eq!(db.bulk_insert_users(&[a.clone(), b.clone()]).await.unwrap(), 1);
+            assert_eq!(db.fetch_keys_for_user(&a.user_id).await.unwrap().len(), 1);
+            assert_eq!(db.fetch_keys_for_user(&b.user_id).await.unwrap().len(), 1);
+
+            // the same user_id with a different email is a conflict, so c isn't saved either
+            let a_changed = User { email: format!("{}@example.com", Uuid::new_v4()), ..a.clone() };
+            assert!(is_unique_violation(&db.bulk_insert_users(&[c.clone(), a_changed]).await.unwrap_err()));
+            // as is a different user_id with the same email
+            let b_email = User { user_id: Uuid::new_v4(), ..b.clone() };
+            assert!(is_unique_violation(&db.bulk_insert_users(&[c.clone(), b_email]).await.unwrap_err()));
+            assert!(db.fetch_keys_for_user(&c.user_id).await.unwrap().is_empty());
+
+            // a is skipped only if its key is the same too
+            let a_new_key = User { key: rand::random(), ..a.clone() };
+            assert!(is_unique_violation(&db.bulk_insert_users(&[c.clone(), a_new_key]).await.unwrap_err()));
+            // a row repeated within the batch is only inserted once, but the same user_id with
+            // another key is a conflict, rather than a second key for c
+            let c_other_key = User { key: rand::random(), ..c.clone() };
+            assert!(is_unique_violation(&db.bulk_insert_users(&[c.clone(), c_other_key]).await.unwrap_err()));
+            assert!(db.fetch_keys_for_user(&c.user_id).await.unwrap().is_empty());
+            assert_eq!(db.bulk_insert_users(&[c.clone(), c.clone()]).await.unwrap(), 1);
+            let c_keys = db.fetch_keys_for_user(&c.user_id).await.unwrap();
+            assert_eq!(c_keys.iter().map(|k| k.key).collect::<Vec<_>>(), vec![c.key]);
+
+            delete_users(&db, &[&a, &b, &c]).await;
+        });
+    }
+
+    #[test]
+    fn dedup_users_drops_repeats_and_rejects_conflicting_rows() {
+        let (a, _) = user("a@example.com");
+        let (b, _) = user("b@example.com");
+        let users = [a.clone(), b.clone(), a.clone()];
+        assert_eq!(dedup_users(&users).unwrap(), vec![&a, &b]);
+
+        let a_other_key = User { key: rand::random(), ..a.clone() };
+        assert!(is_unique_violation(&dedup_users(&[a.clone(), b.clone(), a_other_key]).unwrap_err()));
+        let a_other_email = User { email: "c@example.com".to_string(), ..a.clone() };
+        assert!(is_unique_violation(&dedup_users(&[a_other_email, a]).unwrap_err()));
+    }
+
+    #[test]
+    fn postgres_bulk_insert_workouts_skips_duplicates_and_fails_on_conflicts() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = match postgres().await {
+                Some(db) => db,
+                None => return,
+            };
+            let (u, k) = user(&format!("{}@example.com", Uuid::new_v4()));
+            db.insert_user(&u, &k).await.unwrap();
+
+            let t = (Utc::now() - chrono::Duration::days(1)).trunc_subsecs(0);
+            let squat = Exercise {
+                name: "squat".to_string(),
+                sets: vec![ExerciseSet { reps: 5, weight: Some(100.0), unit: Default::default(), rpe: Some(8.0) }],
+            };
+            let w0 = Workout { exercises: vec![squat], ..workout(u.user_id, t) };
+            let w1 = workout(u.user_id, t + chrono::Duration::hours(2));
+            let w2 = workout(u.user_id, t + chrono::Duration::hours(4));
+
+            assert_eq!(db.bulk_insert_workouts(std::slice::from_ref(&w0)).await.unwrap(), vec![w0.workout_id]);
+            // w0 is skipped, as when a request is sent again
+            assert_eq!(db.bulk_insert_workouts(&[w0.clone(), w1.clone()]).await.unwrap(), vec![w1.workout_id]);
+
+            // the same workout_id at a different start_time is a conflict, so w2 isn't saved either
+            let w0_moved = Workout { start_time: t + chrono::Duration::hours(6), end_time: t + chrono::Duration::hours(7), ..w0.clone() };
+            assert!(is_unique_violation(&db.bulk_insert_workouts(&[w2.clone(), w0_moved]).await.unwrap_err()));
+            // as is a different workout at the same start_time
+            let w0_start = workout(u.user_id, t);
+            assert!(is_unique_violation(&db.bulk_insert_workouts(&[w2.clone(), w0_start]).await.unwrap_err()));
+
+            let mut saved = db.fetch_user_workouts(&u.user_id).await.unwrap();
+            saved.sort_by_key(|w| w.start_time);
+            assert_eq!(saved, vec![w0, w1]);
+
+            delete_users(&db, &[&u]).await;
+        });
+    }
+
+    #[test]
+    fn memory_storage_enforces_schema_constraints() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = MemoryStorage::new();
+            let (u, k) = user("a@example.com");
+            db.insert_user(&u, &k).await.unwrap();
+            let (same_email, same_email_key) = user("a@example.com");
+            assert!(is_unique_violation(&db.insert_user(&same_email, &same_email_key).await.unwrap_err()));
+            assert_eq!(db.fetch_user_keys().await.unwrap(), vec![k.clone()]);
+
+            let t = Utc::now() - chrono::Duration::days(1);
+            let w0 = workout(u.user_id, t);
+            let w1 = workout(u.user_id, t + chrono::Duration::hours(2));
+            db.save_new_workouts(std::slice::from_ref(&w0), &[]).await.unwrap();
+
+            // same start time as w0, so w1 isn't saved either
+            let dup = workout(u.user_id, t);
+            let err = db.save_new_workouts(&[w1.clone(), dup], &[]).await.unwrap_err();
+            assert!(is_unique_violation(&err));
+            // merging into a missing workout
+            let missing = workout(u.user_id, t + chrono::Duration::hours(4));
+            let err = db.save_new_workouts(std::slice::from_ref(&w1), std::slice::from_ref(&missing)).await.unwrap_err();
+            assert!(matches!(err, sqlx::Error::RowNotFound));
+            assert_eq!(db.fetch_user_workouts(&u.user_id).await.unwrap(), vec![w0.clone()]);
+            // a workout merged into before the failing one is put back as it was
+            let w0_merged = Workout { end_time: w0.end_time + chrono::Duration::minutes(30), ..w0.clone() };
+            let err = db.save_new_workouts(std::slice::from_ref(&w1), &[w0_merged, missing.clone()]).await.unwrap_err();
+            assert!(matches!(err, sqlx::Error::RowNotFound));
+            assert_eq!(db.fetch_user_workouts(&u.user_id).await.unwrap(), vec![w0.clone()]);
+
+            let stranger = workout(Uuid::new_v4(), t);
+            assert!(db.save_new_workouts(&[stranger], &[]).await.is_err());
+
+            let moved = Workout { start_time: t - chrono::Duration::hours(2), ..w0.clone() };
+            assert!(db.update_workout(&moved).await.unwrap());
+            assert!(! db.update_workout(&missing).await.unwrap());
+            assert_eq!(db.fetch_workout(&u.user_id, &w0.workout_id).await.unwrap(), Some(moved));
+            assert_eq!(db.fetch_recently_active_user_workouts().await.unwrap().len(), 1);
+            assert!(! db.delete_workout(&Uuid::new_v4(), &w0.workout_id).await.unwrap());
+            assert!(db.delete_workout(&u.user_id, &w0.workout_id).await.unwrap());
+            assert_eq!(db.fetch_workout(&u.user_id, &w0.workout_id).await.unwrap(), None);
+
+            assert_eq!(db.revoke_user_key(&u.user_id, &k.key_id, Utc::now()).await.unwrap(), KeyRevocation::OnlyActiveKey);
+            let k2 = UserKey::new(u.user_id, rand::random());
+            db.insert_user_key(&k2).await.unwrap();
+            assert_eq!(db.revoke_user_key(&u.user_id, &k.key_id, Utc::now()).await.unwrap(), KeyRevocation::Revoked);
+            assert_eq!(db.revoke_user_key(&u.user_id, &k.key_id, Utc::now()).await.unwrap(), KeyRevocation::NotFound);
+            assert_eq!(db.revoke_user_key(&u.user_id, &k2.key_id, Utc::now()).await.unwrap(), KeyRevocation::OnlyActiveKey);
+            assert_eq!(db.fetch_user_keys().await.unwrap(), vec![k2]);
+            assert_eq!(db.fetch_keys_for_user(&u.user_id).await.unwrap().len(), 2);
+            // a revoked key can't be added again
+            let readded = UserKey::new(u.user_id, k.key);
+            assert!(is_unique_violation(&db.insert_user_key(&readded).await.unwrap_err()));
+
+            assert!(db.update_user_time_zone(&u.user_id, chrono_tz::America::New_York).await.unwrap());
+            assert!(! db.update_user_time_zone(&Uuid::new_v4(), chrono_tz::America::New_York).await.unwrap());
+            assert_eq!(db.fetch_user_time_zones().await.unwrap(), vec![(u.user_id, chrono_tz::America::New_York)]);
+        });
+    }
+
+    #[test]
+    fn unavailable_storage_leaves_cache_unchanged() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = MemoryStorage::new();
+            let cache = Cache::default();
+            let (u, k) = user("b@example.com");
+            db.insert_user(&u, &k).await.unwrap();
+            let items = vec![workout(u.user_id, Utc::now() - chrono::Duration::days(1))];
+            let write = |db: MemoryStorage| {
+                cache.write_new_workouts(u.user_id, &items, OverlapPolicy::Accept, move |inserted, merged| async move {
+                    db.save_new_workouts(&inserted, &merged).await
+                })
+            };
+
+            db.set_unavailable(true);
+            assert!(matches!(write(db.clone()).await, Err(sqlx::Error::PoolClosed)));
+            assert!(! cache.workouts_exist(&u.user_id));
+
+            db.set_unavailable(false);
+            let written = write(db.clone()).await.unwrap();
+            assert_eq!(written.inserted, items);
+            assert!(cache.workouts_exist(&u.user_id));
+            assert_eq!(db.fetch_user_workouts(&u.user_id).await.unwrap(), items);
+        });
+    }
+}
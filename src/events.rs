@@ -0,0 +1,649 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::convert::Infallible;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use hashbrown::HashMap;
+use tokio::sync::{Notify, mpsc, oneshot};
+use futures::{SinkExt, Stream, StreamExt};
+use chrono::Utc;
+use warp::ws::{Message, WebSocket};
+use uuid::Uuid;
+use crate::api::{Event, EventStreamHandshake};
+use crate::auth::RequestTarget;
+use crate::cache::Cache;
+
+/// max events kept per user between polls. once full, the oldest event is dropped
+pub const MAX_QUEUED_EVENTS: usize = 100;
+
+/// max events kept per user for replay to reconnecting sse clients (see `EventQueues::subscribe_after`)
+pub const MAX_LOGGED_EVENTS: usize = 256;
+
+/// how long a user's events are still queued and logged after the user's last long-poll or
+/// open stream, so a client that reconnects within it misses nothing. after that, the user's
+/// queue and log are dropped
+pub const SUBSCRIBER_IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// id assigned to each event as it is pushed. ids increase monotonically (they are seeded from
+/// the startup time, in microseconds), but the log events are replayed from is only kept in
+/// memory, so a client reconnecting after a restart misses the events from before it
+pub type EventId = u64;
+
+/// max events waiting to be sent on a single event stream. a stream that falls this far
+/// behind is closed (`StreamClosed::Lagged`)
+pub const MAX_PENDING_STREAM_EVENTS: usize = 64;
+
+/// how often the server pings an event stream websocket. a client that hasn't sent anything
+/// (e.g. a pong) for two intervals is disconnected
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// how long a client has to send its `EventStreamHandshake` after connecting
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_POLICY_VIOLATION: u16 = 1008;
+const CLOSE_TRY_AGAIN_LATER: u16 = 1013;
+
+/// why the server closed an event stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamClosed {
+    /// the client wasn't reading events fast enough
+    Lagged,
+    /// the key the stream was authenticated with was revoked or expired
+    KeyRevoked,
+}
+
+impl StreamClosed {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StreamClosed::Lagged => "lagged",
+            StreamClosed::KeyRevoked => "key revoked",
+        }
+    }
+
+    fn close_message(&self) -> Message {
+        match self {
+            StreamClosed::Lagged => Message::close_with(CLOSE_TRY_AGAIN_LATER, self.as_str()),
+            StreamClosed::KeyRevoked => Message::close_with(CLOSE_POLICY_VIOLATION, self.as_str()),
+        }
+    }
+}
+
+/// sending half of an event stream, kept in `EventQueues`
+struct StreamSender {
+    key_id: Uuid,
+    events: mpsc::Sender<(EventId, Event)>,
+    closed: oneshot::Sender<StreamClosed>,
+}
+
+/// receiving half of an event stream, returned by `EventQueues::subscribe`. `closed` fires
+/// if the server closes the stream.
+pub struct EventStream {
+    pub events: mpsc::Receiver<(EventId, Event)>,
+    pub closed: oneshot::Receiver<StreamClosed>,
+}
+
+struct UserEvents {
+    /// set once the user has long-polled; events are only queued after that
+    polling: bool,
+    queue: VecDeque<Event>,
+    notify: Arc<Notify>,
+    streams: Vec<StreamSender>,
+    /// most recent events, for replay
+    log: VecDeque<(EventId, Event)>,
+    /// last long-poll or stream opened
+    last_subscribed: Instant,
+}
+
+impl UserEvents {
+    fn new() -> Self {
+        Self {
+            polling: false,
+            queue: VecDeque::new(),
+            notify: Default::default(),
+            streams: Vec::new(),
+            log: VecDeque::new(),
+            last_subscribed: Instant::now(),
+        }
+    }
+
+    fn is_idle(&self, now: Instant) -> bool {
+        self.streams.iter().all(|s| s.events.is_closed())
+            && now.saturating_duration_since(self.last_subscribed) > SUBSCRIBER_IDLE_TIMEOUT
+    }
+}
+
+/// per-user queues of events waiting to be picked up by a subscriber, plus any open event
+/// streams and a bounded log of recent events.
+///
+/// events are only kept for users that have long-polled or opened a stream, until they have
+/// been idle for `SUBSCRIBER_IDLE_TIMEOUT`, so memory grows with the number of subscribers
+/// rather than the number of users.
+#[derive(Clone)]
+pub struct EventQueues {
+    users: Arc<Mutex<HashMap<Uuid, UserEvents>>>,
+    next_id: Arc<AtomicU64>,
+    /// when idle users were last evicted
+    last_eviction: Arc<Mutex<Instant>>,
+}
+
+impl Default for EventQueues {
+    fn default() -> Self {
+        Self {
+            users: Default::default(),
+            next_id: Arc::new(AtomicU64::new(Utc::now().timestamp_micros() as u64)),
+            last_eviction: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+}
+
+impl EventQueues {
+    /// log event, send it to the user's open streams, and queue it for long-polling, waking any
+    /// pending subscriber. returns `false` if the event wasn't delivered: it is either only
+    /// logged (the user's subscriber disconnected recently) or dropped (the user has no
+    /// subscribers)
+    pub fn push(&self, user_id: Uuid, event: Event) -> bool {
+        let now = Instant::now();
+        let mut lock = self.users.lock().unwrap();
+
+        {
+            let mut last_eviction = self.last_eviction.lock().unwrap();
+            if now.saturating_duration_since(*last_eviction) > SUBSCRIBER_IDLE_TIMEOUT {
+                evict_idle(&mut lock, now);
+                *last_eviction = now;
+            }
+        }
+
+        let user_events = match lock.get_mut(&user_id) {
+            Some(user_events) if ! user_events.is_idle(now) => user_events,
+            _ => return false,
+        };
+
+        // assigned while holding the lock, so a user's events are logged in id order
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        if user_events.log.len() >= MAX_LOGGED_EVENTS {
+            user_events.log.pop_front();
+        }
+        user_events.log.push_back((id, event.clone()));
+
+        let mut delivered = false;
+
+        for stream in std::mem::take(&mut user_events.streams) {
+            match stream.events.try_send((id, event.clone())) {
+                Ok(()) => {
+                    user_events.streams.push(stream);
+                    delivered = true;
+                }
+
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    let _ = stream.closed.send(StreamClosed::Lagged);
+                }
+
+                Err(mpsc::error::TrySendError::Closed(_)) => {}
+            }
+        }
+
+        if user_events.polling {
+            if user_events.queue.len() >= MAX_QUEUED_EVENTS {
+                user_events.queue.pop_front();
+            }
+            user_events.queue.push_back(event);
+            user_events.notify.notify_waiters();
+            delivered = true;
+        }
+
+        delivered
+    }
+
+    /// take all queued events for user, registering the user as a subscriber if needed
+    pub fn drain(&self, user_id: Uuid) -> Vec<Event> {
+        let mut lock = self.users.lock().unwrap();
+        let user_events = lock.entry(user_id).or_insert_with(UserEvents::new);
+        user_events.polling = true;
+        user_events.last_subscribed = Instant::now();
+        user_events.queue.drain(..).collect()
+    }
+
+    /// open a stream of the user's events, authenticated with `key_id`
+    pub fn subscribe(&self, user_id: Uuid, key_id: Uuid) -> EventStream {
+        self.subscribe_after(user_id, key_id, None).1
+    }
+
+    /// like `subscribe`, also returning the logged events with ids greater than
+    /// `last_event_id`, if given. no event is both replayed and sent on the stream, or missed
+    /// between the two.
+    ///
+    /// only the last `MAX_LOGGED_EVENTS` events are logged, and only while the user has been
+    /// subscribed within `SUBSCRIBER_IDLE_TIMEOUT`, so older events may be missing from the
+    /// replay.
+    pub fn subscribe_after(
+        &self,
+        user_id: Uuid,
+        key_id: Uuid,
+        last_event_id: Option<EventId>,
+    ) -> (Vec<(EventId, Event)>, EventStream) {
+        let (events_tx, events_rx) = mpsc::channel(MAX_PENDING_STREAM_EVENTS);
+        let (closed_tx, closed_rx) = oneshot::channel();
+        let mut lock = self.users.lock().unwrap();
+        let user_events = lock.entry(user_id).or_insert_with(UserEvents::new);
+        user_events.last_subscribed = Instant::now();
+        let replay = match last_event_id {
+            Some(last_event_id) => {
+                user_events.log.iter()
+                    .filter(|(id, _)| *id > last_event_id)
+                    .cloned()
+                    .collect()
+            }
+
+            None => Vec::new(),
+        };
+        user_events.streams.retain(|s| ! s.events.is_closed());
+        user_events.streams.push(StreamSender { key_id, events: events_tx, closed: closed_tx });
+        (replay, EventStream { events: events_rx, closed: closed_rx })
+    }
+
+    /// close the user's streams that were authenticated with `key_id`, returning how many were
+    /// closed
+    pub fn close_streams(&self, user_id: Uuid, key_id: Uuid, reason: StreamClosed) -> usize {
+        let mut lock = self.users.lock().unwrap();
+        let user_events = match lock.get_mut(&user_id) {
+            Some(user_events) => user_events,
+            None => return 0,
+        };
+        let (closed, open) = std::mem::take(&mut user_events.streams)
+            .into_iter()
+            .partition::<Vec<_>, _>(|s| s.key_id == key_id);
+        user_events.streams = open;
+        let n = closed.len();
+        for stream in closed {
+            let _ = stream.closed.send(reason);
+        }
+        n
+    }
+
+    /// number of open streams for user
+    pub fn n_streams(&self, user_id: &Uuid) -> usize {
+        self.users.lock().unwrap()
+            .get(user_id)
+            .map(|x| x.streams.iter().filter(|s| ! s.events.is_closed()).count())
+            .unwrap_or(0)
+    }
+
+    /// wait until at least one event is queued for the user or `timeout` expires, then take
+    /// all queued events. returns an empty list on timeout.
+    pub async fn wait(&self, user_id: Uuid, timeout: Duration) -> Vec<Event> {
+        let notify = {
+            let mut lock = self.users.lock().unwrap();
+            let user_events = lock.entry(user_id).or_insert_with(UserEvents::new);
+            user_events.last_subscribed = Instant::now();
+            if ! user_events.queue.is_empty() {
+                return user_events.queue.drain(..).collect()
+            }
+            user_events.notify.clone()
+        };
+
+        // a push between releasing the lock and awaiting here would be missed, so register
+        // interest first and check the queue once more before waiting
+        let notified = notify.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+
+        let queued = self.drain(user_id);
+        if ! queued.is_empty() {
+            return queued
+        }
+
+        let _ = tokio::time::timeout(timeout, notified).await;
+
+        self.drain(user_id)
+    }
+}
+
+/// drop the queues and logs of users that have been idle for `SUBSCRIBER_IDLE_TIMEOUT`,
+/// returning how many were dropped
+fn evict_idle(users: &mut HashMap<Uuid, UserEvents>, now: Instant) -> usize {
+    let n = users.len();
+    users.retain(|_, user_events| ! user_events.is_idle(now));
+    n - users.len()
+}
+
+/// serve an `events/ws` websocket connection: wait for a signed `EventStreamHandshake`, then
+/// send each of the user's events as a json text message until the client disconnects, stops
+/// answering pings, falls too far behind, or the key it authenticated with is revoked.
+///
+/// `path` and `host` are from the websocket upgrade request, and must match what the
+/// handshake was signed over.
+pub async fn serve_event_stream(cache: Cache, socket: WebSocket, path: String, host: String) {
+    let (mut tx, mut rx) = socket.split();
+
+    let handshake = match tokio::time::timeout(HANDSHAKE_TIMEOUT, rx.next()).await {
+        Ok(Some(Ok(msg))) if msg.is_text() => {
+            serde_json::from_slice::<EventStreamHandshake>(msg.as_bytes())
+                .map_err(|e| format!("invalid handshake: {}", e))
+        }
+
+        _ => Err("expected handshake".to_string()),
+    };
+
+    let target = RequestTarget { method: "GET", path: &path, host: &host };
+    let verified = handshake.and_then(|handshake| {
+        cache.verify_event_stream_handshake(&handshake, &target)
+            .map_err(|e| format!("auth error: {:?}", e))
+    });
+
+    let (user_id, key_id) = match verified {
+        Ok((req, key_id)) => (req.user_id, key_id),
+        Err(e) => {
+            let _ = tx.send(Message::close_with(CLOSE_POLICY_VIOLATION, close_reason(e))).await;
+            return
+        }
+    };
+
+    let EventStream { events: mut events_rx, closed: mut closed_rx } = cache.events().subscribe(user_id, key_id);
+
+    let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+    heartbeat.tick().await; // first tick completes immediately
+    let mut last_heard = Instant::now();
+
+    let close_msg = loop {
+        tokio::select! {
+            closed = &mut closed_rx => {
+                match closed {
+                    Ok(reason) => break reason.close_message(),
+                    Err(_) => return,
+                }
+            }
+
+            event = events_rx.recv() => {
+                match event {
+                    Some((_, event)) => {
+                        let json = serde_json::to_string(&event).unwrap();
+                        if tx.send(Message::text(json)).await.is_err() {
+                            return
+                        }
+                    }
+
+                    None => return,
+                }
+            }
+
+            msg = rx.next() => {
+                match msg {
+                    Some(Ok(msg)) if msg.is_close() => return,
+                    Some(Ok(_)) => last_heard = Instant::now(),
+                    _ => return,
+                }
+            }
+
+            _ = heartbeat.tick() => {
+                if last_heard.elapsed() > HEARTBEAT_INTERVAL * 2 {
+                    break Message::close_with(CLOSE_NORMAL, "heartbeat timeout")
+                }
+                // catches keys that expired while the stream was open
+                if ! cache.key_is_active(&user_id, &key_id) {
+                    break StreamClosed::KeyRevoked.close_message()
+                }
+                if tx.send(Message::ping(Vec::new())).await.is_err() {
+                    return
+                }
+            }
+        }
+    };
+
+    let _ = tx.send(close_msg).await;
+}
+
+/// sse stream of `replay` followed by the events sent on `stream`. each event's `EventId` is
+/// sent as the sse `id`, so a client that reconnects with a `Last-Event-ID` header receives the
+/// events it missed (see `EventQueues::subscribe_after`).
+///
+/// if the server closes the stream, a final `close` event is sent with the reason as data. like
+/// `serve_event_stream`, `key_active` is checked every `HEARTBEAT_INTERVAL`, and the stream is
+/// closed once it returns `false`, e.g. because the key the stream was opened with expired
+pub fn sse_events<F>(
+    replay: Vec<(EventId, Event)>,
+    stream: EventStream,
+    key_active: F,
+) -> impl Stream<Item = Result<warp::sse::Event, Infallible>>
+    where F: FnMut() -> bool + Send + 'static
+{
+    sse_events_checked_every(replay, stream, key_active, HEARTBEAT_INTERVAL)
+}
+
+fn sse_events_checked_every<F>(
+    replay: Vec<(EventId, Event)>,
+    stream: EventStream,
+    key_active: F,
+    check_interval: Duration,
+) -> impl Stream<Item = Result<warp::sse::Event, Infallible>>
+    where F: FnMut() -> bool + Send + 'static
+{
+    let key_check = tokio::time::interval_at(tokio::time::Instant::now() + check_interval, check_interval);
+    let live = futures::stream::unfold(Some((stream, key_check, key_active)), |state| async move {
+        let (EventStream { mut events, mut closed }, mut key_check, mut key_active) = state?;
+        let close = |reason: StreamClosed| warp::sse::Event::default().event("close").data(reason.as_str());
+        loop {
+            // a closed stream ends right away, without sending what is still buffered. a lagged
+            // client gets the buffered events replayed when it reconnects
+            tokio::select! {
+                biased;
+
+                closed = &mut closed => {
+                    let reason = closed.ok()?;
+                    return Some((close(reason), None))
+                }
+
+                event = events.recv() => {
+                    let (id, event) = event?;
+                    return Some((sse_event(id, &event), Some((EventStream { events, closed }, key_check, key_active))))
+                }
+
+                _ = key_check.tick() => {
+                    // catches keys that expired while the stream was open
+                    if ! key_active() {
+                        return Some((close(StreamClosed::KeyRevoked), None))
+                    }
+                }
+            }
+        }
+    });
+
+    futures::stream::iter(replay)
+        .map(|(id, event)| sse_event(id, &event))
+        .chain(live)
+        .map(Ok)
+}
+
+fn sse_event(id: EventId, event: &Event) -> warp::sse::Event {
+    warp::sse::Event::default()
+        .id(id.to_string())
+        .data(serde_json::to_string(event).unwrap())
+}
+
+/// websocket close reasons are limited to 123 bytes
+fn close_reason(mut reason: String) -> String {
+    if reason.len() > 123 {
+        let mut n = 123;
+        while ! reason.is_char_boundary(n) {
+            n -= 1;
+        }
+        reason.truncate(n);
+    }
+    reason
+}
+
+#[allow(unused)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wait_returns_on_new_event_or_timeout() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let events = EventQueues::default();
+            let user_id = Uuid::new_v4();
+            let message = || Event::DopamineShot { message: "you can do it!".to_string() };
+
+            // not subscribed yet: event is dropped
+            assert!(!events.push(user_id, message()));
+
+            let start = std::time::Instant::now();
+            assert!(events.wait(user_id, Duration::from_millis(50)).await.is_empty());
+            assert!(start.elapsed() >= Duration::from_millis(50));
+
+            let waiter = {
+                let events = events.clone();
+                tokio::spawn(async move { events.wait(user_id, Duration::from_secs(10)).await })
+            };
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            assert!(events.push(user_id, message()));
+            let start = std::time::Instant::now();
+            let received = waiter.await.unwrap();
+            assert_eq!(received.len(), 1);
+            assert!(start.elapsed() < Duration::from_secs(5));
+
+            // events queued between polls are returned right away
+            assert!(events.push(user_id, message()));
+            assert!(events.push(user_id, message()));
+            assert_eq!(events.wait(user_id, Duration::from_secs(10)).await.len(), 2);
+
+            for _ in 0..(MAX_QUEUED_EVENTS + 10) {
+                events.push(user_id, message());
+            }
+            assert_eq!(events.drain(user_id).len(), MAX_QUEUED_EVENTS);
+        });
+    }
+
+    #[test]
+    fn streams_receive_events_and_are_closed_when_lagged_or_revoked() {
+        let events = EventQueues::default();
+        let user_id = Uuid::new_v4();
+        let key_a = Uuid::new_v4();
+        let key_b = Uuid::new_v4();
+        let message = || Event::DopamineShot { message: "you can do it!".to_string() };
+
+        let mut a = events.subscribe(user_id, key_a);
+        let mut b = events.subscribe(user_id, key_b);
+        assert_eq!(events.n_streams(&user_id), 2);
+
+        // streams don't register the user for long-polling
+        assert!(events.push(user_id, message()));
+        assert!(a.events.try_recv().is_ok());
+        assert!(b.events.try_recv().is_ok());
+        assert!(events.drain(user_id).is_empty());
+
+        // b stops reading: once its buffer is full it is closed, a is unaffected
+        for _ in 0..MAX_PENDING_STREAM_EVENTS {
+            events.push(user_id, message());
+            assert!(a.events.try_recv().is_ok());
+        }
+        assert_eq!(events.n_streams(&user_id), 2);
+        events.push(user_id, message());
+        assert_eq!(b.closed.try_recv(), Ok(StreamClosed::Lagged));
+        assert_eq!(events.n_streams(&user_id), 1);
+
+        assert_eq!(events.close_streams(user_id, key_b, StreamClosed::KeyRevoked), 0);
+        assert_eq!(events.close_streams(user_id, key_a, StreamClosed::KeyRevoked), 1);
+        assert_eq!(a.closed.try_recv(), Ok(StreamClosed::KeyRevoked));
+        assert_eq!(events.n_streams(&user_id), 0);
+
+        // dropped receivers are pruned
+        let c = events.subscribe(user_id, key_a);
+        drop(c);
+        assert_eq!(events.n_streams(&user_id), 0);
+
+        // an sse stream is closed once its key is no longer active, e.g. because it expired
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let key_active = Arc::new(std::sync::atomic::AtomicBool::new(true));
+            let is_active = {
+                let key_active = key_active.clone();
+                move || key_active.load(Ordering::SeqCst)
+            };
+            let sse = sse_events_checked_every(Vec::new(), events.subscribe(user_id, key_a), is_active, Duration::from_millis(10))
+                .map(|x| x.unwrap().to_string());
+            tokio::pin!(sse);
+
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            assert!(events.push(user_id, message()));
+            assert!(sse.next().await.unwrap().contains("you can do it!"));
+
+            key_active.store(false, Ordering::SeqCst);
+            assert_eq!(sse.next().await.unwrap(), "event:close\ndata:key revoked\n\n");
+            assert!(sse.next().await.is_none());
+        });
+    }
+
+    #[test]
+    fn sse_events_replay_missed_events_by_id() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let events = EventQueues::default();
+            let user_id = Uuid::new_v4();
+            let key_id = Uuid::new_v4();
+            let message = |i: usize| Event::DopamineShot { message: format!("rep {}", i) };
+
+            // events are logged while a recently disconnected client may reconnect
+            drop(events.subscribe(user_id, key_id));
+            for i in 0..3 {
+                assert!(!events.push(user_id, message(i)));
+            }
+            let (replay, _) = events.subscribe_after(user_id, key_id, Some(0));
+            let ids: Vec<EventId> = replay.iter().map(|(id, _)| *id).collect();
+            assert_eq!(ids.len(), 3);
+            assert!(ids.windows(2).all(|w| w[0] < w[1]));
+
+            // reconnecting after the first event replays the other two
+            let (replay, stream) = events.subscribe_after(user_id, key_id, Some(ids[0]));
+            assert_eq!(replay.iter().map(|(id, _)| *id).collect::<Vec<_>>(), &ids[1..]);
+            let (no_replay, _) = events.subscribe_after(user_id, key_id, None);
+            assert!(no_replay.is_empty());
+
+            assert!(events.push(user_id, message(3)));
+
+            let sse = sse_events(replay, stream, || true).map(|x| x.unwrap().to_string());
+            tokio::pin!(sse);
+            let first = sse.next().await.unwrap();
+            assert!(first.contains(&format!("\nid:{}\n", ids[1])));
+            assert!(first.contains("rep 1"));
+            assert!(sse.next().await.unwrap().contains("rep 2"));
+            assert!(sse.next().await.unwrap().contains("rep 3"));
+
+            events.close_streams(user_id, key_id, StreamClosed::KeyRevoked);
+            assert_eq!(sse.next().await.unwrap(), "event:close\ndata:key revoked\n\n");
+            assert!(sse.next().await.is_none());
+
+            for i in 0..(MAX_LOGGED_EVENTS + 10) {
+                events.push(user_id, message(i));
+            }
+            assert_eq!(events.subscribe_after(user_id, key_id, Some(0)).0.len(), MAX_LOGGED_EVENTS);
+        });
+    }
+
+    #[test]
+    fn events_are_only_kept_for_recent_subscribers() {
+        let events = EventQueues::default();
+        let user_id = Uuid::new_v4();
+        let key_id = Uuid::new_v4();
+        let message = || Event::DopamineShot { message: "you can do it!".to_string() };
+
+        // never subscribed: nothing is allocated for the user
+        for _ in 0..10 {
+            assert!(! events.push(user_id, message()));
+        }
+        assert!(events.users.lock().unwrap().is_empty());
+
+        let stream = events.subscribe(user_id, key_id);
+        let later = Instant::now() + SUBSCRIBER_IDLE_TIMEOUT + Duration::from_secs(1);
+        // an open stream keeps the user from being evicted, however long ago it was opened
+        assert_eq!(evict_idle(&mut events.users.lock().unwrap(), later), 0);
+
+        drop(stream);
+        events.push(user_id, message());
+        assert_eq!(events.users.lock().unwrap()[&user_id].log.len(), 1);
+        assert_eq!(evict_idle(&mut events.users.lock().unwrap(), later), 1);
+        assert!(! events.push(user_id, message()));
+        assert!(events.users.lock().unwrap().is_empty());
+    }
+}
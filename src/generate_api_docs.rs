@@ -3,6 +3,8 @@ use serde::Serialize;
 use chrono::prelude::*;
 use uuid::Uuid;
 use fitbod::*;
+use fitbod::query::{MatchMode, Order};
+use fitbod::stats::{BucketSize, WorkoutBucket, WorkoutStats, MAX_AGGREGATE_BUCKETS};
 
 const API_DOCS_TEMPLATE: &str = include_str!("../static/api-documentation.tera.md");
 const OUTPUT_PATH: &str = "./README.md";
@@ -10,6 +12,7 @@ const SCHEMA_SQL: &str = include_str!("../sql/schema-postgresql.sql");
 
 const HELP_MAIN: &str = include_str!("../static/fitbod-server-main-help.txt");
 const HELP_RUN: &str = include_str!("../static/fitbod-server-run-help.txt");
+const HELP_MIGRATE: &str = include_str!("../static/fitbod-server-migrate-help.txt");
 const HELP_LIST_REQ: &str = include_str!("../static/fitbod-server-list-workouts-request-help.txt");
 const HELP_NEW_REQ: &str = include_str!("../static/fitbod-server-new-workouts-request-help.txt");
 const HELP_LIST_REQ_HTTP: &str = include_str!("../static/fitbod-server-list-workouts-request-http.txt");
@@ -27,6 +30,7 @@ fn main() -> Result<(), io::Error> {
     ctx.insert("schema_sql", SCHEMA_SQL);
     ctx.insert("fitbod_server_main_help", HELP_MAIN);
     ctx.insert("fitbod_server_run_help", HELP_RUN);
+    ctx.insert("fitbod_server_migrate_help", HELP_MIGRATE);
     ctx.insert("fitbod_server_list_req_help", HELP_LIST_REQ);
     ctx.insert("fitbod_server_new_req_help", HELP_NEW_REQ);
     ctx.insert("fitbod_server_list_req_http", HELP_LIST_REQ_HTTP);
@@ -39,34 +43,122 @@ fn main() -> Result<(), io::Error> {
     let start_time = Utc::now();
     let end_time = start_time + chrono::Duration::minutes(55);
 
-    let new_workout_req = fitbod::api::NewWorkoutsRequest {
+    let workout = Workout {
         user_id,
-        items: vec![
-            Workout {
-                user_id,
-                workout_id,
-                start_time,
-                end_time,
-            }
+        workout_id,
+        start_time,
+        end_time,
+        exercises: vec![
+            Exercise {
+                name: "back squat".to_string(),
+                sets: vec![
+                    ExerciseSet { reps: 5, weight: Some(100.0), unit: WeightUnit::Kg, rpe: Some(7.5) },
+                    ExerciseSet { reps: 5, weight: Some(100.0), unit: WeightUnit::Kg, rpe: Some(8.5) },
+                ],
+            },
+            Exercise {
+                name: "pull-up".to_string(),
+                sets: vec![
+                    ExerciseSet { reps: 8, weight: None, unit: WeightUnit::Kg, rpe: None },
+                ],
+            },
         ],
+        category: Some(Category::Strength),
+        tags: vec!["leg day".to_string()],
+        note: Some("squats felt heavy".to_string()),
+    };
+
+    let new_workout_req = fitbod::api::NewWorkoutsRequest {
+        user_id,
+        items: vec![workout.clone()],
+        overlap: fitbod::api::OverlapPolicy::Accept,
     };
 
     let new_workout_req_json = serde_json::to_string_pretty(&[new_workout_req]).unwrap();
     ctx.insert("new_workout_request_json", &new_workout_req_json);
 
-    let new_workout_success_resp = NewWorkoutResponse::Success { workout_id };
+    let get_workout_req_json = serde_json::to_string_pretty(&GetWorkoutRequest { user_id, workout_id }).unwrap();
+    ctx.insert("get_workout_req_json", &get_workout_req_json);
+    let get_workout_resp_json = serde_json::to_string_pretty(&WorkoutDetail::from(workout.clone())).unwrap();
+    ctx.insert("get_workout_resp_json", &get_workout_resp_json);
+
+    let update_workout_req = UpdateWorkoutRequest {
+        user_id,
+        workout_id,
+        start_time: Some(start_time - chrono::Duration::minutes(10)),
+        end_time: None,
+        category: None,
+        tags: None,
+        note: Some("started early".to_string()),
+    };
+    let update_workout_req_json = serde_json::to_string_pretty(&update_workout_req).unwrap();
+    ctx.insert("update_workout_req_json", &update_workout_req_json);
+    let updated_workout = update_workout_req.apply(&Workout { exercises: Vec::new(), ..workout.clone() });
+    let update_workout_resp_json = serde_json::to_string_pretty(&updated_workout).unwrap();
+    ctx.insert("update_workout_resp_json", &update_workout_resp_json);
+    let delete_workout_req_json = serde_json::to_string_pretty(&DeleteWorkoutRequest { user_id, workout_id }).unwrap();
+    ctx.insert("delete_workout_req_json", &delete_workout_req_json);
+
+    let new_workout_success_resp = NewWorkoutResponse::Success { workout_id, overlaps: Vec::new() };
     let new_workout_success_resp_json = serde_json::to_string_pretty(&[new_workout_success_resp]).unwrap();
     ctx.insert("new_workout_success_resp_json", &new_workout_success_resp_json);
 
-    let new_workout_err_resp = NewWorkoutResponse::Error { workout_id, err_code: 123, msg: "short message describing error".to_string() };
+    let new_workout_overlap_resp = NewWorkoutResponse::Success { workout_id, overlaps: vec![Uuid::new_v4()] };
+    let new_workout_overlap_resp_json = serde_json::to_string_pretty(&new_workout_overlap_resp).unwrap();
+    ctx.insert("new_workout_overlap_resp_json", &new_workout_overlap_resp_json);
+
+    let new_workout_merged_resp = NewWorkoutResponse::Merged { workout_id, merged_into: Uuid::new_v4() };
+    let new_workout_merged_resp_json = serde_json::to_string_pretty(&new_workout_merged_resp).unwrap();
+    ctx.insert("new_workout_merged_resp_json", &new_workout_merged_resp_json);
+
+    let new_workout_err_resp = NewWorkoutResponse::Error {
+        workout_id,
+        err_code: ERR_START_TIME_CONFLICT,
+        msg: format!("workout {} already exists with the same start_time", Uuid::new_v4()),
+    };
     let new_workout_err_resp_json = serde_json::to_string_pretty(&new_workout_err_resp).unwrap();
     ctx.insert("new_workout_err_resp_json", &new_workout_err_resp_json);
 
+    let new_workout_dup_resp = NewWorkoutResponse::Duplicate { workout_id };
+    let new_workout_dup_resp_json = serde_json::to_string_pretty(&new_workout_dup_resp).unwrap();
+    ctx.insert("new_workout_dup_resp_json", &new_workout_dup_resp_json);
+    ctx.insert("err_start_time_conflict", &ERR_START_TIME_CONFLICT);
+
+    #[derive(Serialize)]
+    struct InvalidFieldsResponse {
+        status: u16,
+        error: String,
+        fields: Vec<fitbod::validate::FieldError>,
+    }
+    let invalid_new_workout_resp = InvalidFieldsResponse {
+        status: 400,
+        error: "invalid request: 2 invalid field(s)".to_string(),
+        fields: vec![
+            fitbod::validate::FieldError::new("items[0].end_time", "must be after start_time"),
+            fitbod::validate::FieldError::new("items[1].exercises[0].sets[2].rpe", "must be between 1 and 10"),
+        ],
+    };
+    let invalid_new_workout_resp_json = serde_json::to_string_pretty(&invalid_new_workout_resp).unwrap();
+    ctx.insert("invalid_new_workout_resp_json", &invalid_new_workout_resp_json);
+    ctx.insert("max_new_workouts", &fitbod::validate::MAX_NEW_WORKOUTS);
+    ctx.insert("max_workout_duration_hours", &fitbod::validate::MAX_WORKOUT_DURATION_HOURS);
+    ctx.insert("max_start_time_ahead_hours", &fitbod::validate::MAX_START_TIME_AHEAD_HOURS);
+    ctx.insert("max_reps", &fitbod::validate::MAX_REPS);
+    ctx.insert("err_workout_id_conflict", &ERR_WORKOUT_ID_CONFLICT);
+    ctx.insert("err_overlap", &ERR_OVERLAP);
+    ctx.insert("err_invalid_merge", &ERR_INVALID_MERGE);
+
     let list_req = ListWorkoutsRequest {
         user_id,
         start: Some(Utc::now() - chrono::Duration::hours(24 * 7 * 3)),
         end: Some(Utc::now()),
         limit: Some(10),
+        order: Order::Desc,
+        mode: MatchMode::StartsWithin,
+        cursor: None,
+        tz: None,
+        category: Some(Category::Strength),
+        tags: Vec::new(),
     };
     let list_req_json = serde_json::to_string_pretty(&list_req).unwrap();
     ctx.insert("list_req_json", &list_req_json);
@@ -76,6 +168,12 @@ fn main() -> Result<(), io::Error> {
         start: None,
         end: None,
         limit: None,
+        order: Order::default(),
+        mode: MatchMode::default(),
+        cursor: None,
+        tz: None,
+        category: None,
+        tags: Vec::new(),
     };
     let list_req_opt_json = serde_json::to_string_pretty(&list_req_opt).unwrap();
     ctx.insert("list_req_opt_json", &list_req_opt_json);
@@ -89,20 +187,79 @@ fn main() -> Result<(), io::Error> {
     assert_eq!(serde_json::from_str::<ListWorkoutsRequest>(&only_user_id_json).unwrap().user_id, list_req.user_id);
     ctx.insert("only_user_id_json", &only_user_id_json);
 
-    let list_item = ListWorkoutsItem { 
-        workout_id,
-        date: start_time.date().naive_local(),
-        duration_minutes: (end_time - start_time).num_minutes() as u32,
-    };
+    let list_item = ListWorkoutsItem::new(&workout, chrono_tz::UTC);
 
     let list_resp = ListWorkoutsResponse {
         user_id,
         n_items: 1,
         items: vec![list_item.clone()],
+        cursor: Some(Cursor { start_time, workout_id }),
+        tz: chrono_tz::UTC,
     };
     let list_resp_json = serde_json::to_string_pretty(&list_resp).unwrap();
     ctx.insert("list_resp_json", &list_resp_json);
 
+    let stats_req = WorkoutStatsRequest {
+        user_id,
+        start: Some(Utc::now() - chrono::Duration::days(30)),
+        end: None,
+        tz: None,
+    };
+    let stats_req_json = serde_json::to_string_pretty(&stats_req).unwrap();
+    ctx.insert("stats_req_json", &stats_req_json);
+
+    let stats_resp = WorkoutStatsResponse {
+        user_id,
+        start: stats_req.start,
+        end: stats_req.end,
+        tz: chrono_tz::UTC,
+        stats: WorkoutStats {
+            n_workouts: 14,
+            total_duration_minutes: 742,
+            avg_duration_minutes: Some(742.0 / 14.0),
+            longest_daily_streak: 4,
+            current_daily_streak: 2,
+            longest_weekly_streak: 5,
+            current_weekly_streak: 5,
+            most_recent: Some(list_item.clone()),
+        },
+    };
+    let stats_resp_json = serde_json::to_string_pretty(&stats_resp).unwrap();
+    ctx.insert("stats_resp_json", &stats_resp_json);
+
+    let tz: chrono_tz::Tz = "America/New_York".parse().unwrap();
+    let tz_req_json = serde_json::to_string_pretty(&UserTimeZoneRequest { user_id, tz: Some(tz) }).unwrap();
+    ctx.insert("tz_req_json", &tz_req_json);
+    let tz_resp_json = serde_json::to_string_pretty(&UserTimeZoneResponse { user_id, tz }).unwrap();
+    ctx.insert("tz_resp_json", &tz_resp_json);
+
+    let aggregate_req = AggregateWorkoutsRequest {
+        user_id,
+        bucket: BucketSize::Week,
+        start: Some(Utc.with_ymd_and_hms(2021, 7, 12, 4, 0, 0).unwrap()),
+        end: Some(Utc.with_ymd_and_hms(2021, 8, 2, 4, 0, 0).unwrap()),
+        tz: Some(tz),
+    };
+    let aggregate_req_json = serde_json::to_string_pretty(&aggregate_req).unwrap();
+    ctx.insert("aggregate_req_json", &aggregate_req_json);
+
+    let buckets: Vec<WorkoutBucket> = [(12, 3, 155), (19, 0, 0), (26, 2, 95)].iter()
+        .map(|&(day, n_workouts, total_duration_minutes)| WorkoutBucket {
+            date: NaiveDate::from_ymd_opt(2021, 7, day).unwrap(),
+            n_workouts,
+            total_duration_minutes,
+        }).collect();
+    let aggregate_resp = AggregateWorkoutsResponse {
+        user_id,
+        bucket: BucketSize::Week,
+        tz,
+        n_buckets: buckets.len(),
+        buckets,
+    };
+    let aggregate_resp_json = serde_json::to_string_pretty(&aggregate_resp).unwrap();
+    ctx.insert("aggregate_resp_json", &aggregate_resp_json);
+    ctx.insert("max_aggregate_buckets", &MAX_AGGREGATE_BUCKETS);
+
     let new_workout = NewEvents {
         user_id,
         n_items: 1,
@@ -119,6 +276,82 @@ fn main() -> Result<(), io::Error> {
     let dopamine_shot_json = serde_json::to_string_pretty(&dopamine_shot).unwrap();
     ctx.insert("dopamine_shot_json", &dopamine_shot_json);
 
+    let (_, public_key) = fitbod::auth::gen_keypair();
+    let new_key_req = NewKeyRequest {
+        user_id,
+        key: base64::encode(&public_key[..]),
+        expires: Some(Utc::now() + chrono::Duration::days(365)),
+    };
+    let new_key_req_json = serde_json::to_string_pretty(&new_key_req).unwrap();
+    ctx.insert("new_key_req_json", &new_key_req_json);
+
+    let user_key = UserKey {
+        expires: new_key_req.expires,
+        ..UserKey::new(user_id, public_key)
+    };
+    let key_item_json = serde_json::to_string_pretty(&KeyItem::from(&user_key)).unwrap();
+    ctx.insert("key_item_json", &key_item_json);
+
+    let register_req = RegisterUserRequest {
+        email: "new-user@example.com".to_string(),
+        key: base64::encode(&public_key[..]),
+    };
+    let register_req_json = serde_json::to_string_pretty(&register_req).unwrap();
+    ctx.insert("register_req_json", &register_req_json);
+
+    let register_resp = RegisterUserResponse {
+        user_id,
+        email: register_req.email.clone(),
+        key_id: user_key.key_id,
+        created: user_key.created,
+    };
+    let register_resp_json = serde_json::to_string_pretty(&register_resp).unwrap();
+    ctx.insert("register_resp_json", &register_resp_json);
+
+    let list_keys_req_json = serde_json::to_string_pretty(&ListKeysRequest { user_id }).unwrap();
+    ctx.insert("list_keys_req_json", &list_keys_req_json);
+
+    let list_keys_resp = ListKeysResponse {
+        user_id,
+        n_items: 1,
+        items: vec![KeyItem::from(&user_key)],
+    };
+    let list_keys_resp_json = serde_json::to_string_pretty(&list_keys_resp).unwrap();
+    ctx.insert("list_keys_resp_json", &list_keys_resp_json);
+
+    let subscribe_req = SubscribeEventsRequest { user_id, timeout_secs: Some(DEFAULT_SUBSCRIBE_TIMEOUT_SECS) };
+    let subscribe_req_json = serde_json::to_string_pretty(&subscribe_req).unwrap();
+    ctx.insert("subscribe_req_json", &subscribe_req_json);
+    ctx.insert("max_subscribe_timeout_secs", &MAX_SUBSCRIBE_TIMEOUT_SECS);
+
+    let (priv_key, _) = gen_keypair();
+    let handshake_ts = Utc::now().timestamp();
+    let handshake_req = serde_json::json!({ "user_id": user_id }).to_string();
+    let handshake = EventStreamHandshake {
+        timestamp: handshake_ts,
+        signature: RequestSigner::new(handshake_ts, &handshake_req)
+            .method("GET")
+            .path(&format!("/api/{}/events/ws", API_VERSION))
+            .host("fitbod.me")
+            .sign(&priv_key),
+        key_id: Some(user_key.key_id),
+        request: handshake_req.clone(),
+    };
+    let event_stream_handshake_json = serde_json::to_string_pretty(&handshake).unwrap();
+    ctx.insert("event_stream_handshake_json", &event_stream_handshake_json);
+    let event_stream_msg_json = serde_json::to_string(&Event::NewWorkout(list_item.clone())).unwrap();
+    ctx.insert("event_stream_msg_json", &event_stream_msg_json);
+    ctx.insert("heartbeat_interval_secs", &fitbod::events::HEARTBEAT_INTERVAL.as_secs());
+    ctx.insert("handshake_timeout_secs", &fitbod::events::HANDSHAKE_TIMEOUT.as_secs());
+    ctx.insert("max_pending_stream_events", &fitbod::events::MAX_PENDING_STREAM_EVENTS);
+    ctx.insert("max_logged_events", &fitbod::events::MAX_LOGGED_EVENTS);
+    ctx.insert("subscriber_idle_timeout_mins", &(fitbod::events::SUBSCRIBER_IDLE_TIMEOUT.as_secs() / 60));
+    ctx.insert("sse_event_id_example", &(Utc::now().timestamp_micros() as u64));
+
+    let revoke_key_req_json = serde_json::to_string_pretty(&RevokeKeyRequest { user_id, key_id: user_key.key_id }).unwrap();
+    ctx.insert("revoke_key_req_json", &revoke_key_req_json);
+    ctx.insert("key_id_header", KEY_ID_HEADER);
+
     let api_docs = tera.render("api-documentation.md", &ctx).unwrap();
     std::fs::write(OUTPUT_PATH, &api_docs)?;
     Ok(())
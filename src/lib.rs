@@ -5,10 +5,17 @@ use uuid::Uuid;
 pub use api::*;
 pub use auth::*;
 
+pub mod admin;
 pub mod api;
 pub mod auth;
 pub mod cache;
 pub mod db;
+pub mod events;
+pub mod migrate;
+pub mod query;
+pub mod sqlite;
+pub mod stats;
+pub mod validate;
 
 /// user representation matching `users` db table
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -19,13 +26,206 @@ pub struct User {
     pub created: DateTime<Utc>,
 }
 
-/// workout representation matching `workouts` db table
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+/// public key representation matching `user_keys` db table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserKey {
+    pub key_id: Uuid,
+    pub user_id: Uuid,
+    pub key: [u8; 32],
+    pub created: DateTime<Utc>,
+    pub expires: Option<DateTime<Utc>>,
+    pub revoked: Option<DateTime<Utc>>,
+}
+
+impl UserKey {
+    /// new, non-expiring key with a random `key_id`
+    pub fn new(user_id: Uuid, key: [u8; 32]) -> Self {
+        Self {
+            key_id: Uuid::new_v4(),
+            user_id,
+            key,
+            created: Utc::now(),
+            expires: None,
+            revoked: None,
+        }
+    }
+
+    /// whether the key may be used to verify requests at time `t`
+    pub fn is_active_at(&self, t: DateTime<Utc>) -> bool {
+        self.revoked.map(|r| r > t).unwrap_or(true)
+        && self.expires.map(|e| e > t).unwrap_or(true)
+    }
+}
+
+/// workout representation matching `workouts` db table, with its rows from the `exercises`
+/// and `exercise_sets` tables
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Workout {
     pub workout_id: Uuid,
     pub user_id: Uuid,
     pub start_time: DateTime<Utc>,
     pub end_time: DateTime<Utc>,
+    /// optional, in the order they were performed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exercises: Vec<Exercise>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category: Option<Category>,
+    /// free-form labels, e.g. "leg day"
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+/// kind of workout, matching the `category` column of `workouts` db table
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Category {
+    Strength,
+    Cardio,
+    Mobility,
+    Sport,
+    Other,
+}
+
+/// one exercise in a workout, matching `exercises` db table
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Exercise {
+    pub name: String,
+    #[serde(default)]
+    pub sets: Vec<ExerciseSet>,
+}
+
+/// one set of an exercise, matching `exercise_sets` db table
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct ExerciseSet {
+    pub reps: u32,
+    /// load per rep, in `unit`. `None` for bodyweight exercises
+    #[serde(default)]
+    pub weight: Option<f64>,
+    #[serde(default)]
+    pub unit: WeightUnit,
+    /// rate of perceived exertion, 1 to 10
+    #[serde(default)]
+    pub rpe: Option<f32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WeightUnit {
+    #[default]
+    Kg,
+    Lb,
+}
+
+pub const KG_PER_LB: f64 = 0.453_592_37;
+
+impl Workout {
+    /// duration rounded to the nearest minute. zero if `end_time` is before `start_time`, which
+    /// `Workout::validate` rejects but older rows may have
+    pub fn duration_minutes(&self) -> u32 {
+        ((self.end_time - self.start_time).num_seconds().max(0) as f64 / 60.0).round() as u32
+    }
+
+    /// whether the workouts are in progress at the same time. workouts that end exactly when
+    /// the other starts don't overlap
+    pub fn overlaps(&self, other: &Workout) -> bool {
+        self.start_time < other.end_time && other.start_time < self.end_time
+    }
+
+    /// this workout combined with `other`, e.g. one session logged on two devices. keeps this
+    /// workout's `workout_id` and spans the time of both. exercises, tags and note of `other`
+    /// are only added if this workout doesn't have them already, so merging the same workout
+    /// twice changes nothing
+    pub fn merged_with(&self, other: &Workout) -> Workout {
+        let new_exercises = other.exercises.iter()
+            .filter(|x| !self.exercises.contains(x))
+            .cloned();
+        let exercises = if other.start_time < self.start_time {
+            new_exercises.chain(self.exercises.iter().cloned()).collect()
+        } else {
+            self.exercises.iter().cloned().chain(new_exercises).collect()
+        };
+
+        let mut tags = self.tags.clone();
+        tags.extend(other.tags.iter().filter(|t| !self.tags.contains(t)).cloned());
+
+        let note = match (&self.note, &other.note) {
+            (Some(a), Some(b)) if a.contains(b.as_str()) => Some(a.clone()),
+            (Some(a), Some(b)) => Some(format!("{}\n{}", a, b)),
+            (a, b) => a.clone().or_else(|| b.clone()),
+        };
+
+        Workout {
+            user_id: self.user_id,
+            workout_id: self.workout_id,
+            start_time: self.start_time.min(other.start_time),
+            end_time: self.end_time.max(other.end_time),
+            exercises,
+            category: self.category.or(other.category),
+            tags,
+            note,
+        }
+    }
+}
+
+impl ExerciseSet {
+    /// reps times weight, in kg. zero if there is no weight
+    pub fn volume_kg(&self) -> f64 {
+        let weight_kg = match self.unit {
+            WeightUnit::Kg => self.weight.unwrap_or(0.0),
+            WeightUnit::Lb => self.weight.unwrap_or(0.0) * KG_PER_LB,
+        };
+        self.reps as f64 * weight_kg
+    }
+}
+
+impl WeightUnit {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WeightUnit::Kg => "kg",
+            WeightUnit::Lb => "lb",
+        }
+    }
+}
+
+impl Category {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Category::Strength => "strength",
+            Category::Cardio => "cardio",
+            Category::Mobility => "mobility",
+            Category::Sport => "sport",
+            Category::Other => "other",
+        }
+    }
+}
+
+impl std::str::FromStr for Category {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strength" => Ok(Category::Strength),
+            "cardio" => Ok(Category::Cardio),
+            "mobility" => Ok(Category::Mobility),
+            "sport" => Ok(Category::Sport),
+            "other" => Ok(Category::Other),
+            other => Err(format!("invalid category: {} (expected strength, cardio, mobility, sport or other)", other)),
+        }
+    }
+}
+
+impl std::str::FromStr for WeightUnit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kg" => Ok(WeightUnit::Kg),
+            "lb" => Ok(WeightUnit::Lb),
+            other => Err(format!("invalid weight unit: {} (expected kg or lb)", other)),
+        }
+    }
 }
 
 pub trait UserId {
@@ -43,7 +243,17 @@ macro_rules! impl_user_id {
 }
 
 impl_user_id!(User);
+impl_user_id!(UserKey);
 impl_user_id!(Workout);
 impl_user_id!(SubscribeEventsRequest);
 impl_user_id!(ListWorkoutsRequest);
 impl_user_id!(NewWorkoutsRequest);
+impl_user_id!(GetWorkoutRequest);
+impl_user_id!(UpdateWorkoutRequest);
+impl_user_id!(DeleteWorkoutRequest);
+impl_user_id!(WorkoutStatsRequest);
+impl_user_id!(AggregateWorkoutsRequest);
+impl_user_id!(UserTimeZoneRequest);
+impl_user_id!(NewKeyRequest);
+impl_user_id!(ListKeysRequest);
+impl_user_id!(RevokeKeyRequest);
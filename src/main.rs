@@ -11,11 +11,15 @@ use pretty_toa::ThousandsSep;
 use tokio::runtime::Runtime;
 use warp::{Filter, Rejection, Reply, filters::path::FullPath};
 use structopt::StructOpt;
-use fitbod::{Workout, ListWorkoutsRequest, ListWorkoutsResponse, NewWorkoutsRequest};
+use fitbod::db::{KeyRevocation, Storage};
+use fitbod::query::{MatchMode, Order, WorkoutQuery};
+use fitbod::stats::{Aggregation, WorkoutStats};
+use fitbod::{Workout, ListWorkoutsRequest, ListWorkoutsResponse, NewWorkoutsRequest, GetWorkoutRequest, UpdateWorkoutRequest, DeleteWorkoutRequest, WorkoutStatsRequest, WorkoutStatsResponse, AggregateWorkoutsRequest, AggregateWorkoutsResponse, UserTimeZoneRequest, UserTimeZoneResponse, SubscribeEventsRequest, NewKeyRequest, ListKeysRequest, RevokeKeyRequest};
 
 /// fitbod api example server
 ///
-/// DATABASE_URL env var must be present with postgres connection info
+/// DATABASE_URL env var must be present with postgres connection info, a sqlite url (e.g.
+/// "sqlite://var/fitbod.db"), or "memory:" to keep everything in memory
 #[derive(StructOpt)]
 #[structopt(author = env!("CARGO_PKG_AUTHORS"))]
 enum Opt {
@@ -24,6 +28,40 @@ enum Opt {
         /// api server address to listen on
         #[structopt(value_name = "ADDR")]
         bind: SocketAddr,
+
+        /// reject signed requests whose timestamp differs from server time by more than
+        /// this many seconds
+        #[structopt(long, default_value = "30")]
+        max_clock_skew: i64,
+
+        /// number of recently seen request signatures remembered per user to detect replays
+        #[structopt(long, default_value = "1024")]
+        max_seen_signatures: usize,
+
+        /// only accept requests signed with the v2 (method, path, host, body hash) scheme
+        #[structopt(long)]
+        reject_v1_signatures: bool,
+
+        /// allow admins listed in --admin-keys to sign requests on behalf of users. every such
+        /// request is appended to --admin-audit-log. disabled unless this flag is present
+        #[structopt(long, requires_all = &["admin-keys", "admin-audit-log"])]
+        enable_admin_access: bool,
+
+        /// csv file of admin keys, with columns admin_id,name,public_key
+        #[structopt(long, value_name = "PATH")]
+        admin_keys: Option<PathBuf>,
+
+        /// file that admin requests made on behalf of users are appended to, as json lines
+        #[structopt(long, value_name = "PATH")]
+        admin_audit_log: Option<PathBuf>,
+    },
+
+    /// apply pending schema migrations to the database, each in its own transaction. `run`
+    /// refuses to start until the schema is up to date
+    Migrate {
+        /// only list pending migrations, without applying them
+        #[structopt(long)]
+        dry_run: bool,
     },
 
     /// print example http request for /api/v1/workouts/list endpoint to stdout
@@ -52,6 +90,49 @@ enum Opt {
         #[structopt(long)]
         limit: Option<usize>,
 
+        /// continue from the `cursor` of a previous response
+        #[structopt(long)]
+        cursor: Option<fitbod::api::Cursor>,
+
+        /// "desc" (newest first) or "asc"
+        #[structopt(long, default_value = "desc")]
+        order: Order,
+
+        /// "starts_within" or "overlaps"
+        #[structopt(long, default_value = "starts_within")]
+        mode: MatchMode,
+
+        /// IANA time zone for item dates, e.g. America/New_York. defaults to the user's
+        #[structopt(long)]
+        tz: Option<chrono_tz::Tz>,
+
+        /// only workouts in this category (strength, cardio, mobility, sport or other)
+        #[structopt(long)]
+        category: Option<fitbod::Category>,
+
+        /// only workouts with this tag. may be given more than once
+        #[structopt(long = "tag")]
+        tags: Vec<String>,
+
+        /// value of http host header
+        #[structopt(long, default_value = "fitbod.jstrong.dev")]
+        host: String,
+
+        /// output curl command instead of http request text
+        #[structopt(long)]
+        curl: bool,
+
+        /// for --curl mode, what address to connect to to send request
+        #[structopt(short, long, default_value = "https://fitbod.jstrong.dev")]
+        connect: String,
+    },
+
+    /// print example http request for /api/v1/users/register endpoint to stdout. a new keypair is
+    /// generated, and the private key is printed to stderr
+    RegisterUserRequest {
+        /// email of the new user
+        email: String,
+
         /// value of http host header
         #[structopt(long, default_value = "fitbod.jstrong.dev")]
         host: String,
@@ -85,6 +166,10 @@ enum Opt {
         /// workout duration in minutes
         duration: u32,
 
+        /// what to do if the workout overlaps another: "accept", "reject" or "merge"
+        #[structopt(long, default_value = "accept")]
+        overlap: fitbod::api::OverlapPolicy,
+
         /// value of http host header
         #[structopt(long, default_value = "fitbod.jstrong.dev")]
         host: String,
@@ -115,19 +200,40 @@ struct ErrorMsg {
 
 impl warp::reject::Reject for ErrorMsg {}
 
+/// request failed validation. rejected with `400 Bad Request`, listing the problems in `fields`
+#[derive(Debug, Clone)]
+struct InvalidFields(Vec<fitbod::validate::FieldError>);
+
+impl warp::reject::Reject for InvalidFields {}
+
+/// body of all error responses
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ErrorResponse {
+    status: u16,
+    error: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    fields: Vec<fitbod::validate::FieldError>,
+}
+
 async fn handle_rejection(err: Rejection) -> Result<impl Reply, std::convert::Infallible> {
     let code;
     let message;
+    let mut fields = Vec::new();
     if let Some(ErrorMsg { status, error }) = err.find() {
         code = http::StatusCode::from_u16(*status).unwrap();
         message = error.to_string();
+    } else if let Some(InvalidFields(errors)) = err.find() {
+        code = http::StatusCode::BAD_REQUEST;
+        message = format!("invalid request: {} invalid field(s)", errors.len());
+        fields = errors.clone();
     } else {
         code = http::StatusCode::NOT_FOUND;
         message = "not found".to_string();
     };
-    let json = warp::reply::json(&ErrorMsg {
+    let json = warp::reply::json(&ErrorResponse {
         status: code.as_u16(),
         error: message,
+        fields,
     });
 
     Ok(warp::reply::with_status(json, code))
@@ -162,12 +268,35 @@ fn http_request() -> impl Filter<Extract = (http::Request<bytes::Bytes>,), Error
         })
 }
 
-async fn init_cache(cache: &fitbod::cache::Cache, db: &fitbod::db::DataBase) {
+/// if none of the user's workouts are cached, fetch them from the db, so writes are checked
+/// against all of the user's workouts. fails if the db can't be reached, since checking
+/// against an empty cache would treat every workout as missing or new
+async fn ensure_workouts_cached<S: Storage>(cache: &fitbod::cache::Cache, db: &S, user_id: Uuid) -> Result<(), sqlx::Error> {
+    if ! cache.workouts_exist(&user_id) {
+        fetch_and_cache_workouts(cache, db, user_id).await?;
+    }
+    Ok(())
+}
+
+/// fetch all of the user's workouts from the db, for a read that missed the cache, and cache
+/// them for the next one
+async fn fetch_and_cache_workouts<S: Storage>(cache: &fitbod::cache::Cache, db: &S, user_id: Uuid) -> Result<Vec<Workout>, sqlx::Error> {
+    let mut workouts = db.fetch_user_workouts(&user_id).await?;
+    if ! workouts.is_empty() {
+        cache.cache_workouts(user_id, &mut workouts[..]);
+    }
+    Ok(workouts)
+}
+
+async fn init_cache<S: Storage>(cache: &fitbod::cache::Cache, db: &S) {
     let init_start = Instant::now();
     let user_keys = db.fetch_user_keys().await.unwrap();
-    let n_users = user_keys.len();
-    for (user_id, key) in user_keys {
-        cache.insert_key(user_id, key);
+    let n_users = user_keys.iter().map(|k| k.user_id).unique().count();
+    for key in user_keys {
+        cache.insert_key(key);
+    }
+    for (user_id, tz) in db.fetch_user_time_zones().await.unwrap() {
+        cache.set_time_zone(user_id, tz);
     }
     let mut prefetch_workouts: Vec<fitbod::Workout> = db.fetch_recently_active_user_workouts().await.unwrap();
     prefetch_workouts.sort_unstable_by_key(|x| (x.user_id, x.start_time));
@@ -188,174 +317,878 @@ async fn init_cache(cache: &fitbod::cache::Cache, db: &fitbod::db::DataBase) {
 }
 
 
-fn run(db_url: &str, bind: SocketAddr) -> Result<(), Box<dyn std::error::Error>> {
+fn run(
+    db_url: &str,
+    bind: SocketAddr,
+    auth_config: fitbod::cache::AuthConfig,
+    admin: Option<fitbod::admin::AdminAccess>,
+) -> Result<(), Box<dyn std::error::Error>> {
     let rt  = Runtime::new()?;
     rt.block_on(async {
-        let cache = fitbod::cache::Cache::default();
-        let db = fitbod::db::DataBase::new(&db_url).await.unwrap();
-
-        init_cache(&cache, &db).await;
-
-        let cache = warp::any().map(move || cache.clone());
-        let db = warp::any().map(move || db.clone());
-
-        let api_routes = warp::path("api")
-            .and(warp::path("v1"))
-            .and(warp::post())
-            .and(cache)
-            .and(db);
-
-        let list_workouts = api_routes.clone()
-            .and(warp::path("workouts"))
-            .and(warp::path("list"))
-            .and(http_request())
-            .and_then(|cache: fitbod::cache::Cache, db: fitbod::db::DataBase, http_req| async move {
-                match cache.parse_and_verify_http_request::<ListWorkoutsRequest>(&http_req) {
-                    Ok(req) => {
-                        match cache.get_cached_workouts(&req.user_id, req.start, req.end, req.limit) {
-                            Some(workouts) => {
-                                let items: Vec<_> = workouts.iter()
-                                    .map(|x| fitbod::api::ListWorkoutsItem::from(x))
-                                    .collect();
-                                let resp = ListWorkoutsResponse {
-                                    user_id: req.user_id,
-                                    n_items: items.len(),
-                                    items,
-                                };
-                                Ok(warp::reply::json(&resp))
-                            }
+        let mut cache = fitbod::cache::Cache::new(auth_config);
+        if let Some(admin) = admin {
+            println!("admin access enabled for {} admin keys", admin.n_keys());
+            cache = cache.with_admin_access(admin);
+        }
+        if db_url == fitbod::db::MEMORY_URL {
+            println!("using in-memory storage, nothing will be persisted");
+            serve(fitbod::db::MemoryStorage::new(), cache, bind).await;
+        } else if db_url.starts_with(fitbod::sqlite::URL_PREFIX) {
+            let db = fitbod::sqlite::SqliteDataBase::new(db_url).await?;
+            fitbod::migrate::check_version(fitbod::migrate::SQLITE, db.schema_version().await?)?;
+            serve(db, cache, bind).await;
+        } else {
+            let db = fitbod::db::DataBase::new(db_url).await?;
+            fitbod::migrate::check_version(fitbod::migrate::POSTGRESQL, db.schema_version().await?)?;
+            serve(db, cache, bind).await;
+        }
+        Ok(())
+    })
+}
 
-                            None => {
-                                match db.fetch_user_workouts(&req.user_id).await {
-                                    Ok(mut workouts) if ! workouts.is_empty() => {
-                                        // cache db results
-                                        cache.cache_workouts(req.user_id, &mut workouts[..]);
-                                        // apply request filters to db results
-                                        workouts.sort_unstable_by(|a, b| a.start_time.cmp(&b.start_time));
-                                        let start   = req.start.unwrap_or_else(|| Utc.ymd(1970, 1, 1).and_hms(0, 0, 0));
-                                        let end     = req.end  .unwrap_or_else(|| Utc.ymd(2142, 7, 27).and_hms(0, 0, 0));
-                                        let limit   = req.limit.unwrap_or(usize::MAX);
-                                        let filtered: Vec<_> = workouts.into_iter()
-                                            .rev()
-                                            .filter(|x| {
-                                                x.start_time >= start
-                                                && x.end_time < end
-                                            })
-                                            .take(limit)
-                                            .map(|x| fitbod::api::ListWorkoutsItem::from(&x))
-                                            .collect();
-                                        let resp = ListWorkoutsResponse {
-                                            user_id: req.user_id,
-                                            n_items: filtered.len(),
-                                            items: filtered,
-                                        };
-                                        Ok(warp::reply::json(&resp))
-                                    }
-
-                                    Ok(empty) => {
-                                        assert!(empty.is_empty());
-                                        let resp = ListWorkoutsResponse {
-                                            user_id: req.user_id,
-                                            n_items: 0,
-                                            items: Vec::new(),
-                                        };
-                                        Ok(warp::reply::json(&resp))
-                                    }
-
-                                    Err(e) => {
-                                        Err(warp::reject::custom(ErrorMsg {
-                                            status: 500,
-                                            error: format!("database error: {}", e),
-                                        }))
-                                    }
+fn migrate(db_url: &str, dry_run: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let rt  = Runtime::new()?;
+    rt.block_on(async {
+        let (migrations, current) = if db_url == fitbod::db::MEMORY_URL {
+            println!("in-memory storage has no schema to migrate");
+            return Ok(())
+        } else if db_url.starts_with(fitbod::sqlite::URL_PREFIX) {
+            let db = fitbod::sqlite::SqliteDataBase::new(db_url).await?;
+            let current = db.schema_version().await?;
+            if ! dry_run {
+                db.migrate().await?;
+            }
+            (fitbod::migrate::SQLITE, current)
+        } else {
+            let db = fitbod::db::DataBase::new(db_url).await?;
+            let current = db.schema_version().await?;
+            if ! dry_run {
+                db.migrate().await?;
+            }
+            (fitbod::migrate::POSTGRESQL, current)
+        };
+
+        let pending = fitbod::migrate::pending(migrations, current);
+        let current = current.map(|v| v.to_string()).unwrap_or_else(|| "none".to_string());
+        if pending.is_empty() {
+            println!("schema is up to date (version {})", current);
+            return Ok(())
+        }
+        println!("schema version: {}", current);
+        for m in pending {
+            println!("{} {}: {}", if dry_run { "pending" } else { "applied" }, m.version, m.descr);
+        }
+        Ok(())
+    })
+}
+
+/// serve the api on `bind`, with `db` as the storage behind `cache`
+async fn serve<S: Storage>(db: S, cache: fitbod::cache::Cache, bind: SocketAddr) {
+    init_cache(&cache, &db).await;
+
+    let cache = warp::any().map(move || cache.clone());
+    let db = warp::any().map(move || db.clone());
+
+    let api_routes = warp::path("api")
+        .and(warp::path("v1"))
+        .and(warp::post())
+        .and(cache.clone())
+        .and(db);
+
+    let list_workouts = api_routes.clone()
+        .and(warp::path("workouts"))
+        .and(warp::path("list"))
+        .and(http_request())
+        .and_then(|cache: fitbod::cache::Cache, db: S, http_req| async move {
+            match cache.parse_and_verify_http_request::<ListWorkoutsRequest>(&http_req) {
+                Ok(req) => {
+                    // one extra workout is fetched to find out whether there is another page
+                    let query = WorkoutQuery {
+                        limit: req.limit.map(|n| n.saturating_add(1)),
+                        ..req.query()
+                    };
+                    let tz = req.tz.unwrap_or_else(|| cache.time_zone(&req.user_id));
+
+                    match cache.get_cached_workouts(&req.user_id, &query) {
+                        Some(workouts) => {
+                            let resp = ListWorkoutsResponse::new(req.user_id, workouts, req.limit, tz);
+                            Ok(warp::reply::json(&resp))
+                        }
+
+                        None => {
+                            match fetch_and_cache_workouts(&cache, &db, req.user_id).await {
+                                Ok(workouts) => {
+                                    let selected = query.select_unsorted(workouts);
+                                    let resp = ListWorkoutsResponse::new(req.user_id, selected, req.limit, tz);
+                                    Ok(warp::reply::json(&resp))
                                 }
 
+                                Err(e) => {
+                                    Err(warp::reject::custom(ErrorMsg {
+                                        status: 500,
+                                        error: format!("database error: {}", e),
+                                    }))
+                                }
                             }
                         }
                     }
+                }
+
+                Err(e) => {
+                    Err(warp::reject::custom(ErrorMsg {
+                        status: 400,
+                        error: format!("auth error: {:?}", e),
+                    }))
+                }
+            }
+        });
+
+    let new_workouts = api_routes.clone()
+        .and(warp::path("workouts"))
+        .and(warp::path("new"))
+        .and(http_request())
+        .and_then(|cache: fitbod::cache::Cache, db: S, http_req| async move {
+            match cache.parse_and_verify_http_request::<NewWorkoutsRequest>(&http_req) {
+                Ok(req) => {
+                    if let Err(errors) = req.validate(Utc::now()) {
+                        return Err(warp::reject::custom(InvalidFields(errors)))
+                    }
+
+                    // if no workouts are cached, fetch from db so we know which of these
+                    // ones are new
+                    //
+                    // this could be improved - perhaps the insert query could be converted
+                    // to upsert + select in the case that we have no cache for the user
+                    //
+                    ensure_workouts_cached(&cache, &db, req.user_id).await
+                        .map_err(|e| {
+                            warp::reject::custom(ErrorMsg {
+                                status: 500,
+                                error: format!("database error: {}", e),
+                            })
+                        })?;
+
+                    // the cache is only changed once the db transaction commits. until then
+                    // other writes of the user's workouts wait, so a retry of items that fail
+                    // to save isn't mistaken for a duplicate
+                    let save = |inserted: Vec<Workout>, merged: Vec<Workout>| async move {
+                        db.save_new_workouts(&inserted, &merged).await
+                    };
+                    let written = match cache.write_new_workouts(req.user_id, &req.items[..], req.overlap, save).await {
+                        Ok(written) => written,
+                        Err(e) => {
+                            return Err(warp::reject::custom(ErrorMsg {
+                                status: 500,
+                                error: format!("database error: {}", e),
+                            }))
+                        }
+                    };
 
+                    let tz = cache.time_zone(&req.user_id);
+                    for workout in &written.inserted {
+                        let event = fitbod::api::Event::NewWorkout(fitbod::api::ListWorkoutsItem::new(workout, tz));
+                        cache.events().push(req.user_id, event);
+                    }
+                    for workout in &written.merged {
+                        let event = fitbod::api::Event::WorkoutUpdated(fitbod::api::ListWorkoutsItem::new(workout, tz));
+                        cache.events().push(req.user_id, event);
+                    }
+
+                    let resp: Vec<fitbod::api::NewWorkoutResponse> = req.items.iter()
+                        .zip(written.outcomes.iter())
+                        .map(|(workout, outcome)| outcome.to_response(workout.workout_id))
+                        .collect();
+
+                    Ok(warp::reply::json(&resp))
+                }
+
+                Err(e) => {
+                    Err(warp::reject::custom(ErrorMsg {
+                        status: 400,
+                        error: format!("auth error: {:?}", e),
+                    }))
+                }
+            }
+        });
+
+    let get_workout = api_routes.clone()
+        .and(warp::path("workouts"))
+        .and(warp::path("get"))
+        .and(http_request())
+        .and_then(|cache: fitbod::cache::Cache, db: S, http_req| async move {
+            let req = match cache.parse_and_verify_http_request::<GetWorkoutRequest>(&http_req) {
+                Ok(req) => req,
+                Err(e) => {
+                    return Err(warp::reject::custom(ErrorMsg {
+                        status: 400,
+                        error: format!("auth error: {:?}", e),
+                    }))
+                }
+            };
+
+            // once any of the user's workouts are cached, all of them are
+            let workout = if cache.workouts_exist(&req.user_id) {
+                cache.get_cached_workout(&req.user_id, &req.workout_id)
+            } else {
+                match db.fetch_workout(&req.user_id, &req.workout_id).await {
+                    Ok(workout) => workout,
                     Err(e) => {
-                        Err(warp::reject::custom(ErrorMsg {
-                            status: 400,
-                            error: format!("auth error: {:?}", e),
+                        return Err(warp::reject::custom(ErrorMsg {
+                            status: 500,
+                            error: format!("database error: {}", e),
                         }))
                     }
                 }
-            });
-
-        let new_workouts = api_routes
-            .and(warp::path("workouts"))
-            .and(warp::path("new"))
-            .and(http_request())
-            .and_then(|cache: fitbod::cache::Cache, db: fitbod::db::DataBase, http_req| async move {
-                match cache.parse_and_verify_http_request::<NewWorkoutsRequest>(&http_req) {
-                    Ok(mut req) => {
-                        // if no workouts are cached, fetch from db so we know which of these
-                        // ones are new
-                        //
-                        // this could be improved - perhaps the insert query could be converted
-                        // to upsert + select in the case that we have no cache for the user
-                        //
-                        if ! cache.workouts_exist(&req.user_id) {
-                            if let Ok(mut db_workouts) = db.fetch_user_workouts(&req.user_id).await {
-                                let _ = cache.cache_workouts(req.user_id, &mut db_workouts[..]);
-                            }
+            };
+
+            match workout {
+                Some(workout) => Ok(warp::reply::json(&fitbod::api::WorkoutDetail::from(workout))),
+                None => {
+                    Err(warp::reject::custom(ErrorMsg {
+                        status: 404,
+                        error: format!("workout not found: {}", req.workout_id),
+                    }))
+                }
+            }
+        });
+
+    let workout_stats = api_routes.clone()
+        .and(warp::path("workouts"))
+        .and(warp::path("stats"))
+        .and(http_request())
+        .and_then(|cache: fitbod::cache::Cache, db: S, http_req| async move {
+            let req = match cache.parse_and_verify_http_request::<WorkoutStatsRequest>(&http_req) {
+                Ok(req) => req,
+                Err(e) => {
+                    return Err(warp::reject::custom(ErrorMsg {
+                        status: 400,
+                        error: format!("auth error: {:?}", e),
+                    }))
+                }
+            };
+
+            let query = req.query();
+            let tz = req.tz.unwrap_or_else(|| cache.time_zone(&req.user_id));
+            let today = Utc::now().with_timezone(&tz).date_naive();
+            let stats = match cache.get_workout_stats(&req.user_id, &query, tz, today) {
+                Some(stats) => stats,
+
+                None => {
+                    match fetch_and_cache_workouts(&cache, &db, req.user_id).await {
+                        Ok(workouts) => {
+                            let selected = query.select_unsorted(workouts);
+                            WorkoutStats::compute(selected.iter(), tz, today)
                         }
 
-                        let unseen = cache.cache_workouts(req.user_id, &mut req.items[..]);
-
-                        if ! unseen.is_empty() {
-                            match db.insert_workouts(&unseen).await {
-                                Ok(_) => Ok(warp::reply::with_status(warp::reply::reply(), http::StatusCode::NO_CONTENT)),
+                        Err(e) => {
+                            return Err(warp::reject::custom(ErrorMsg {
+                                status: 500,
+                                error: format!("database error: {}", e),
+                            }))
+                        }
+                    }
+                }
+            };
+
+            let resp = WorkoutStatsResponse {
+                user_id: req.user_id,
+                start: req.start,
+                end: req.end,
+                tz,
+                stats,
+            };
+            Ok(warp::reply::json(&resp))
+        });
+
+    let aggregate_workouts = api_routes.clone()
+        .and(warp::path("workouts"))
+        .and(warp::path("aggregate"))
+        .and(http_request())
+        .and_then(|cache: fitbod::cache::Cache, db: S, http_req| async move {
+            let req = match cache.parse_and_verify_http_request::<AggregateWorkoutsRequest>(&http_req) {
+                Ok(req) => req,
+                Err(e) => {
+                    return Err(warp::reject::custom(ErrorMsg {
+                        status: 400,
+                        error: format!("auth error: {:?}", e),
+                    }))
+                }
+            };
+
+            let aggregation = Aggregation {
+                size: req.bucket,
+                tz: req.tz.unwrap_or_else(|| cache.time_zone(&req.user_id)),
+                start: req.start,
+                end: req.end.unwrap_or_else(Utc::now),
+            };
+            let buckets = match cache.aggregate_workouts(&req.user_id, &aggregation) {
+                Some(buckets) => buckets,
+
+                None => {
+                    match fetch_and_cache_workouts(&cache, &db, req.user_id).await {
+                        Ok(workouts) => {
+                            let selected = aggregation.query().select_unsorted(workouts);
+                            aggregation.aggregate(selected.iter())
+                        }
 
-                                Err(e) => {
-                                    Err(warp::reject::custom(ErrorMsg {
-                                        status: 500,
-                                        error: format!("database error: {}", e),
-                                    }))
-                                }
-                            }
-                        } else {
-                            Ok(warp::reply::with_status(warp::reply::reply(), http::StatusCode::NO_CONTENT))
+                        Err(e) => {
+                            return Err(warp::reject::custom(ErrorMsg {
+                                status: 500,
+                                error: format!("database error: {}", e),
+                            }))
                         }
                     }
+                }
+            };
+
+            match buckets {
+                Ok(buckets) => {
+                    let resp = AggregateWorkoutsResponse {
+                        user_id: req.user_id,
+                        bucket: aggregation.size,
+                        tz: aggregation.tz,
+                        n_buckets: buckets.len(),
+                        buckets,
+                    };
+                    Ok(warp::reply::json(&resp))
+                }
+
+                Err(e) => {
+                    Err(warp::reject::custom(ErrorMsg {
+                        status: 400,
+                        error: e,
+                    }))
+                }
+            }
+        });
+
+    let update_workout = api_routes.clone()
+        .and(warp::path("workouts"))
+        .and(warp::path("update"))
+        .and(http_request())
+        .and_then(|cache: fitbod::cache::Cache, db: S, http_req| async move {
+            let req = match cache.parse_and_verify_http_request::<UpdateWorkoutRequest>(&http_req) {
+                Ok(req) => req,
+                Err(e) => {
+                    return Err(warp::reject::custom(ErrorMsg {
+                        status: 400,
+                        error: format!("auth error: {:?}", e),
+                    }))
+                }
+            };
+
+            ensure_workouts_cached(&cache, &db, req.user_id).await
+                .map_err(|e| {
+                    warp::reject::custom(ErrorMsg {
+                        status: 500,
+                        error: format!("database error: {}", e),
+                    })
+                })?;
+
+            let not_found = || {
+                warp::reject::custom(ErrorMsg {
+                    status: 404,
+                    error: format!("workout not found: {}", req.workout_id),
+                })
+            };
+
+            // keeps workouts/new from checking items against the cache while it's changed below
+            let _writing = cache.lock_user_writes(req.user_id).await;
+
+            let existing = cache.get_cached_workout(&req.user_id, &req.workout_id)
+                .ok_or_else(not_found)?;
+
+            let updated = req.apply(&existing);
+            if let Err(errors) = updated.validate(Utc::now()) {
+                return Err(warp::reject::custom(InvalidFields(errors)))
+            }
+
+            // cache is updated first, so a conflicting start_time is rejected without a
+            // round trip to the db. if the db update fails, the cache change is undone
+            let previous = match cache.update_workout(&updated) {
+                fitbod::cache::UpdateOutcome::Updated { previous } => previous,
+
+                fitbod::cache::UpdateOutcome::NotFound => return Err(not_found()),
+
+                fitbod::cache::UpdateOutcome::StartTimeConflict { existing_workout_id } => {
+                    return Err(warp::reject::custom(ErrorMsg {
+                        status: 409,
+                        error: format!("workout {} already exists with the same start_time", existing_workout_id),
+                    }))
+                }
+            };
+
+            match db.update_workout(&updated).await {
+                Ok(true) => {
+                    let tz = cache.time_zone(&req.user_id);
+                    let event = fitbod::api::Event::WorkoutUpdated(fitbod::api::ListWorkoutsItem::new(&updated, tz));
+                    cache.events().push(req.user_id, event);
+                    Ok(warp::reply::json(&updated))
+                }
+
+                Ok(false) => {
+                    cache.update_workout(&previous);
+                    Err(not_found())
+                }
+
+                Err(e) if fitbod::db::is_unique_violation(&e) => {
+                    cache.update_workout(&previous);
+                    Err(warp::reject::custom(ErrorMsg {
+                        status: 409,
+                        error: "a workout already exists with the same start_time".to_string(),
+                    }))
+                }
+
+                Err(e) => {
+                    cache.update_workout(&previous);
+                    Err(warp::reject::custom(ErrorMsg {
+                        status: 500,
+                        error: format!("database error: {}", e),
+                    }))
+                }
+            }
+        });
+
+    let delete_workout = api_routes.clone()
+        .and(warp::path("workouts"))
+        .and(warp::path("delete"))
+        .and(http_request())
+        .and_then(|cache: fitbod::cache::Cache, db: S, http_req| async move {
+            let req = match cache.parse_and_verify_http_request::<DeleteWorkoutRequest>(&http_req) {
+                Ok(req) => req,
+                Err(e) => {
+                    return Err(warp::reject::custom(ErrorMsg {
+                        status: 400,
+                        error: format!("auth error: {:?}", e),
+                    }))
+                }
+            };
+
+            ensure_workouts_cached(&cache, &db, req.user_id).await
+                .map_err(|e| {
+                    warp::reject::custom(ErrorMsg {
+                        status: 500,
+                        error: format!("database error: {}", e),
+                    })
+                })?;
+
+            let not_found = || {
+                warp::reject::custom(ErrorMsg {
+                    status: 404,
+                    error: format!("workout not found: {}", req.workout_id),
+                })
+            };
+
+            let _writing = cache.lock_user_writes(req.user_id).await;
+
+            let removed = cache.remove_workout(&req.user_id, &req.workout_id)
+                .ok_or_else(not_found)?;
+
+            match db.delete_workout(&req.user_id, &req.workout_id).await {
+                Ok(true) => {
+                    let event = fitbod::api::Event::WorkoutDeleted { workout_id: req.workout_id };
+                    cache.events().push(req.user_id, event);
+                    Ok(warp::reply::with_status(warp::reply::reply(), http::StatusCode::NO_CONTENT))
+                }
+
+                Ok(false) => {
+                    cache.try_cache_workouts(req.user_id, std::slice::from_ref(&removed));
+                    Err(not_found())
+                }
+
+                Err(e) => {
+                    cache.try_cache_workouts(req.user_id, std::slice::from_ref(&removed));
+                    Err(warp::reject::custom(ErrorMsg {
+                        status: 500,
+                        error: format!("database error: {}", e),
+                    }))
+                }
+            }
+        });
+
+    let subscribe_events = api_routes.clone()
+        .and(warp::path("events"))
+        .and(warp::path("subscribe"))
+        .and(http_request())
+        .and_then(|cache: fitbod::cache::Cache, _db: S, http_req| async move {
+            match cache.parse_and_verify_http_request::<SubscribeEventsRequest>(&http_req) {
+                Ok(req) => {
+                    let items = cache.events().wait(req.user_id, req.timeout()).await;
+                    let resp = fitbod::api::NewEvents {
+                        user_id: req.user_id,
+                        n_items: items.len(),
+                        items,
+                    };
+                    Ok(warp::reply::json(&resp))
+                }
+
+                Err(e) => {
+                    Err(warp::reject::custom(ErrorMsg {
+                        status: 400,
+                        error: format!("auth error: {:?}", e),
+                    }))
+                }
+            }
+        });
+
+    let events_ws = warp::path("api")
+        .and(warp::path("v1"))
+        .and(warp::path("events"))
+        .and(warp::path("ws"))
+        .and(warp::path::end())
+        .and(warp::ws())
+        .and(cache.clone())
+        .and(warp::filters::path::full())
+        .and(warp::header::optional::<String>("host"))
+        .map(|ws: warp::ws::Ws, cache: fitbod::cache::Cache, path: FullPath, host: Option<String>| {
+            let path = path.as_str().to_string();
+            let host = host.unwrap_or_default();
+            ws.on_upgrade(move |socket| fitbod::events::serve_event_stream(cache, socket, path, host))
+        });
+
+    let events_sse = api_routes.clone()
+        .and(warp::path("events"))
+        .and(warp::path("sse"))
+        .and(warp::header::optional::<u64>("last-event-id"))
+        .and(http_request())
+        .and_then(|cache: fitbod::cache::Cache, _db: S, last_event_id: Option<u64>, http_req| async move {
+            match cache.parse_and_verify_http_request_with_key_id::<SubscribeEventsRequest>(&http_req) {
+                Ok((req, key_id)) => {
+                    let (replay, stream) = cache.events().subscribe_after(req.user_id, key_id, last_event_id);
+                    let key_active = move || cache.key_is_active(&req.user_id, &key_id);
+                    let events = fitbod::events::sse_events(replay, stream, key_active);
+                    Ok(warp::sse::reply(warp::sse::keep_alive().stream(events)))
+                }
+
+                Err(e) => {
+                    Err(warp::reject::custom(ErrorMsg {
+                        status: 400,
+                        error: format!("auth error: {:?}", e),
+                    }))
+                }
+            }
+        });
+
+    // browsers' `EventSource` can only send GET requests, without custom headers, so the
+    // signature is sent in the query string instead
+    let events_sse_get = warp::path("api")
+        .and(warp::path("v1"))
+        .and(warp::path("events"))
+        .and(warp::path("sse"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(cache.clone())
+        .and(warp::query::<fitbod::api::EventStreamQuery>())
+        .and(warp::header::optional::<u64>("last-event-id"))
+        .and(warp::filters::path::full())
+        .and(warp::header::optional::<String>("host"))
+        .and_then(|cache: fitbod::cache::Cache, query: fitbod::api::EventStreamQuery, last_event_id: Option<u64>, path: FullPath, host: Option<String>| async move {
+            let host = host.unwrap_or_default();
+            let target = fitbod::auth::RequestTarget { method: "GET", path: path.as_str(), host: &host };
+            match cache.verify_event_stream_handshake(&query.handshake(), &target) {
+                Ok((req, key_id)) => {
+                    let last_event_id = last_event_id.or(query.last_event_id);
+                    let (replay, stream) = cache.events().subscribe_after(req.user_id, key_id, last_event_id);
+                    let key_active = move || cache.key_is_active(&req.user_id, &key_id);
+                    let events = fitbod::events::sse_events(replay, stream, key_active);
+                    Ok(warp::sse::reply(warp::sse::keep_alive().stream(events)))
+                }
+
+                Err(e) => {
+                    Err(warp::reject::custom(ErrorMsg {
+                        status: 400,
+                        error: format!("auth error: {:?}", e),
+                    }))
+                }
+            }
+        });
+
+    let register_user = api_routes.clone()
+        .and(warp::path("users"))
+        .and(warp::path("register"))
+        .and(http_request())
+        .and_then(|cache: fitbod::cache::Cache, db: S, http_req| async move {
+            let (req, key) = match cache.parse_and_verify_registration_request(&http_req) {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    return Err(warp::reject::custom(ErrorMsg {
+                        status: 400,
+                        error: format!("auth error: {:?}", e),
+                    }))
+                }
+            };
+
+            let email = req.email.trim();
+            if email.is_empty() || ! email.contains('@') {
+                return Err(warp::reject::custom(ErrorMsg {
+                    status: 400,
+                    error: "invalid email".to_string(),
+                }))
+            }
+
+            let user_key = fitbod::UserKey::new(Uuid::new_v4(), key);
+            let user = fitbod::User {
+                user_id: user_key.user_id,
+                email: email.to_string(),
+                key,
+                created: user_key.created,
+            };
+
+            match db.insert_user(&user, &user_key).await {
+                Ok(_) => {
+                    let resp = fitbod::api::RegisterUserResponse {
+                        user_id: user.user_id,
+                        email: user.email,
+                        key_id: user_key.key_id,
+                        created: user.created,
+                    };
+                    // make the new user's key usable right away
+                    cache.insert_key(user_key);
+                    Ok(warp::reply::with_status(warp::reply::json(&resp), http::StatusCode::CREATED))
+                }
+
+                Err(e) if fitbod::db::is_unique_violation(&e) => {
+                    Err(warp::reject::custom(ErrorMsg {
+                        status: 409,
+                        error: "email is already registered".to_string(),
+                    }))
+                }
+
+                Err(e) => {
+                    Err(warp::reject::custom(ErrorMsg {
+                        status: 500,
+                        error: format!("database error: {}", e),
+                    }))
+                }
+            }
+        });
+
+    let user_time_zone = api_routes.clone()
+        .and(warp::path("users"))
+        .and(warp::path("tz"))
+        .and(http_request())
+        .and_then(|cache: fitbod::cache::Cache, db: S, http_req| async move {
+            let req = match cache.parse_and_verify_http_request::<UserTimeZoneRequest>(&http_req) {
+                Ok(req) => req,
+                Err(e) => {
+                    return Err(warp::reject::custom(ErrorMsg {
+                        status: 400,
+                        error: format!("auth error: {:?}", e),
+                    }))
+                }
+            };
+
+            if let Some(tz) = req.tz {
+                match db.update_user_time_zone(&req.user_id, tz).await {
+                    Ok(true) => cache.set_time_zone(req.user_id, tz),
+
+                    Ok(false) => {
+                        return Err(warp::reject::custom(ErrorMsg {
+                            status: 404,
+                            error: format!("user not found: {}", req.user_id),
+                        }))
+                    }
 
                     Err(e) => {
-                        Err(warp::reject::custom(ErrorMsg {
-                            status: 400,
-                            error: format!("auth error: {:?}", e),
+                        return Err(warp::reject::custom(ErrorMsg {
+                            status: 500,
+                            error: format!("database error: {}", e),
                         }))
                     }
                 }
-            });
+            }
+
+            let resp = UserTimeZoneResponse {
+                user_id: req.user_id,
+                tz: cache.time_zone(&req.user_id),
+            };
+            Ok(warp::reply::json(&resp))
+        });
+
+    let new_key = api_routes.clone()
+        .and(warp::path("keys"))
+        .and(warp::path("new"))
+        .and(http_request())
+        .and_then(|cache: fitbod::cache::Cache, db: S, http_req| async move {
+            let req = match cache.parse_and_verify_http_request::<NewKeyRequest>(&http_req) {
+                Ok(req) => req,
+                Err(e) => {
+                    return Err(warp::reject::custom(ErrorMsg {
+                        status: 400,
+                        error: format!("auth error: {:?}", e),
+                    }))
+                }
+            };
+
+            let key: fitbod::auth::PublicKey = match base64::decode(&req.key).ok().and_then(|x| x.try_into().ok()) {
+                Some(key) => key,
+                None => {
+                    return Err(warp::reject::custom(ErrorMsg {
+                        status: 400,
+                        error: "key must be a base64-encoded 32-byte ed25519 public key".to_string(),
+                    }))
+                }
+            };
+
+            if req.expires.map(|t| t <= Utc::now()).unwrap_or(false) {
+                return Err(warp::reject::custom(ErrorMsg {
+                    status: 400,
+                    error: "expires must be in the future".to_string(),
+                }))
+            }
+
+            if cache.active_keys(&req.user_id).iter().any(|k| k.key == key) {
+                return Err(warp::reject::custom(ErrorMsg {
+                    status: 409,
+                    error: "key is already registered".to_string(),
+                }))
+            }
+
+            let user_key = fitbod::UserKey {
+                expires: req.expires,
+                ..fitbod::UserKey::new(req.user_id, key)
+            };
+
+            match db.insert_user_key(&user_key).await {
+                Ok(_) => {
+                    let resp = fitbod::api::KeyItem::from(&user_key);
+                    cache.insert_key(user_key);
+                    Ok(warp::reply::json(&resp))
+                }
+
+                // a revoked key stays in user_keys, so it can't be registered again
+                Err(e) if fitbod::db::is_unique_violation(&e) => {
+                    Err(warp::reject::custom(ErrorMsg {
+                        status: 409,
+                        error: "key is already registered (revoked keys can't be added again)".to_string(),
+                    }))
+                }
 
-        let base_ping = warp::get()
-            .and(warp::path("ping"));
+                Err(e) => {
+                    Err(warp::reject::custom(ErrorMsg {
+                        status: 500,
+                        error: format!("database error: {}", e),
+                    }))
+                }
+            }
+        });
+
+    let list_keys = api_routes.clone()
+        .and(warp::path("keys"))
+        .and(warp::path("list"))
+        .and(http_request())
+        .and_then(|cache: fitbod::cache::Cache, db: S, http_req| async move {
+            match cache.parse_and_verify_http_request::<ListKeysRequest>(&http_req) {
+                Ok(req) => {
+                    match db.fetch_keys_for_user(&req.user_id).await {
+                        Ok(keys) => {
+                            let items: Vec<_> = keys.iter()
+                                .map(fitbod::api::KeyItem::from)
+                                .collect();
+                            let resp = fitbod::api::ListKeysResponse {
+                                user_id: req.user_id,
+                                n_items: items.len(),
+                                items,
+                            };
+                            Ok(warp::reply::json(&resp))
+                        }
 
-        let api_ping = warp::get()
-            .and(warp::path("api"))
-            .and(warp::path("v1"))
-            .and(warp::path("ping"));
+                        Err(e) => {
+                            Err(warp::reject::custom(ErrorMsg {
+                                status: 500,
+                                error: format!("database error: {}", e),
+                            }))
+                        }
+                    }
+                }
 
-        let ping = base_ping.or(api_ping);
+                Err(e) => {
+                    Err(warp::reject::custom(ErrorMsg {
+                        status: 400,
+                        error: format!("auth error: {:?}", e),
+                    }))
+                }
+            }
+        });
+
+    let revoke_key = api_routes
+        .and(warp::path("keys"))
+        .and(warp::path("revoke"))
+        .and(http_request())
+        .and_then(|cache: fitbod::cache::Cache, db: S, http_req| async move {
+            let req = match cache.parse_and_verify_http_request::<RevokeKeyRequest>(&http_req) {
+                Ok(req) => req,
+                Err(e) => {
+                    return Err(warp::reject::custom(ErrorMsg {
+                        status: 400,
+                        error: format!("auth error: {:?}", e),
+                    }))
+                }
+            };
 
-        let god_mode_ping = ping.clone()
-            .and(warp::header::exact("x-fitbod-god-mode", "1"))
-            .map(|_| { 
-                "GOD MODE PONG!\n"
-            });
+            match db.revoke_user_key(&req.user_id, &req.key_id, Utc::now()).await {
+                Ok(KeyRevocation::Revoked) => {
+                    cache.remove_key(&req.user_id, &req.key_id);
+                    Ok(warp::reply::with_status(warp::reply::reply(), http::StatusCode::NO_CONTENT))
+                }
 
-        let ping = god_mode_ping.or(ping.map(|_| "pong\n"));
-          
-        let routes = list_workouts
-            .or(new_workouts)
-            .or(ping)
-            .recover(handle_rejection);
+                Ok(KeyRevocation::NotFound) => {
+                    Err(warp::reject::custom(ErrorMsg {
+                        status: 404,
+                        error: format!("key not found: {}", req.key_id),
+                    }))
+                }
 
-        warp::serve(routes).run(bind).await;
-    });
+                // revoking the last active key would lock the user out for good
+                Ok(KeyRevocation::OnlyActiveKey) => {
+                    Err(warp::reject::custom(ErrorMsg {
+                        status: 400,
+                        error: "cannot revoke the only active key".to_string(),
+                    }))
+                }
 
-    Ok(())
+                Err(e) => {
+                    Err(warp::reject::custom(ErrorMsg {
+                        status: 500,
+                        error: format!("database error: {}", e),
+                    }))
+                }
+            }
+        });
+
+    let base_ping = warp::get()
+        .and(warp::path("ping"));
+
+    let api_ping = warp::get()
+        .and(warp::path("api"))
+        .and(warp::path("v1"))
+        .and(warp::path("ping"));
+
+    let ping = base_ping.or(api_ping).map(|_| "pong\n");
+      
+    let routes = list_workouts
+        .or(new_workouts)
+        .or(get_workout)
+        .or(workout_stats)
+        .or(aggregate_workouts)
+        .or(update_workout)
+        .or(delete_workout)
+        .or(subscribe_events)
+        .or(events_ws)
+        .or(events_sse)
+        .or(events_sse_get)
+        .or(register_user)
+        .or(user_time_zone)
+        .or(new_key)
+        .or(list_keys)
+        .or(revoke_key)
+        .or(ping)
+        .recover(handle_rejection)
+        .map(|reply| {
+            warp::reply::with_header(reply, fitbod::api::SERVER_TIME_HEADER, Utc::now().timestamp().to_string())
+        });
+
+    warp::serve(routes).run(bind).await;
 }
 
 fn load_csv<T, P>(input_path: P) -> Vec<T>
@@ -377,6 +1210,7 @@ fn load_private_keys<P: AsRef<Path>>(input_path: P) -> Vec<LocalUserData> {
     load_csv(input_path)
 }
 
+#[allow(clippy::too_many_arguments)]
 fn list_workouts_request(
     users_csv_path: &Path,
     user_id: Option<Uuid>,
@@ -384,6 +1218,12 @@ fn list_workouts_request(
     start: Option<NaiveDate>,
     end: Option<NaiveDate>,
     limit: Option<usize>,
+    cursor: Option<fitbod::api::Cursor>,
+    order: Order,
+    mode: MatchMode,
+    tz: Option<chrono_tz::Tz>,
+    category: Option<fitbod::Category>,
+    tags: Vec<String>,
     curl: bool,
     host: String,
     addr: String,
@@ -405,19 +1245,27 @@ fn list_workouts_request(
     let key = as_priv_key(base64::decode(encoded_key).unwrap());
     let req = fitbod::api::ListWorkoutsRequest {
         user_id,
-        start: start.map(|dt| Utc.from_local_date(&dt).unwrap().and_hms(0, 0, 0)),
-        end: end.map(|dt| Utc.from_local_date(&dt).unwrap().and_hms(0, 0, 0)),
+        start: start.map(|dt| Utc.from_utc_datetime(&dt.and_hms_opt(0, 0, 0).unwrap())),
+        end: end.map(|dt| Utc.from_utc_datetime(&dt.and_hms_opt(0, 0, 0).unwrap())),
         limit,
+        order,
+        mode,
+        cursor,
+        tz,
+        category,
+        tags,
     };
     example_api_req("/api/v1/workouts/list", &req, &key, &host, curl, &addr);
 }
 
+#[allow(clippy::too_many_arguments)]
 fn new_workouts_request(
     users_csv_path: &Path,
     user_id: Option<Uuid>,
     email: Option<String>,
     dt: NaiveDate,
     duration: u32,
+    overlap: fitbod::api::OverlapPolicy,
     curl: bool,
     host: String,
     addr: String,
@@ -437,23 +1285,36 @@ fn new_workouts_request(
         .private_key
         .as_str();
     let key = as_priv_key(base64::decode(encoded_key).unwrap());
-    let start_time: DateTime<Utc> = Utc.from_local_date(&dt)
-        .unwrap()
-        .and_hms(14, 30, 0); // 6:30am PST
+    let start_time: DateTime<Utc> = Utc.from_utc_datetime(&dt.and_hms_opt(14, 30, 0).unwrap()); // 6:30am PST
     let end_time = start_time + chrono::Duration::minutes(duration as i64);
     let workout = fitbod::Workout {
         user_id,
         workout_id: Uuid::new_v4(),
         start_time,
         end_time,
+        exercises: Vec::new(),
+        category: None,
+        tags: Vec::new(),
+        note: None,
     };
     let req = fitbod::api::NewWorkoutsRequest {
         user_id,
         items: vec![workout],
+        overlap,
     };
     example_api_req("/api/v1/workouts/new", &req, &key, &host, curl, &addr);
 }
 
+fn register_user_request(email: String, curl: bool, host: String, addr: String) {
+    let (priv_key, pub_key) = fitbod::auth::gen_keypair();
+    eprintln!("private key: {}", base64::encode(&priv_key[..]));
+    let req = fitbod::api::RegisterUserRequest {
+        email,
+        key: base64::encode(&pub_key[..]),
+    };
+    example_api_req("/api/v1/users/register", &req, &priv_key, &host, curl, &addr);
+}
+
 fn as_priv_key<T: AsRef<[u8]>>(bytes: T) -> fitbod::auth::PrivateKey {
     bytes.as_ref().try_into().unwrap()
 }
@@ -463,11 +1324,21 @@ fn example_api_req<T>(path: &str, req: &T, key: &fitbod::auth::PrivateKey, host:
 {
     let req_json = serde_json::to_string(req).unwrap();
     let timestamp = Utc::now().timestamp();
-    let sig = fitbod::auth::sign_request(timestamp, &req_json, &key);
+    // curl sends the host (and port) of the url it connects to as the host header
+    let connect_uri: http::Uri = addr.parse().unwrap();
+    let signed_host = if curl {
+        connect_uri.authority().map(|x| x.as_str()).unwrap_or(host)
+    } else {
+        host
+    };
+    let sig = fitbod::auth::RequestSigner::new(timestamp, &req_json)
+        .path(path)
+        .host(signed_host)
+        .sign(key);
     let timestamp_str = timestamp.to_string();
 
     if curl {
-        println!("curl -H 'x-fitbod-access-signature: {sig}' -H 'x-fitbod-access-timestamp: {ts}' --data '{d}' {a}{p}",
+        println!("curl -H 'x-fitbod-access-scheme: v2' -H 'x-fitbod-access-signature: {sig}' -H 'x-fitbod-access-timestamp: {ts}' --data '{d}' {a}{p}",
             sig = sig,
             ts = timestamp_str,
             d = req_json,
@@ -495,29 +1366,60 @@ fn main() {
     assert_ne!(&db_url[..], "", "DATABASE_URL env var required");
 
     match Opt::from_args() {
-        Opt::Run { bind } => {
-            run(&db_url, bind).unwrap()
+        Opt::Run {
+            bind, max_clock_skew, max_seen_signatures, reject_v1_signatures,
+            enable_admin_access, admin_keys, admin_audit_log,
+        } => {
+            let auth_config = fitbod::cache::AuthConfig {
+                max_clock_skew,
+                max_seen_signatures,
+                allow_v1_signatures: ! reject_v1_signatures,
+            };
+            let admin = match (enable_admin_access, admin_keys, admin_audit_log) {
+                (true, Some(admin_keys), Some(admin_audit_log)) => {
+                    let records: Vec<fitbod::admin::AdminKeyRecord> = load_csv(admin_keys);
+                    let keys = fitbod::admin::AdminAccess::decode_keys(&records).unwrap();
+                    Some(fitbod::admin::AdminAccess::open(keys, admin_audit_log).unwrap())
+                }
+
+                _ => None,
+            };
+            if let Err(e) = run(&db_url, bind, auth_config, admin) {
+                eprintln!("error: {}", e);
+                std::process::exit(1);
+            }
+        }
+
+        Opt::Migrate { dry_run } => {
+            if let Err(e) = migrate(&db_url, dry_run) {
+                eprintln!("error: {}", e);
+                std::process::exit(1);
+            }
         }
 
         Opt::ListWorkoutsRequest {
             users_csv_path, user_id, email, start, end,
-            limit, host, curl, connect,
+            limit, cursor, order, mode, tz, category, tags, host, curl, connect,
         } => {
             assert!(users_csv_path.exists(), "path does not exist: {}", users_csv_path.display());
             list_workouts_request(
                 &users_csv_path, user_id, email, start,
-                end, limit, curl, host, connect,
+                end, limit, cursor, order, mode, tz, category, tags, curl, host, connect,
             );
         }
 
+        Opt::RegisterUserRequest { email, host, curl, connect } => {
+            register_user_request(email, curl, host, connect);
+        }
+
         Opt::NewWorkoutsRequest {
             users_csv_path, user_id, email, date: dt, duration,
-            host, curl, connect,
+            overlap, host, curl, connect,
         } => {
             assert!(users_csv_path.exists(), "path does not exist: {}", users_csv_path.display());
             new_workouts_request(
                 &users_csv_path, user_id, email, dt,
-                duration, curl, host, connect,
+                duration, overlap, curl, host, connect,
             );
         }
     }
@@ -0,0 +1,170 @@
+//! versioned schema migrations, embedded in the binary. applied by `fitbod-server migrate`
+//! (see `DataBase::migrate` and `SqliteDataBase::migrate`), and recorded in the `migrations`
+//! table, which `fitbod-server run` checks before starting
+
+use std::fmt;
+use std::str::FromStr;
+
+/// schema version, as recorded in `migrations.version`, e.g. "1.4.0"
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Version(pub u32, pub u32, pub u32);
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+impl FromStr for Version {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<u32> = s.split('.')
+            .map(|x| x.parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| format!("invalid version: '{}'", s))?;
+        match parts[..] {
+            [major, minor, patch] => Ok(Version(major, minor, patch)),
+            _ => Err(format!("invalid version: '{}'", s)),
+        }
+    }
+}
+
+pub struct Migration {
+    pub version: Version,
+    /// recorded in `migrations.descr`
+    pub descr: &'static str,
+    pub sql: &'static str,
+}
+
+/// migrations of the postgresql schema, oldest first. `sql/schema-postgresql.sql` is the
+/// schema after all of them
+pub const POSTGRESQL: &[Migration] = &[
+    Migration {
+        version: Version(1, 0, 0),
+        descr: "initial schema: users, workouts, and migrations tables; workout_durations view",
+        sql: include_str!("../sql/migrations/postgresql/1.0.0.sql"),
+    },
+    Migration {
+        version: Version(1, 1, 0),
+        descr: "user_keys table: multiple keys per user, with expiry and revocation",
+        sql: include_str!("../sql/migrations/postgresql/1.1.0.sql"),
+    },
+    Migration {
+        version: Version(1, 2, 0),
+        descr: "users.tz column: per-user time zone for workout dates",
+        sql: include_str!("../sql/migrations/postgresql/1.2.0.sql"),
+    },
+    Migration {
+        version: Version(1, 3, 0),
+        descr: "exercises and exercise_sets tables: optional exercises, sets, reps and load of workouts",
+        sql: include_str!("../sql/migrations/postgresql/1.3.0.sql"),
+    },
+    Migration {
+        version: Version(1, 4, 0),
+        descr: "workouts.category, workouts.tags and workouts.note columns",
+        sql: include_str!("../sql/migrations/postgresql/1.4.0.sql"),
+    },
+];
+
+/// migrations of the sqlite schema, oldest first. the first one creates the schema as of the
+/// matching postgresql version
+pub const SQLITE: &[Migration] = &[
+    Migration {
+        version: Version(1, 4, 0),
+        descr: "initial sqlite schema, matching postgresql schema 1.4.0",
+        sql: include_str!("../sql/schema-sqlite.sql"),
+    },
+];
+
+/// version of the schema after all of `migrations`
+pub fn latest(migrations: &[Migration]) -> Version {
+    migrations.last().expect("no migrations").version
+}
+
+/// migrations newer than `current`, which is `None` for an empty database
+pub fn pending(migrations: &[Migration], current: Option<Version>) -> &[Migration] {
+    let n_applied = migrations.iter().take_while(|m| Some(m.version) <= current).count();
+    &migrations[n_applied..]
+}
+
+/// highest of the versions recorded in the `migrations` table. unparseable versions are ignored
+pub fn current_version<I, S>(recorded: I) -> Option<Version>
+    where I: IntoIterator<Item = S>,
+          S: AsRef<str>
+{
+    recorded.into_iter().filter_map(|v| v.as_ref().parse().ok()).max()
+}
+
+/// the database schema is older than the binary expects
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutdatedSchema {
+    /// `None` if no migrations were applied
+    pub current: Option<Version>,
+    pub expected: Version,
+}
+
+impl fmt::Display for OutdatedSchema {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.current {
+            Some(v) => write!(f, "database schema version {} is older than {}", v, self.expected)?,
+            None => write!(f, "database has no schema, {} expected", self.expected)?,
+        }
+        write!(f, ". run `fitbod-server migrate` to upgrade it")
+    }
+}
+
+impl std::error::Error for OutdatedSchema {}
+
+/// error if `current` is older than the latest of `migrations`. a newer schema is accepted,
+/// so a previous binary can still run after a migration that only adds to the schema
+pub fn check_version(migrations: &[Migration], current: Option<Version>) -> Result<(), OutdatedSchema> {
+    let expected = latest(migrations);
+    if current.map(|v| v < expected).unwrap_or(true) {
+        return Err(OutdatedSchema { current, expected })
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pending_migrations_follow_recorded_version() {
+        assert!(POSTGRESQL.windows(2).all(|w| w[0].version < w[1].version));
+        assert!(SQLITE.windows(2).all(|w| w[0].version < w[1].version));
+        assert_eq!(latest(SQLITE), latest(POSTGRESQL));
+
+        assert_eq!("1.10.2".parse(), Ok(Version(1, 10, 2)));
+        assert!("1.2".parse::<Version>().is_err());
+        assert!("1.x.0".parse::<Version>().is_err());
+
+        let current = current_version(["1.0.0", "1.10.0", "1.2.0", "junk"]);
+        assert_eq!(current, Some(Version(1, 10, 0)));
+
+        let versions = |m: &[Migration]| m.iter().map(|m| m.version.to_string()).collect::<Vec<_>>();
+        assert_eq!(versions(pending(POSTGRESQL, None)).len(), POSTGRESQL.len());
+        assert_eq!(versions(pending(POSTGRESQL, Some(Version(1, 2, 0)))), vec!["1.3.0", "1.4.0"]);
+        assert!(pending(POSTGRESQL, Some(latest(POSTGRESQL))).is_empty());
+
+        assert!(check_version(POSTGRESQL, None).is_err());
+        assert_eq!(
+            check_version(POSTGRESQL, Some(Version(1, 2, 0))),
+            Err(OutdatedSchema { current: Some(Version(1, 2, 0)), expected: latest(POSTGRESQL) }),
+        );
+        assert_eq!(check_version(POSTGRESQL, Some(latest(POSTGRESQL))), Ok(()));
+        assert_eq!(check_version(POSTGRESQL, Some(Version(2, 0, 0))), Ok(()));
+    }
+
+    #[test]
+    fn full_schema_records_every_migration() {
+        let schema = include_str!("../sql/schema-postgresql.sql");
+        let recorded: Vec<&str> = schema.split("insert into migrations(version, descr) values (")
+            .skip(1)
+            .map(|x| x.trim_start().trim_start_matches('\'').split('\'').next().unwrap())
+            .collect();
+        let versions: Vec<String> = POSTGRESQL.iter().map(|m| m.version.to_string()).collect();
+        assert_eq!(recorded, versions);
+    }
+}
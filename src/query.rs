@@ -0,0 +1,351 @@
+use std::ops::Bound;
+use std::str::FromStr;
+use serde::{Serialize, Deserialize};
+use chrono::prelude::*;
+use crate::{Workout, Category};
+use crate::api::Cursor;
+
+pub type StartTimeBounds = (Bound<DateTime<Utc>>, Bound<DateTime<Utc>>);
+
+/// order of workouts in a listing, by `start_time` (ties broken by `workout_id`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Order {
+    /// oldest first
+    Asc,
+    /// newest first
+    #[default]
+    Desc,
+}
+
+impl FromStr for Order {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "asc" => Ok(Order::Asc),
+            "desc" => Ok(Order::Desc),
+            other => Err(format!("invalid order: {} (expected asc or desc)", other)),
+        }
+    }
+}
+
+/// how `start` and `end` of a listing select workouts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchMode {
+    /// `start_time` is in `start..end`
+    #[default]
+    StartsWithin,
+    /// `start_time` is before `end` and the workout is in progress at or after `start`, i.e.
+    /// it either starts at or after `start`, or ends after it
+    Overlaps,
+}
+
+impl FromStr for MatchMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "starts_within" => Ok(MatchMode::StartsWithin),
+            "overlaps" => Ok(MatchMode::Overlaps),
+            other => Err(format!("invalid mode: {} (expected starts_within or overlaps)", other)),
+        }
+    }
+}
+
+/// filters, order and paging of a workouts listing. the same query is applied to cached and
+/// db workouts, so both return the same results
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct WorkoutQuery {
+    pub start: Option<DateTime<Utc>>,
+    pub end: Option<DateTime<Utc>>,
+    pub order: Order,
+    pub mode: MatchMode,
+    pub limit: Option<usize>,
+    /// only workouts after this position (in `order`) are returned
+    pub cursor: Option<Cursor>,
+    /// only workouts in this category
+    pub category: Option<Category>,
+    /// only workouts with all of these tags
+    pub tags: Vec<String>,
+}
+
+impl WorkoutQuery {
+    /// whether `workout` is in the listing, disregarding `limit`
+    pub fn matches(&self, workout: &Workout) -> bool {
+        let in_range = match self.mode {
+            MatchMode::StartsWithin => {
+                self.start.map(|t| workout.start_time >= t).unwrap_or(true)
+                && self.end.map(|t| workout.start_time < t).unwrap_or(true)
+            }
+
+            MatchMode::Overlaps => {
+                self.start.map(|t| workout.start_time >= t || workout.end_time > t).unwrap_or(true)
+                && self.end.map(|t| workout.start_time < t).unwrap_or(true)
+            }
+        };
+
+        in_range && self.is_past_cursor(workout) && self.matches_labels(workout)
+    }
+
+    fn matches_labels(&self, workout: &Workout) -> bool {
+        self.category.map(|c| workout.category == Some(c)).unwrap_or(true)
+        && self.tags.iter().all(|t| workout.tags.contains(t))
+    }
+
+    fn is_past_cursor(&self, workout: &Workout) -> bool {
+        match (self.cursor, self.order) {
+            (None, _) => true,
+            (Some(c), Order::Desc) => c.is_before(workout),
+            (Some(c), Order::Asc) => c.is_after(workout),
+        }
+    }
+
+    /// bounds on `start_time` of any matching workout, used to skip non-matching workouts in
+    /// an index ordered by `start_time`. `None` if no workout can match
+    pub fn start_time_bounds(&self) -> Option<StartTimeBounds> {
+        let mut lower = match self.mode {
+            MatchMode::StartsWithin => self.start,
+            // a workout that started long before `start` may still be in progress
+            MatchMode::Overlaps => None,
+        };
+        let mut upper = self.end.map(Bound::Excluded).unwrap_or(Bound::Unbounded);
+
+        match (self.cursor, self.order) {
+            (Some(c), Order::Asc) => {
+                lower = Some(lower.map(|t| t.max(c.start_time)).unwrap_or(c.start_time));
+            }
+
+            (Some(c), Order::Desc) => {
+                if self.end.map(|t| c.start_time < t).unwrap_or(true) {
+                    upper = Bound::Included(c.start_time);
+                }
+            }
+
+            (None, _) => {}
+        }
+
+        let empty = match (lower, upper) {
+            (Some(l), Bound::Included(u)) => u < l,
+            (Some(l), Bound::Excluded(u)) => u <= l,
+            _ => false,
+        };
+        if empty {
+            return None
+        }
+
+        Some((lower.map(Bound::Included).unwrap_or(Bound::Unbounded), upper))
+    }
+
+    /// apply the query to `workouts`, which must be in ascending order by
+    /// (`start_time`, `workout_id`)
+    pub fn select<'a, I>(&self, workouts: I) -> Vec<Workout>
+        where I: DoubleEndedIterator<Item = &'a Workout>
+    {
+        let limit = self.limit.unwrap_or(usize::MAX);
+        let matching = workouts.filter(|w| self.matches(w));
+        match self.order {
+            Order::Asc => matching.take(limit).cloned().collect(),
+            Order::Desc => matching.rev().take(limit).cloned().collect(),
+        }
+    }
+
+    /// apply the query to `workouts` in any order, e.g. as fetched from the db
+    pub fn select_unsorted(&self, mut workouts: Vec<Workout>) -> Vec<Workout> {
+        workouts.sort_unstable_by_key(|w| (w.start_time, w.workout_id));
+        self.select(workouts.iter())
+    }
+}
+
+#[cfg(test)]
+#[allow(unused)]
+mod tests {
+    use super::*;
+    use rand::seq::SliceRandom;
+    use uuid::Uuid;
+    use crate::cache::Cache;
+    use crate::api::ListWorkoutsResponse;
+
+    /// workouts every 3 hours lasting 1-5 hours, so many overlap the boundaries used below.
+    /// categories cycle through strength, cardio and none; every other workout is tagged
+    /// "morning" and every fifth "pr"
+    fn example_workouts(user_id: Uuid) -> Vec<Workout> {
+        let t0 = Utc.with_ymd_and_hms(2021, 7, 27, 6, 30, 0).unwrap();
+        (0..30)
+            .map(|i| {
+                let start_time = t0 + chrono::Duration::hours(i * 3);
+                let end_time = start_time + chrono::Duration::minutes(60 + (i % 5) * 60);
+                let category = [Some(Category::Strength), Some(Category::Cardio), None][i as usize % 3];
+                let tags = [(2, "morning"), (5, "pr")].iter()
+                    .filter(|(n, _)| i % n == 0)
+                    .map(|(_, tag)| tag.to_string())
+                    .collect();
+                Workout { user_id, workout_id: Uuid::new_v4(), start_time, end_time, exercises: Vec::new(), category, tags, note: None }
+            }).collect()
+    }
+
+    /// what the list handler does when the user isn't cached: apply the query to db rows,
+    /// which come back in no particular order
+    fn select_from_db(query: &WorkoutQuery, workouts: &[Workout]) -> Vec<Workout> {
+        let mut rows = workouts.to_vec();
+        rows.shuffle(&mut rand::thread_rng());
+        query.select_unsorted(rows)
+    }
+
+    #[test]
+    fn cache_and_db_paths_return_identical_results() {
+        let cache = Cache::default();
+        let user_id = Uuid::new_v4();
+        let workouts = example_workouts(user_id);
+        cache.try_cache_workouts(user_id, &workouts);
+
+        let mut times: Vec<Option<DateTime<Utc>>> = vec![None];
+        for w in workouts.iter().step_by(7) {
+            times.push(Some(w.start_time));
+            times.push(Some(w.end_time));
+            times.push(Some(w.start_time + chrono::Duration::minutes(30)));
+        }
+        let cursors: Vec<Option<Cursor>> = std::iter::once(None)
+            .chain(workouts.iter().step_by(4).map(|w| Some(Cursor::from(w))))
+            .collect();
+
+        let labels: Vec<(Option<Category>, Vec<String>)> = vec![
+            (None, vec![]),
+            (Some(Category::Strength), vec![]),
+            (None, vec!["morning".to_string()]),
+            (Some(Category::Cardio), vec!["morning".to_string(), "pr".to_string()]),
+        ];
+
+        let mut n_checked = 0;
+        for &start in &times {
+            for &end in &times {
+                for &order in &[Order::Asc, Order::Desc] {
+                    for &mode in &[MatchMode::StartsWithin, MatchMode::Overlaps] {
+                        for &limit in &[None, Some(0), Some(3)] {
+                            for &cursor in &cursors {
+                                for (category, tags) in &labels {
+                                    let query = WorkoutQuery {
+                                        start, end, order, mode, limit, cursor,
+                                        category: *category,
+                                        tags: tags.clone(),
+                                    };
+                                    let cached = cache.get_cached_workouts(&user_id, &query).unwrap();
+                                    assert_eq!(cached, select_from_db(&query, &workouts), "{:?}", query);
+                                    n_checked += 1;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        assert!(n_checked > 10_000);
+    }
+
+    #[test]
+    fn category_and_tag_filters_apply_before_limit() {
+        let cache = Cache::default();
+        let user_id = Uuid::new_v4();
+        let workouts = example_workouts(user_id);
+        cache.try_cache_workouts(user_id, &workouts);
+
+        let query = WorkoutQuery {
+            order: Order::Asc,
+            limit: Some(2),
+            category: Some(Category::Strength),
+            tags: vec!["morning".to_string()],
+            ..Default::default()
+        };
+        // strength is every third workout, morning every other: 0, 6, 12, ...
+        let expected = vec![workouts[0].clone(), workouts[6].clone()];
+        assert_eq!(cache.get_cached_workouts(&user_id, &query).unwrap(), expected);
+        assert_eq!(select_from_db(&query, &workouts), expected);
+
+        let query = WorkoutQuery { tags: vec!["morning".to_string(), "pr".to_string()], limit: None, ..query };
+        // of the 30 workouts, only the first is a multiple of 2, 3 and 5
+        let expected = vec![workouts[0].clone()];
+        assert_eq!(cache.get_cached_workouts(&user_id, &query).unwrap(), expected);
+        assert_eq!(select_from_db(&query, &workouts), expected);
+
+        let query = WorkoutQuery { tags: vec!["evening".to_string()], ..query };
+        assert!(cache.get_cached_workouts(&user_id, &query).unwrap().is_empty());
+    }
+
+    #[test]
+    fn workouts_straddling_a_boundary_are_matched_by_mode() {
+        let cache = Cache::default();
+        let user_id = Uuid::new_v4();
+        let workouts = example_workouts(user_id);
+        cache.try_cache_workouts(user_id, &workouts);
+
+        // workouts[4] runs for 5 hours, past the start of workouts[5]
+        let start = workouts[5].start_time;
+        let end = workouts[4].start_time + chrono::Duration::hours(1);
+        assert!(workouts[4].end_time > start);
+
+        // workouts[4] straddles `end` and is included either way. workouts[3] is still in
+        // progress at `start`, so only overlaps includes it
+        let query = WorkoutQuery { start: Some(workouts[4].start_time), end: Some(end), ..Default::default() };
+        assert_eq!(cache.get_cached_workouts(&user_id, &query).unwrap(), vec![workouts[4].clone()]);
+        assert_eq!(select_from_db(&query, &workouts), vec![workouts[4].clone()]);
+
+        let query = WorkoutQuery { mode: MatchMode::Overlaps, ..query };
+        let expected = vec![workouts[4].clone(), workouts[3].clone()];
+        assert_eq!(cache.get_cached_workouts(&user_id, &query).unwrap(), expected);
+        assert_eq!(select_from_db(&query, &workouts), expected);
+
+        let query = WorkoutQuery { start: Some(start), end: Some(workouts[6].start_time), ..Default::default() };
+        assert_eq!(cache.get_cached_workouts(&user_id, &query).unwrap(), vec![workouts[5].clone()]);
+        assert_eq!(select_from_db(&query, &workouts), vec![workouts[5].clone()]);
+
+        let query = WorkoutQuery { mode: MatchMode::Overlaps, ..query };
+        let expected = vec![workouts[5].clone(), workouts[4].clone()];
+        assert_eq!(cache.get_cached_workouts(&user_id, &query).unwrap(), expected);
+        assert_eq!(select_from_db(&query, &workouts), expected);
+
+        let query = WorkoutQuery { order: Order::Asc, ..query };
+        let expected = vec![workouts[4].clone(), workouts[5].clone()];
+        assert_eq!(cache.get_cached_workouts(&user_id, &query).unwrap(), expected);
+        assert_eq!(select_from_db(&query, &workouts), expected);
+    }
+
+    #[test]
+    fn cursor_pages_follow_order_on_both_paths() {
+        let cache = Cache::default();
+        let user_id = Uuid::new_v4();
+        let workouts = example_workouts(user_id);
+        cache.try_cache_workouts(user_id, &workouts);
+
+        let limit = 4;
+        for &order in &[Order::Asc, Order::Desc] {
+            let base = WorkoutQuery {
+                start: Some(workouts[3].start_time + chrono::Duration::minutes(30)),
+                end: Some(workouts[25].start_time),
+                order,
+                mode: MatchMode::Overlaps,
+                ..Default::default()
+            };
+            let all = cache.get_cached_workouts(&user_id, &base).unwrap();
+            assert_eq!(all.first(), Some(if order == Order::Asc { &workouts[3] } else { &workouts[24] }));
+
+            let mut cached_pages = Vec::new();
+            let mut db_pages = Vec::new();
+            let mut cursor = None;
+            loop {
+                let query = WorkoutQuery { limit: Some(limit + 1), cursor, ..base.clone() };
+                let page = cache.get_cached_workouts(&user_id, &query).unwrap();
+                assert_eq!(page, select_from_db(&query, &workouts));
+                let resp = ListWorkoutsResponse::new(user_id, page.clone(), Some(limit), chrono_tz::UTC);
+                cached_pages.extend(page.iter().take(limit).cloned());
+                db_pages.extend(select_from_db(&query, &workouts).into_iter().take(limit));
+                match resp.cursor {
+                    Some(next) => cursor = Some(next),
+                    None => break,
+                }
+            }
+            assert_eq!(cached_pages, all);
+            assert_eq!(db_pages, all);
+        }
+    }
+}
@@ -0,0 +1,660 @@
+//! sqlite implementation of `Storage`, for small self-hosted deployments and local development.
+//! selected by a `sqlite:` database url, e.g. `sqlite://var/fitbod.db`
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::str::FromStr;
+use sqlx::{Pool, Executor, Transaction};
+use sqlx::sqlite::{Sqlite, SqliteConnection, SqliteConnectOptions, SqlitePoolOptions};
+use chrono::prelude::*;
+use chrono_tz::Tz;
+use uuid::Uuid;
+use crate::auth::PublicKey;
+use crate::db::{KeyRevocation, Storage, StorageFuture};
+use crate::migrate::{self, Version};
+use crate::{Workout, Exercise, ExerciseSet, User, UserKey};
+
+/// prefix of database urls that select `SqliteDataBase`
+pub const URL_PREFIX: &str = "sqlite:";
+
+/// wrapper around sqlite connection pool. stores the same data as `DataBase`, using the
+/// column types of `sql/schema-sqlite.sql`
+#[derive(Clone)]
+pub struct SqliteDataBase {
+    pool: Pool<Sqlite>,
+}
+
+impl SqliteDataBase {
+    /// opens the database. a new or empty database is migrated to the latest schema right away,
+    /// an existing one is left for `migrate`.
+    ///
+    /// an in-memory database (e.g. "sqlite::memory:") only exists for the connection that opened
+    /// it, so its pool is kept to one connection that is never closed
+    pub async fn new(database_url: &str) -> Result<Self, sqlx::Error> {
+        let options = SqliteConnectOptions::from_str(database_url)?
+            .create_if_missing(true);
+        let pool_options = if database_url.contains(":memory:") || database_url.contains("mode=memory") {
+            SqlitePoolOptions::new()
+                .max_connections(1)
+                .idle_timeout(None)
+                .max_lifetime(None)
+        } else {
+            SqlitePoolOptions::new()
+        };
+        let pool = pool_options.connect_with(options).await?;
+        let db = Self { pool };
+        if db.schema_version().await?.is_none() {
+            db.migrate().await?;
+        }
+        Ok(db)
+    }
+
+    /// version of the schema, from the `migrations` table. `None` if there is no such table
+    pub async fn schema_version(&self) -> Result<Option<Version>, sqlx::Error> {
+        let mut conn = self.pool.acquire().await?;
+        schema_version(&mut conn).await
+    }
+
+    /// apply pending `migrate::SQLITE` migrations, each in its own transaction with its record
+    /// in the `migrations` table. returns the versions applied
+    pub async fn migrate(&self) -> Result<Vec<Version>, sqlx::Error> {
+        let mut applied = Vec::new();
+        loop {
+            let mut tx = self.pool.begin().await?;
+
+            let current = schema_version(&mut tx).await?;
+            let migration = match migrate::pending(migrate::SQLITE, current).first() {
+                Some(m) => m,
+                None => return Ok(applied),
+            };
+
+            tx.execute(migration.sql).await?;
+            tx.execute(
+                sqlx::query("insert into migrations (version, descr) values (?1, ?2)")
+                    .bind(migration.version.to_string())
+                    .bind(migration.descr)
+            ).await?;
+
+            tx.commit().await?;
+
+            applied.push(migration.version);
+        }
+    }
+
+    pub async fn fetch_user_keys(&self) -> Result<Vec<UserKey>, sqlx::Error> {
+        let rows: Vec<UserKeyRow> = sqlx::query_as(
+                "select key_id, user_id, key, created, expires, revoked \
+                 from user_keys \
+                 where revoked is null \
+                   and (expires is null or expires > ?1)")
+            .bind(to_micros(Utc::now()))
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.into_iter().map(user_key_from_row).collect())
+    }
+
+    pub async fn fetch_keys_for_user(&self, user_id: &Uuid) -> Result<Vec<UserKey>, sqlx::Error> {
+        let rows: Vec<UserKeyRow> = sqlx::query_as(
+                "select key_id, user_id, key, created, expires, revoked \
+                 from user_keys \
+                 where user_id = ?1 \
+                 order by created")
+            .bind(user_id)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.into_iter().map(user_key_from_row).collect())
+    }
+
+    pub async fn insert_user_key(&self, k: &UserKey) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "insert into user_keys (key_id, user_id, key, created, expires, revoked) \
+             values (?1, ?2, ?3, ?4, ?5, ?6)"
+        )
+            .bind(k.key_id)
+            .bind(k.user_id)
+            .bind(&k.key[..])
+            .bind(to_micros(k.created))
+            .bind(k.expires.map(to_micros))
+            .bind(k.revoked.map(to_micros))
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// see `DataBase::revoke_user_key`. sqlite runs one write statement at a time, so checking
+    /// for other active keys in the update is enough to make it atomic
+    pub async fn revoke_user_key(&self, user_id: &Uuid, key_id: &Uuid, revoked: DateTime<Utc>) -> Result<KeyRevocation, sqlx::Error> {
+        let result = sqlx::query(
+            "update user_keys set revoked = ?3 \
+             where user_id = ?1 and key_id = ?2 and revoked is null \
+               and ((expires is not null and expires <= ?3) or exists ( \
+                 select 1 from user_keys o \
+                 where o.user_id = ?1 and o.key_id <> ?2 and o.revoked is null \
+                   and (o.expires is null or o.expires > ?3)))"
+        )
+            .bind(user_id)
+            .bind(key_id)
+            .bind(to_micros(revoked))
+            .execute(&self.pool)
+            .await?;
+        if result.rows_affected() > 0 {
+            return Ok(KeyRevocation::Revoked)
+        }
+
+        let (n,): (i64,) = sqlx::query_as(
+                "select count(*) from user_keys where user_id = ?1 and key_id = ?2 and revoked is null")
+            .bind(user_id)
+            .bind(key_id)
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(if n > 0 { KeyRevocation::OnlyActiveKey } else { KeyRevocation::NotFound })
+    }
+
+    pub async fn fetch_user_time_zones(&self) -> Result<Vec<(Uuid, Tz)>, sqlx::Error> {
+        let rows: Vec<(Uuid, String)> = sqlx::query_as(
+                "select user_id, tz from users where tz <> 'UTC'")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.into_iter().filter_map(|(user_id, tz)| Some((user_id, tz.parse().ok()?))).collect())
+    }
+
+    pub async fn update_user_time_zone(&self, user_id: &Uuid, tz: Tz) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            "update users set tz = ?2 where user_id = ?1"
+        )
+            .bind(user_id)
+            .bind(tz.name())
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn fetch_recently_active_user_workouts(&self) -> Result<Vec<Workout>, sqlx::Error> {
+        let since = to_micros(Utc::now() - chrono::Duration::days(7));
+        self.fetch_workouts(
+            "w.user_id in (select distinct(user_id) from workouts where start_time >= ?1)",
+            |q| q.bind(since),
+        ).await
+    }
+
+    pub async fn fetch_user_workouts(&self, user_id: &Uuid) -> Result<Vec<Workout>, sqlx::Error> {
+        self.fetch_workouts("w.user_id = ?1", |q| q.bind(*user_id)).await
+    }
+
+    pub async fn fetch_workout(&self, user_id: &Uuid, workout_id: &Uuid) -> Result<Option<Workout>, sqlx::Error> {
+        let mut workouts = self.fetch_workouts(
+            "w.user_id = ?1 and w.workout_id = ?2",
+            |q| q.bind(*user_id).bind(*workout_id),
+        ).await?;
+        Ok(workouts.pop())
+    }
+
+    /// workouts matching `filter`, a condition on `workouts w` whose parameters are bound by
+    /// `bind`, with their exercises. sqlite has no arrays to pass the workout ids of the first
+    /// query to the next ones, so each query applies `filter` again, in one transaction
+    async fn fetch_workouts<F>(&self, filter: &str, bind: F) -> Result<Vec<Workout>, sqlx::Error>
+        where F: for<'q> Fn(Query<'q>) -> Query<'q>
+    {
+        let mut tx = self.pool.begin().await?;
+
+        let workout_sql = format!(
+            "select w.user_id, w.workout_id, w.start_time, w.end_time, w.category, w.tags, w.note \
+             from workouts w \
+             where {}", filter);
+        let workout_rows: Vec<WorkoutRow> = bind(sqlx::query(&workout_sql))
+            .try_map(|row| sqlx::FromRow::from_row(&row))
+            .fetch_all(&mut tx)
+            .await?;
+        let mut workouts: Vec<Workout> = workout_rows.into_iter().map(workout_from_row).collect();
+        if workouts.is_empty() {
+            return Ok(workouts)
+        }
+
+        let exercise_sql = format!(
+            "select e.workout_id, e.name \
+             from exercises e \
+             inner join workouts w on w.workout_id = e.workout_id \
+             where {} \
+             order by e.workout_id, e.position", filter);
+        let exercise_rows: Vec<(Uuid, String)> = bind(sqlx::query(&exercise_sql))
+            .try_map(|row| sqlx::FromRow::from_row(&row))
+            .fetch_all(&mut tx)
+            .await?;
+        if exercise_rows.is_empty() {
+            return Ok(workouts)
+        }
+
+        let set_sql = format!(
+            "select s.workout_id, s.exercise_position, s.reps, s.weight, s.unit, s.rpe \
+             from exercise_sets s \
+             inner join workouts w on w.workout_id = s.workout_id \
+             where {} \
+             order by s.workout_id, s.exercise_position, s.position", filter);
+        let set_rows: Vec<ExerciseSetRow> = bind(sqlx::query(&set_sql))
+            .try_map(|row| sqlx::FromRow::from_row(&row))
+            .fetch_all(&mut tx)
+            .await?;
+
+        tx.commit().await?;
+
+        let mut exercises: HashMap<Uuid, Vec<Exercise>> = HashMap::new();
+        for (workout_id, name) in exercise_rows {
+            exercises.entry(workout_id).or_default().push(Exercise { name, sets: Vec::new() });
+        }
+        // positions are contiguous from 0, as written by `insert_exercises`
+        for (workout_id, exercise_position, reps, weight, unit, rpe) in set_rows {
+            let exercise = exercises.get_mut(&workout_id)
+                .and_then(|x| x.get_mut(exercise_position as usize));
+            if let Some(exercise) = exercise {
+                let unit = unit.parse().unwrap_or_default();
+                exercise.sets.push(ExerciseSet { reps: reps as u32, weight, unit, rpe: rpe.map(|x| x as f32) });
+            }
+        }
+        for workout in workouts.iter_mut() {
+            if let Some(x) = exercises.remove(&workout.workout_id) {
+                workout.exercises = x;
+            }
+        }
+
+        Ok(workouts)
+    }
+
+    pub async fn save_new_workouts(&self, inserted: &[Workout], merged: &[Workout]) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        for w in inserted {
+            insert_workout(&mut tx, w).await?;
+        }
+
+        for w in merged {
+            if ! update_workout(&mut tx, w).await? {
+                return Err(sqlx::Error::RowNotFound)
+            }
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    pub async fn update_workout(&self, workout: &Workout) -> Result<bool, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        if ! update_workout(&mut tx, workout).await? {
+            return Ok(false)
+        }
+
+        tx.commit().await?;
+
+        Ok(true)
+    }
+
+    pub async fn delete_workout(&self, user_id: &Uuid, workout_id: &Uuid) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            "delete from workouts where user_id = ?1 and workout_id = ?2"
+        )
+            .bind(user_id)
+            .bind(workout_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn insert_user(&self, user: &User, key: &UserKey) -> Result<(), sqlx::Error> {
+        debug_assert_eq!(user.user_id, key.user_id);
+        debug_assert_eq!(user.key, key.key);
+
+        let mut tx = self.pool.begin().await?;
+
+        tx.execute(
+            sqlx::query(
+                "insert into users (user_id, email, key, created) values (?1, ?2, ?3, ?4)"
+            )
+                .bind(user.user_id)
+                .bind(&user.email[..])
+                .bind(&user.key[..])
+                .bind(to_micros(user.created))
+        ).await?;
+
+        tx.execute(
+            sqlx::query(
+                "insert into user_keys (key_id, user_id, key, created, expires) values (?1, ?2, ?3, ?4, ?5)"
+            )
+                .bind(key.key_id)
+                .bind(key.user_id)
+                .bind(&key.key[..])
+                .bind(to_micros(key.created))
+                .bind(key.expires.map(to_micros))
+        ).await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+}
+
+impl Storage for SqliteDataBase {
+    fn fetch_user_keys(&self) -> StorageFuture<'_, Vec<UserKey>> {
+        Box::pin(SqliteDataBase::fetch_user_keys(self))
+    }
+
+    fn fetch_keys_for_user<'a>(&'a self, user_id: &'a Uuid) -> StorageFuture<'a, Vec<UserKey>> {
+        Box::pin(SqliteDataBase::fetch_keys_for_user(self, user_id))
+    }
+
+    fn insert_user_key<'a>(&'a self, k: &'a UserKey) -> StorageFuture<'a, ()> {
+        Box::pin(SqliteDataBase::insert_user_key(self, k))
+    }
+
+    fn revoke_user_key<'a>(&'a self, user_id: &'a Uuid, key_id: &'a Uuid, revoked: DateTime<Utc>) -> StorageFuture<'a, KeyRevocation> {
+        Box::pin(SqliteDataBase::revoke_user_key(self, user_id, key_id, revoked))
+    }
+
+    fn fetch_user_time_zones(&self) -> StorageFuture<'_, Vec<(Uuid, Tz)>> {
+        Box::pin(SqliteDataBase::fetch_user_time_zones(self))
+    }
+
+    fn update_user_time_zone<'a>(&'a self, user_id: &'a Uuid, tz: Tz) -> StorageFuture<'a, bool> {
+        Box::pin(SqliteDataBase::update_user_time_zone(self, user_id, tz))
+    }
+
+    fn fetch_recently_active_user_workouts(&self) -> StorageFuture<'_, Vec<Workout>> {
+        Box::pin(SqliteDataBase::fetch_recently_active_user_workouts(self))
+    }
+
+    fn fetch_user_workouts<'a>(&'a self, user_id: &'a Uuid) -> StorageFuture<'a, Vec<Workout>> {
+        Box::pin(SqliteDataBase::fetch_user_workouts(self, user_id))
+    }
+
+    fn fetch_workout<'a>(&'a self, user_id: &'a Uuid, workout_id: &'a Uuid) -> StorageFuture<'a, Option<Workout>> {
+        Box::pin(SqliteDataBase::fetch_workout(self, user_id, workout_id))
+    }
+
+    fn save_new_workouts<'a>(&'a self, inserted: &'a [Workout], merged: &'a [Workout]) -> StorageFuture<'a, ()> {
+        Box::pin(SqliteDataBase::save_new_workouts(self, inserted, merged))
+    }
+
+    fn update_workout<'a>(&'a self, workout: &'a Workout) -> StorageFuture<'a, bool> {
+        Box::pin(SqliteDataBase::update_workout(self, workout))
+    }
+
+    fn delete_workout<'a>(&'a self, user_id: &'a Uuid, workout_id: &'a Uuid) -> StorageFuture<'a, bool> {
+        Box::pin(SqliteDataBase::delete_workout(self, user_id, workout_id))
+    }
+
+    fn insert_user<'a>(&'a self, user: &'a User, key: &'a UserKey) -> StorageFuture<'a, ()> {
+        Box::pin(SqliteDataBase::insert_user(self, user, key))
+    }
+}
+
+/// see `SqliteDataBase::schema_version`
+async fn schema_version(conn: &mut SqliteConnection) -> Result<Option<Version>, sqlx::Error> {
+    let (n_tables,): (i64,) = sqlx::query_as(
+            "select count(*) from sqlite_master where type = 'table' and name = 'migrations'")
+        .fetch_one(&mut *conn)
+        .await?;
+    if n_tables == 0 {
+        return Ok(None)
+    }
+    let rows: Vec<(String,)> = sqlx::query_as("select version from migrations")
+        .fetch_all(&mut *conn)
+        .await?;
+    Ok(migrate::current_version(rows.into_iter().map(|(version,)| version)))
+}
+
+type Query<'q> = sqlx::query::Query<'q, Sqlite, sqlx::sqlite::SqliteArguments<'q>>;
+
+async fn insert_workout(tx: &mut Transaction<'_, Sqlite>, w: &Workout) -> Result<(), sqlx::Error> {
+    tx.execute(
+        sqlx::query(
+            "insert into workouts (user_id, workout_id, start_time, end_time, category, tags, note) \
+             values (?1, ?2, ?3, ?4, ?5, ?6, ?7)"
+        )
+            .bind(w.user_id)
+            .bind(w.workout_id)
+            .bind(to_micros(w.start_time))
+            .bind(to_micros(w.end_time))
+            .bind(w.category.map(|c| c.as_str()))
+            .bind(tags_to_json(&w.tags))
+            .bind(w.note.as_deref())
+    ).await?;
+
+    insert_exercises(tx, w).await
+}
+
+/// see `DataBase::update_workout`
+async fn update_workout(tx: &mut Transaction<'_, Sqlite>, w: &Workout) -> Result<bool, sqlx::Error> {
+    let result = tx.execute(
+        sqlx::query(
+            "update workouts set start_time = ?3, end_time = ?4, category = ?5, tags = ?6, note = ?7 \
+             where user_id = ?1 and workout_id = ?2"
+        )
+            .bind(w.user_id)
+            .bind(w.workout_id)
+            .bind(to_micros(w.start_time))
+            .bind(to_micros(w.end_time))
+            .bind(w.category.map(|c| c.as_str()))
+            .bind(tags_to_json(&w.tags))
+            .bind(w.note.as_deref())
+    ).await?;
+    if result.rows_affected() == 0 {
+        return Ok(false)
+    }
+
+    // sets are deleted with their exercises
+    tx.execute(
+        sqlx::query("delete from exercises where workout_id = ?1").bind(w.workout_id)
+    ).await?;
+    insert_exercises(tx, w).await?;
+
+    Ok(true)
+}
+
+/// insert exercises and sets of `w`, numbering them in order from 0
+async fn insert_exercises(tx: &mut Transaction<'_, Sqlite>, w: &Workout) -> Result<(), sqlx::Error> {
+    for (i, exercise) in w.exercises.iter().enumerate() {
+        tx.execute(
+            sqlx::query(
+                "insert into exercises (workout_id, position, name) values (?1, ?2, ?3)"
+            )
+                .bind(w.workout_id)
+                .bind(i as i64)
+                .bind(&exercise.name[..])
+        ).await?;
+
+        for (j, set) in exercise.sets.iter().enumerate() {
+            tx.execute(
+                sqlx::query(
+                    "insert into exercise_sets (workout_id, exercise_position, position, reps, weight, unit, rpe) \
+                     values (?1, ?2, ?3, ?4, ?5, ?6, ?7)"
+                )
+                    .bind(w.workout_id)
+                    .bind(i as i64)
+                    .bind(j as i64)
+                    .bind(set.reps as i64)
+                    .bind(set.weight)
+                    .bind(set.unit.as_str())
+                    .bind(set.rpe.map(f64::from))
+            ).await?;
+        }
+    }
+
+    Ok(())
+}
+
+fn to_micros(t: DateTime<Utc>) -> i64 {
+    t.timestamp_micros()
+}
+
+fn from_micros(micros: i64) -> DateTime<Utc> {
+    DateTime::from_timestamp_micros(micros).expect("timestamp out of range")
+}
+
+fn tags_to_json(tags: &[String]) -> String {
+    serde_json::to_string(tags).expect("failed to serialize tags")
+}
+
+/// (user_id, workout_id, start_time, end_time, category, tags, note)
+type WorkoutRow = (Uuid, Uuid, i64, i64, Option<String>, String, Option<String>);
+
+/// exercises are filled in separately, by `SqliteDataBase::fetch_workouts`. an unrecognized
+/// category is treated as no category, and tags that aren't a json array of strings as no tags
+fn workout_from_row((user_id, workout_id, start_time, end_time, category, tags, note): WorkoutRow) -> Workout {
+    let category = category.and_then(|c| c.parse().ok());
+    let tags = serde_json::from_str(&tags).unwrap_or_default();
+    Workout {
+        user_id,
+        workout_id,
+        start_time: from_micros(start_time),
+        end_time: from_micros(end_time),
+        exercises: Vec::new(),
+        category,
+        tags,
+        note,
+    }
+}
+
+/// (workout_id, exercise_position, reps, weight, unit, rpe)
+type ExerciseSetRow = (Uuid, i64, i64, Option<f64>, String, Option<f64>);
+
+type UserKeyRow = (Uuid, Uuid, Vec<u8>, i64, Option<i64>, Option<i64>);
+
+fn user_key_from_row((key_id, user_id, key, created, expires, revoked): UserKeyRow) -> UserKey {
+    let key: PublicKey = key.try_into().expect("failed to convert Vec<u8> to PublicKey");
+    UserKey {
+        key_id,
+        user_id,
+        key,
+        created: from_micros(created),
+        expires: expires.map(from_micros),
+        revoked: revoked.map(from_micros),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::SubsecRound;
+    use crate::{Category, WeightUnit};
+    use crate::db::is_unique_violation;
+
+    /// timestamps are truncated to the microseconds stored in the db
+    fn user(email: &str) -> (User, UserKey) {
+        let created = Utc::now().trunc_subsecs(6);
+        let user = User { user_id: Uuid::new_v4(), email: email.to_string(), key: rand::random(), created };
+        let key = UserKey { created, ..UserKey::new(user.user_id, user.key) };
+        (user, key)
+    }
+
+    fn workout(user_id: Uuid, start_time: DateTime<Utc>) -> Workout {
+        Workout {
+            user_id,
+            workout_id: Uuid::new_v4(),
+            start_time,
+            end_time: start_time + chrono::Duration::minutes(45),
+            exercises: Vec::new(),
+            category: None,
+            tags: Vec::new(),
+            note: None,
+        }
+    }
+
+    #[test]
+    fn workouts_and_keys_round_trip() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = SqliteDataBase::new("sqlite::memory:").await.unwrap();
+            // concurrent queries share the one in-memory database, rather than each opening an
+            // empty one without tables
+            let (a, b) = tokio::join!(db.fetch_user_keys(), db.fetch_user_time_zones());
+            assert!(a.unwrap().is_empty() && b.unwrap().is_empty());
+
+            let (u, k) = user("a@example.com");
+            db.insert_user(&u, &k).await.unwrap();
+            let (same_email, same_email_key) = user("a@example.com");
+            assert!(db.insert_user(&same_email, &same_email_key).await.is_err());
+            assert_eq!(db.fetch_user_keys().await.unwrap(), vec![k.clone()]);
+
+            // microsecond precision, like postgres
+            let t = Utc.with_ymd_and_hms(2021, 7, 27, 12, 0, 0).unwrap() + chrono::Duration::microseconds(123_456);
+            let w0 = Workout {
+                exercises: vec![Exercise {
+                    name: "squat".to_string(),
+                    sets: vec![
+                        ExerciseSet { reps: 5, weight: Some(100.0), unit: WeightUnit::Kg, rpe: Some(8.5) },
+                        ExerciseSet { reps: 12, weight: None, unit: WeightUnit::Lb, rpe: None },
+                    ],
+                }],
+                category: Some(Category::Strength),
+                tags: vec!["legs".to_string(), "pr".to_string()],
+                note: Some("felt good".to_string()),
+                ..workout(u.user_id, t)
+            };
+            let w1 = workout(u.user_id, t + chrono::Duration::hours(2));
+            db.save_new_workouts(&[w0.clone(), w1.clone()], &[]).await.unwrap();
+            assert_eq!(db.fetch_workout(&u.user_id, &w0.workout_id).await.unwrap(), Some(w0.clone()));
+
+            let dup = workout(u.user_id, t);
+            assert!(is_unique_violation(&db.save_new_workouts(&[dup], &[]).await.unwrap_err()));
+            // merging into a missing workout fails, and the new workout isn't saved either
+            let w2 = workout(u.user_id, t + chrono::Duration::hours(4));
+            let missing = workout(u.user_id, t + chrono::Duration::hours(6));
+            let err = db.save_new_workouts(std::slice::from_ref(&w2), std::slice::from_ref(&missing)).await.unwrap_err();
+            assert!(matches!(err, sqlx::Error::RowNotFound));
+            let mut workouts = db.fetch_user_workouts(&u.user_id).await.unwrap();
+            workouts.sort_by_key(|w| w.start_time);
+            assert_eq!(workouts, vec![w0.clone(), w1.clone()]);
+
+            let updated = Workout { exercises: Vec::new(), tags: Vec::new(), note: None, ..w0.clone() };
+            assert!(db.update_workout(&updated).await.unwrap());
+            assert!(! db.update_workout(&missing).await.unwrap());
+            assert_eq!(db.fetch_workout(&u.user_id, &w0.workout_id).await.unwrap(), Some(updated));
+            assert!(db.delete_workout(&u.user_id, &w1.workout_id).await.unwrap());
+            assert!(! db.delete_workout(&u.user_id, &w1.workout_id).await.unwrap());
+            // start times are in 2021, so the user isn't recently active
+            assert!(db.fetch_recently_active_user_workouts().await.unwrap().is_empty());
+
+            assert_eq!(db.revoke_user_key(&u.user_id, &k.key_id, Utc::now()).await.unwrap(), KeyRevocation::OnlyActiveKey);
+            let k2 = UserKey { created: Utc::now().trunc_subsecs(6), ..UserKey::new(u.user_id, rand::random()) };
+            db.insert_user_key(&k2).await.unwrap();
+            assert_eq!(db.revoke_user_key(&u.user_id, &k.key_id, Utc::now()).await.unwrap(), KeyRevocation::Revoked);
+            assert_eq!(db.revoke_user_key(&u.user_id, &k.key_id, Utc::now()).await.unwrap(), KeyRevocation::NotFound);
+            assert_eq!(db.fetch_user_keys().await.unwrap(), vec![k2]);
+            assert_eq!(db.fetch_keys_for_user(&u.user_id).await.unwrap().len(), 2);
+            // a revoked key can't be added again
+            let readded = UserKey::new(u.user_id, k.key);
+            assert!(crate::db::is_unique_violation(&db.insert_user_key(&readded).await.unwrap_err()));
+
+            assert!(db.update_user_time_zone(&u.user_id, chrono_tz::Europe::Berlin).await.unwrap());
+            assert_eq!(db.fetch_user_time_zones().await.unwrap(), vec![(u.user_id, chrono_tz::Europe::Berlin)]);
+        });
+    }
+
+    #[test]
+    fn database_file_is_created_and_migrated_once() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let path = std::env::temp_dir().join(format!("fitbod-test-{}.db", Uuid::new_v4()));
+            let url = format!("sqlite://{}", path.display());
+            let (u, k) = user("b@example.com");
+            let db = SqliteDataBase::new(&url).await.unwrap();
+            db.insert_user(&u, &k).await.unwrap();
+            db.pool.close().await;
+
+            let db = SqliteDataBase::new(&url).await.unwrap();
+            assert_eq!(db.fetch_keys_for_user(&u.user_id).await.unwrap(), vec![k]);
+            assert_eq!(db.schema_version().await.unwrap(), Some(migrate::latest(migrate::SQLITE)));
+            assert_eq!(db.migrate().await.unwrap(), vec![]);
+            let (n_migrations,): (i64,) = sqlx::query_as("select count(*) from migrations")
+                .fetch_one(&db.pool)
+                .await
+                .unwrap();
+            assert_eq!(n_migrations, migrate::SQLITE.len() as i64);
+            db.pool.close().await;
+
+            for suffix in &["", "-wal", "-shm"] {
+                let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+            }
+        });
+    }
+}
@@ -0,0 +1,389 @@
+use std::collections::BTreeMap;
+use serde::{Serialize, Deserialize};
+use chrono::prelude::*;
+use chrono_tz::Tz;
+use crate::Workout;
+use crate::api::ListWorkoutsItem;
+use crate::query::{Order, WorkoutQuery};
+
+/// max number of buckets in one aggregation (10 years of days)
+pub const MAX_AGGREGATE_BUCKETS: usize = 3660;
+
+/// totals, averages and streaks over a user's workouts. durations are in whole minutes, as in
+/// `ListWorkoutsItem::duration_minutes`, so totals match a listing of the same workouts
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WorkoutStats {
+    pub n_workouts: usize,
+    pub total_duration_minutes: u64,
+    /// `null` if there are no workouts
+    pub avg_duration_minutes: Option<f64>,
+    /// most consecutive days with at least one workout
+    pub longest_daily_streak: u32,
+    /// consecutive days with at least one workout, ending today, or yesterday if there was no
+    /// workout yet today
+    pub current_daily_streak: u32,
+    /// most consecutive (ISO, Monday to Sunday) weeks with at least one workout
+    pub longest_weekly_streak: u32,
+    /// consecutive weeks with at least one workout, ending this week, or last week if there
+    /// was no workout yet this week
+    pub current_weekly_streak: u32,
+    pub most_recent: Option<ListWorkoutsItem>,
+}
+
+impl WorkoutStats {
+    /// stats over `workouts`, which must be in ascending order by `start_time`. days are dates
+    /// of `start_time` in `tz`, and current streaks count back from `today` (also in `tz`)
+    pub fn compute<'a, I>(workouts: I, tz: Tz, today: NaiveDate) -> Self
+        where I: Iterator<Item = &'a Workout>
+    {
+        let mut n_workouts = 0;
+        let mut total_duration_minutes = 0u64;
+        let mut days = Streaks::new(chrono::Duration::days(1));
+        let mut weeks = Streaks::new(chrono::Duration::weeks(1));
+        let mut most_recent = None;
+
+        for workout in workouts {
+            let item = ListWorkoutsItem::new(workout, tz);
+            n_workouts += 1;
+            total_duration_minutes += item.duration_minutes as u64;
+            days.push(item.date);
+            weeks.push(week_start(item.date));
+            most_recent = Some(item);
+        }
+
+        let avg_duration_minutes = match n_workouts {
+            0 => None,
+            n => Some(total_duration_minutes as f64 / n as f64),
+        };
+
+        Self {
+            n_workouts,
+            total_duration_minutes,
+            avg_duration_minutes,
+            longest_daily_streak: days.longest,
+            current_daily_streak: days.current(today),
+            longest_weekly_streak: weeks.longest,
+            current_weekly_streak: weeks.current(week_start(today)),
+            most_recent,
+        }
+    }
+}
+
+/// monday of the ISO week containing `date`
+pub fn week_start(date: NaiveDate) -> NaiveDate {
+    date - chrono::Duration::days(date.weekday().num_days_from_monday() as i64)
+}
+
+/// length of the periods workouts are aggregated over
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BucketSize {
+    Day,
+    /// ISO week, Monday to Sunday
+    Week,
+    Month,
+}
+
+impl BucketSize {
+    /// first day of the bucket containing `date`
+    pub fn bucket_start(&self, date: NaiveDate) -> NaiveDate {
+        match self {
+            BucketSize::Day => date,
+            BucketSize::Week => week_start(date),
+            BucketSize::Month => date.with_day(1).unwrap(),
+        }
+    }
+
+    /// first day of the bucket after the one starting at `bucket_start`
+    pub fn next_bucket(&self, bucket_start: NaiveDate) -> NaiveDate {
+        match self {
+            BucketSize::Day => bucket_start + chrono::Duration::days(1),
+            BucketSize::Week => bucket_start + chrono::Duration::weeks(1),
+            BucketSize::Month => bucket_start + chrono::Months::new(1),
+        }
+    }
+}
+
+/// workouts that started during one bucket
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WorkoutBucket {
+    /// first day of the bucket, in the aggregation's time zone
+    pub date: NaiveDate,
+    pub n_workouts: usize,
+    pub total_duration_minutes: u64,
+}
+
+/// aggregation of workouts that started in `start..end` into buckets of `size`, with bucket
+/// boundaries at midnight in `tz`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aggregation {
+    pub size: BucketSize,
+    pub tz: Tz,
+    /// if `None`, buckets start at the earliest workout
+    pub start: Option<DateTime<Utc>>,
+    pub end: DateTime<Utc>,
+}
+
+impl Aggregation {
+    /// selects the workouts to aggregate, oldest first, as `aggregate` expects
+    pub fn query(&self) -> WorkoutQuery {
+        WorkoutQuery {
+            start: self.start,
+            end: Some(self.end),
+            order: Order::Asc,
+            ..Default::default()
+        }
+    }
+
+    fn local_bucket(&self, t: DateTime<Utc>) -> NaiveDate {
+        self.size.bucket_start(t.with_timezone(&self.tz).date_naive())
+    }
+
+    /// one bucket for every period from `start` (or the earliest workout) up to `end`, in
+    /// order, including empty ones. `workouts` must be in ascending order by `start_time`, and
+    /// already filtered by `query`. errors if there would be more than `MAX_AGGREGATE_BUCKETS`
+    pub fn aggregate<'a, I>(&self, workouts: I) -> Result<Vec<WorkoutBucket>, String>
+        where I: Iterator<Item = &'a Workout>
+    {
+        let mut totals: BTreeMap<NaiveDate, (usize, u64)> = BTreeMap::new();
+        for workout in workouts {
+            let total = totals.entry(self.local_bucket(workout.start_time)).or_default();
+            total.0 += 1;
+            total.1 += workout.duration_minutes() as u64;
+        }
+
+        let first = match self.start {
+            Some(start) => self.local_bucket(start),
+            None => match totals.keys().next() {
+                Some(&first) => first,
+                None => return Ok(Vec::new()),
+            },
+        };
+        if self.start.map(|start| start >= self.end).unwrap_or(false) {
+            return Ok(Vec::new())
+        }
+        // `end` is exclusive
+        let last = self.local_bucket(self.end - chrono::Duration::nanoseconds(1));
+
+        let mut buckets = Vec::new();
+        let mut date = first;
+        while date <= last {
+            if buckets.len() == MAX_AGGREGATE_BUCKETS {
+                return Err(format!("too many buckets: more than {} {:?} buckets from {} to {}",
+                    MAX_AGGREGATE_BUCKETS, self.size, first, last))
+            }
+            let (n_workouts, total_duration_minutes) = totals.get(&date).cloned().unwrap_or_default();
+            buckets.push(WorkoutBucket { date, n_workouts, total_duration_minutes });
+            date = self.size.next_bucket(date);
+        }
+
+        Ok(buckets)
+    }
+}
+
+/// runs of consecutive periods (days or weeks), fed period starts in ascending order
+struct Streaks {
+    step: chrono::Duration,
+    last: Option<NaiveDate>,
+    run: u32,
+    longest: u32,
+}
+
+impl Streaks {
+    fn new(step: chrono::Duration) -> Self {
+        Self { step, last: None, run: 0, longest: 0 }
+    }
+
+    fn push(&mut self, period: NaiveDate) {
+        match self.last {
+            Some(last) if last == period => return,
+            Some(last) if last + self.step == period => self.run += 1,
+            _ => self.run = 1,
+        }
+        self.last = Some(period);
+        self.longest = self.longest.max(self.run);
+    }
+
+    /// length of the run ending at `period`, or the period before it
+    fn current(&self, period: NaiveDate) -> u32 {
+        match self.last {
+            Some(last) if last == period || last + self.step == period => self.run,
+            _ => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(unused)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn workout_on(date: NaiveDate, minutes: i64) -> Workout {
+        let start_time = Utc.from_utc_datetime(&date.and_hms_opt(18, 0, 0).unwrap());
+        Workout {
+            workout_id: Uuid::new_v4(),
+            user_id: Uuid::nil(),
+            start_time,
+            end_time: start_time + chrono::Duration::minutes(minutes),
+            exercises: Vec::new(),
+            category: None,
+            tags: Vec::new(),
+            note: None,
+        }
+    }
+
+    #[test]
+    fn daily_and_weekly_streaks() {
+        let d = |day: u32| NaiveDate::from_ymd_opt(2021, 7, day).unwrap();
+        // mon 5th .. wed 7th, then fri 9th twice, then mon 19th .. tue 20th
+        let workouts: Vec<Workout> = vec![
+            workout_on(d(5), 30), workout_on(d(6), 45), workout_on(d(7), 60),
+            workout_on(d(9), 20), workout_on(d(9), 40),
+            workout_on(d(19), 30), workout_on(d(20), 30),
+        ];
+
+        let stats = WorkoutStats::compute(workouts.iter(), chrono_tz::UTC, d(21));
+        assert_eq!(stats.n_workouts, 7);
+        assert_eq!(stats.total_duration_minutes, 255);
+        assert!((stats.avg_duration_minutes.unwrap() - 255.0 / 7.0).abs() < 1e-9);
+        assert_eq!(stats.longest_daily_streak, 3);
+        assert_eq!(stats.current_daily_streak, 2);
+        // the week of the 12th has no workouts
+        assert_eq!(stats.longest_weekly_streak, 1);
+        assert_eq!(stats.current_weekly_streak, 1);
+        assert_eq!(stats.most_recent.as_ref().map(|x| x.workout_id), Some(workouts[6].workout_id));
+
+        assert_eq!(WorkoutStats::compute(workouts.iter(), chrono_tz::UTC, d(20)).current_daily_streak, 2);
+        assert_eq!(WorkoutStats::compute(workouts.iter(), chrono_tz::UTC, d(22)).current_daily_streak, 0);
+        assert_eq!(WorkoutStats::compute(workouts.iter(), chrono_tz::UTC, d(26)).current_weekly_streak, 1);
+        assert_eq!(WorkoutStats::compute(workouts.iter(), chrono_tz::UTC, d(26)).current_daily_streak, 0);
+        assert_eq!(WorkoutStats::compute(workouts.iter().take(5), chrono_tz::UTC, d(14)).current_weekly_streak, 1);
+        assert_eq!(WorkoutStats::compute(workouts.iter().take(5), chrono_tz::UTC, d(19)).current_weekly_streak, 0);
+
+        let consecutive_weeks: Vec<Workout> = (0..4).map(|i| workout_on(d(4) + chrono::Duration::days(i * 6), 30)).collect();
+        let stats = WorkoutStats::compute(consecutive_weeks.iter(), chrono_tz::UTC, d(23));
+        assert_eq!(stats.longest_daily_streak, 1);
+        assert_eq!(stats.longest_weekly_streak, 4);
+        assert_eq!(stats.current_weekly_streak, 4);
+    }
+
+    #[test]
+    fn cached_stats_match_stats_from_db_rows() {
+        let cache = crate::cache::Cache::default();
+        let user_id = Uuid::new_v4();
+        let t0 = NaiveDate::from_ymd_opt(2021, 7, 1).unwrap();
+        let workouts: Vec<Workout> = (0..40)
+            .filter(|i| i % 7 != 3)
+            .map(|i| Workout { user_id, ..workout_on(t0 + chrono::Duration::days(i), 20 + i) })
+            .collect();
+        cache.try_cache_workouts(user_id, &workouts);
+
+        let today = t0 + chrono::Duration::days(40);
+        let mut rows = workouts.clone();
+        rows.reverse();
+        for &(start, end) in &[(None, None), (Some(5), Some(30)), (Some(10), None), (None, Some(12)), (Some(30), Some(10))] {
+            let at = |d: i64| Utc.from_utc_datetime(&(t0 + chrono::Duration::days(d)).and_hms_opt(0, 0, 0).unwrap());
+            let req = crate::api::WorkoutStatsRequest { user_id, start: start.map(at), end: end.map(at), tz: None };
+            let query = req.query();
+            let cached = cache.get_workout_stats(&user_id, &query, chrono_tz::UTC, today).unwrap();
+            let from_db = WorkoutStats::compute(query.select_unsorted(rows.clone()).iter(), chrono_tz::UTC, today);
+            assert_eq!(cached, from_db);
+        }
+
+        let all = cache.get_workout_stats(&user_id, &Default::default(), chrono_tz::UTC, today).unwrap();
+        assert_eq!(all.n_workouts, workouts.len());
+        assert_eq!(all.longest_daily_streak, 6);
+        assert_eq!(all.current_daily_streak, 1);
+    }
+
+    #[test]
+    fn buckets_follow_time_zone_and_are_dense() {
+        let cache = crate::cache::Cache::default();
+        let user_id = Uuid::new_v4();
+        let tokyo: Tz = "Asia/Tokyo".parse().unwrap();
+        // monday 2021-07-26 07:00 in tokyo is sunday 22:00 utc
+        let monday_morning = tokyo.with_ymd_and_hms(2021, 7, 26, 7, 0, 0).unwrap().with_timezone(&Utc);
+        assert_eq!(monday_morning.weekday(), Weekday::Sun);
+        let workouts = vec![
+            Workout { user_id, ..workout_on(NaiveDate::from_ymd_opt(2021, 7, 12).unwrap(), 30) },
+            Workout {
+                user_id,
+                workout_id: Uuid::new_v4(),
+                start_time: monday_morning,
+                end_time: monday_morning + chrono::Duration::minutes(50),
+                exercises: Vec::new(),
+                category: None,
+                tags: Vec::new(),
+                note: None,
+            },
+        ];
+        cache.try_cache_workouts(user_id, &workouts);
+
+        let end = Utc.with_ymd_and_hms(2021, 8, 1, 12, 0, 0).unwrap();
+        let weekly = |tz: Tz| Aggregation { size: BucketSize::Week, tz, start: None, end };
+        let bucket = |day: u32, n_workouts: usize, total_duration_minutes: u64| WorkoutBucket {
+            date: NaiveDate::from_ymd_opt(2021, 7, day).unwrap(),
+            n_workouts,
+            total_duration_minutes,
+        };
+
+        assert_eq!(
+            cache.aggregate_workouts(&user_id, &weekly(tokyo)).unwrap().unwrap(),
+            vec![bucket(12, 1, 30), bucket(19, 0, 0), bucket(26, 1, 50)],
+        );
+        assert_eq!(
+            cache.aggregate_workouts(&user_id, &weekly(chrono_tz::UTC)).unwrap().unwrap(),
+            vec![bucket(12, 1, 30), bucket(19, 1, 50), bucket(26, 0, 0)],
+        );
+
+        // same results from db rows
+        for aggregation in &[weekly(tokyo), weekly(chrono_tz::UTC)] {
+            let selected = aggregation.query().select_unsorted(workouts.clone());
+            assert_eq!(
+                cache.aggregate_workouts(&user_id, aggregation).unwrap(),
+                aggregation.aggregate(selected.iter()),
+            );
+        }
+
+        let daily = Aggregation {
+            size: BucketSize::Day,
+            tz: tokyo,
+            start: Some(tokyo.with_ymd_and_hms(2021, 7, 24, 0, 0, 0).unwrap().with_timezone(&Utc)),
+            end: tokyo.with_ymd_and_hms(2021, 7, 27, 0, 0, 0).unwrap().with_timezone(&Utc),
+        };
+        assert_eq!(
+            cache.aggregate_workouts(&user_id, &daily).unwrap().unwrap(),
+            vec![bucket(24, 0, 0), bucket(25, 0, 0), bucket(26, 1, 50)],
+        );
+
+        let monthly = Aggregation {
+            size: BucketSize::Month,
+            tz: tokyo,
+            start: Some(Utc.with_ymd_and_hms(2021, 5, 20, 0, 0, 0).unwrap()),
+            end,
+        };
+        let months: Vec<(u32, usize)> = cache.aggregate_workouts(&user_id, &monthly).unwrap().unwrap()
+            .iter()
+            .map(|b| (b.date.month(), b.n_workouts))
+            .collect();
+        assert_eq!(months, vec![(5, 0), (6, 0), (7, 2), (8, 0)]);
+
+        let empty = Aggregation { start: Some(end), end: monthly.start.unwrap(), ..monthly };
+        assert_eq!(cache.aggregate_workouts(&user_id, &empty).unwrap().unwrap(), vec![]);
+
+        let too_long = Aggregation { start: Some(Utc.with_ymd_and_hms(2000, 1, 1, 0, 0, 0).unwrap()), ..daily };
+        assert!(cache.aggregate_workouts(&user_id, &too_long).unwrap().is_err());
+    }
+
+    #[test]
+    fn no_workouts() {
+        let stats = WorkoutStats::compute(std::iter::empty(), chrono_tz::UTC, NaiveDate::from_ymd_opt(2021, 7, 21).unwrap());
+        assert_eq!(stats.n_workouts, 0);
+        assert_eq!(stats.total_duration_minutes, 0);
+        assert_eq!(stats.avg_duration_minutes, None);
+        assert_eq!(stats.longest_daily_streak, 0);
+        assert_eq!(stats.current_daily_streak, 0);
+        assert_eq!(stats.most_recent, None);
+    }
+}
@@ -0,0 +1,199 @@
+//! checks on workouts sent by users, applied before anything touches the cache or db
+
+use serde::{Serialize, Deserialize};
+use chrono::prelude::*;
+use crate::Workout;
+use crate::api::NewWorkoutsRequest;
+
+/// most items accepted in one `NewWorkoutsRequest`
+pub const MAX_NEW_WORKOUTS: usize = 1000;
+/// longest accepted workout
+pub const MAX_WORKOUT_DURATION_HOURS: i64 = 24;
+/// how far in the future a workout may start. allows for clocks of devices being off
+pub const MAX_START_TIME_AHEAD_HOURS: i64 = 24;
+/// most reps accepted in a set. reps are stored as a signed 32-bit integer
+pub const MAX_REPS: u32 = 10_000;
+
+/// problem with one field of a request
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FieldError {
+    /// path to the field in the request body, e.g. "items[2].end_time"
+    pub field: String,
+    pub error: String,
+}
+
+impl FieldError {
+    pub fn new(field: impl Into<String>, error: impl Into<String>) -> Self {
+        Self { field: field.into(), error: error.into() }
+    }
+
+    fn prefixed(self, prefix: &str) -> Self {
+        Self { field: format!("{}.{}", prefix, self.field), ..self }
+    }
+}
+
+impl NewWorkoutsRequest {
+    /// all problems with the request's items, or with their number. `now` is the current
+    /// time on the server
+    pub fn validate(&self, now: DateTime<Utc>) -> Result<(), Vec<FieldError>> {
+        if self.items.len() > MAX_NEW_WORKOUTS {
+            return Err(vec![FieldError::new(
+                "items",
+                format!("at most {} workouts may be sent in one request, got {}", MAX_NEW_WORKOUTS, self.items.len()),
+            )])
+        }
+
+        let mut errors = Vec::new();
+        for (i, workout) in self.items.iter().enumerate() {
+            let prefix = format!("items[{}]", i);
+            if workout.user_id != self.user_id {
+                errors.push(FieldError::new("user_id", "does not match user_id of the request").prefixed(&prefix));
+            }
+            if let Err(e) = workout.validate(now) {
+                errors.extend(e.into_iter().map(|e| e.prefixed(&prefix)));
+            }
+        }
+
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+}
+
+impl Workout {
+    /// whether `end_time` is after `start_time`, by at most `MAX_WORKOUT_DURATION_HOURS`. also
+    /// checked for workouts created by merging, see `Workout::merged_with`
+    pub fn validate_duration(&self) -> Result<(), FieldError> {
+        if self.end_time <= self.start_time {
+            Err(FieldError::new("end_time", "must be after start_time"))
+        } else if self.end_time - self.start_time > chrono::Duration::hours(MAX_WORKOUT_DURATION_HOURS) {
+            Err(FieldError::new(
+                "end_time",
+                format!("workout may last at most {} hours", MAX_WORKOUT_DURATION_HOURS),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// all problems with the workout's fields. `now` is the current time on the server
+    pub fn validate(&self, now: DateTime<Utc>) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+
+        if let Err(e) = self.validate_duration() {
+            errors.push(e);
+        }
+
+        if self.start_time > now + chrono::Duration::hours(MAX_START_TIME_AHEAD_HOURS) {
+            errors.push(FieldError::new(
+                "start_time",
+                format!("may be at most {} hours in the future", MAX_START_TIME_AHEAD_HOURS),
+            ));
+        }
+
+        for (i, exercise) in self.exercises.iter().enumerate() {
+            let field = |name: &str| format!("exercises[{}].{}", i, name);
+            if exercise.name.trim().is_empty() {
+                errors.push(FieldError::new(field("name"), "must not be empty"));
+            }
+
+            for (j, set) in exercise.sets.iter().enumerate() {
+                let field = |name: &str| field(&format!("sets[{}].{}", j, name));
+                if set.reps > MAX_REPS {
+                    errors.push(FieldError::new(field("reps"), format!("must be at most {}", MAX_REPS)));
+                }
+                if set.weight.map(|w| !(w >= 0.0 && w.is_finite())).unwrap_or(false) {
+                    errors.push(FieldError::new(field("weight"), "must be a non-negative number"));
+                }
+                if set.rpe.map(|r| !(1.0..=10.0).contains(&r)).unwrap_or(false) {
+                    errors.push(FieldError::new(field("rpe"), "must be between 1 and 10"));
+                }
+            }
+        }
+
+        for (i, tag) in self.tags.iter().enumerate() {
+            if tag.trim().is_empty() {
+                errors.push(FieldError::new(format!("tags[{}]", i), "must not be empty"));
+            }
+        }
+
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+    use crate::{Exercise, ExerciseSet, WeightUnit};
+
+    fn workout(user_id: Uuid, start_time: DateTime<Utc>, minutes: i64) -> Workout {
+        Workout {
+            user_id,
+            workout_id: Uuid::new_v4(),
+            start_time,
+            end_time: start_time + chrono::Duration::minutes(minutes),
+            exercises: Vec::new(),
+            category: None,
+            tags: Vec::new(),
+            note: None,
+        }
+    }
+
+    fn fields(result: Result<(), Vec<FieldError>>) -> Vec<String> {
+        result.unwrap_err().into_iter().map(|e| e.field).collect()
+    }
+
+    #[test]
+    fn invalid_items_are_reported_by_field() {
+        let now = Utc.with_ymd_and_hms(2021, 7, 27, 12, 0, 0).unwrap();
+        let user_id = Uuid::new_v4();
+        let ok = workout(user_id, now - chrono::Duration::hours(2), 60);
+        let req = NewWorkoutsRequest { user_id, items: vec![ok.clone()], overlap: Default::default() };
+        assert_eq!(req.validate(now), Ok(()));
+
+        let backwards = workout(user_id, now - chrono::Duration::hours(2), -30);
+        let too_long = workout(user_id, now - chrono::Duration::hours(30), 25 * 60);
+        let future = workout(user_id, Utc.with_ymd_and_hms(2061, 7, 27, 12, 0, 0).unwrap(), 60);
+        let other_user = workout(Uuid::new_v4(), now - chrono::Duration::hours(5), 60);
+        let mut details = ok.clone();
+        details.exercises = vec![Exercise {
+            name: " ".to_string(),
+            sets: vec![
+                ExerciseSet { reps: 5, weight: Some(-10.0), unit: WeightUnit::Kg, rpe: Some(11.0) },
+                ExerciseSet { reps: 5, weight: Some(10.0), unit: WeightUnit::Kg, rpe: Some(8.0) },
+            ],
+        }];
+        details.tags = vec!["legs".to_string(), "".to_string()];
+
+        let req = NewWorkoutsRequest {
+            user_id,
+            items: vec![ok.clone(), backwards, too_long, future, other_user, details],
+            overlap: Default::default(),
+        };
+        assert_eq!(fields(req.validate(now)), vec![
+            "items[1].end_time",
+            "items[2].end_time",
+            "items[3].start_time",
+            "items[4].user_id",
+            "items[5].exercises[0].name",
+            "items[5].exercises[0].sets[0].weight",
+            "items[5].exercises[0].sets[0].rpe",
+            "items[5].tags[1]",
+        ]);
+
+        // a workout ending exactly when it starts has no duration
+        assert_eq!(fields(workout(user_id, now, 0).validate(now)), vec!["end_time"]);
+    }
+
+    #[test]
+    fn batch_size_is_limited() {
+        let now = Utc::now();
+        let user_id = Uuid::new_v4();
+        let items: Vec<Workout> = (0..MAX_NEW_WORKOUTS as i64 + 1)
+            .map(|i| workout(user_id, now - chrono::Duration::hours(i + 1), 30))
+            .collect();
+        let req = NewWorkoutsRequest { user_id, items: items[..MAX_NEW_WORKOUTS].to_vec(), overlap: Default::default() };
+        assert_eq!(req.validate(now), Ok(()));
+        let req = NewWorkoutsRequest { user_id, items, overlap: Default::default() };
+        assert_eq!(fields(req.validate(now)), vec!["items"]);
+    }
+}